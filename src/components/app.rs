@@ -1,17 +1,39 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use bit_set::BitSet;
 use gloo::net::http::Request;
 use itertools::Itertools;
-use js_sys::{JsString, Uint8Array};
+use js_sys::Uint8Array;
 use leptos::prelude::*;
-use web_sys::File;
+use leptos::task::spawn_local;
+use log::{error, warn};
+use web_sys::{BaseAudioContext, File};
 
+use crate::components::export_controls::ExportControls;
 use crate::components::keyboard_listener::KeyboardListener;
+use crate::components::library_controls::LibraryControls;
+use crate::components::midi_listener::MidiListener;
 use crate::components::mobile_controls::MobileControls;
 use crate::components::sheet_music::SheetMusic;
+use crate::components::transport_controls::TransportControls;
 use crate::components::voice_control::{VoiceControl, VoiceState};
 use crate::future_util::PromiseAsFuture;
-use crate::playback_manager::PlaybackManager;
+use crate::instrument::{InstrumentPreset, INSTRUMENT_PRESETS};
+use crate::keymap::{detect_key_labels, Keymap};
+use crate::loop_region::LoopRegion;
+use crate::midi_import::MIDI_MAGIC;
+use crate::offline_cache;
+use crate::panning::PAN_PRESETS;
+use crate::playback_manager::{ArticulationMode, PlaybackManager};
+use crate::sampler::{midi_note_name, Sampler};
 use crate::song_data::SongData;
+use crate::song_library::{self, LibraryEntry};
+use crate::song_settings::{content_hash, SongSettings, VoiceMix, DEFAULT_VOLUME, DEFAULT_ZOOM};
+use crate::soundfont::SoundFont;
+use crate::temperament::{Temperament, HISTORICAL_TEMPERAMENTS, STANDARD_A4_HZ};
+use crate::timeline::PlaybackOrder;
+use crate::tuning::{Tuning, TuningReference, TUNING_PRESETS};
 
 const SONGS: &[&str] = &[
     "A Million Stars",
@@ -52,6 +74,24 @@ const SONGS: &[&str] = &[
     "You Are The One",
 ];
 
+/// Songs which also have a MIDI arrangement in `examples/`.
+const MIDI_SONGS: &[&str] = &["A Million Stars", "Lone Prairie", "Mam'selle"];
+
+const PITCH_CLASS_NAMES: [&str; 12] = [
+    "C",
+    "C♯/D♭",
+    "D",
+    "E♭",
+    "E",
+    "F",
+    "F♯/G♭",
+    "G",
+    "A♭",
+    "A",
+    "B♭",
+    "B",
+];
+
 /// Converts a 0-100 volume to a gain multiplier by interpolating between the given min/max
 /// relative decibel levels and then converting that to a multiplier. Min db should probably be
 /// around -60 to -90.
@@ -72,33 +112,222 @@ fn volume_to_gain(volume: u32, min_db: f32, max_db: f32) -> f32 {
 
 #[derive(Clone, Eq, PartialEq)]
 pub enum SongChoice {
-    BuiltIn { name: String },
-    Uploaded { file: File },
+    BuiltIn {
+        name: String,
+    },
+    BuiltInMidi {
+        name: String,
+    },
+    /// Can be either MusicXML or MIDI, we figure out which from the contents.
+    Uploaded {
+        file: File,
+    },
+    /// A MusicXML file from the user's library, by its id.
+    Library {
+        id: String,
+    },
 }
 
-fn create_voice_states(num_voices: usize) -> Vec<VoiceState> {
-    let names = if num_voices == 4 {
-        ["Tenor", "Lead", "Bari", "Bass"]
-            .into_iter()
-            .map(|name| name.to_string())
-            .collect_vec()
-    } else {
-        (1..=num_voices)
-            .map(|num| format!("Voice {num}"))
-            .collect_vec()
-    };
+impl SongChoice {
+    /// Identifies the song across sessions so we can save settings for it. Uploads don't have a
+    /// stable name, so they're identified by their contents.
+    fn song_key(&self, data: &[u8]) -> String {
+        match self {
+            SongChoice::BuiltIn { name } => format!("mxl:{name}"),
+            SongChoice::BuiltInMidi { name } => format!("mid:{name}"),
+            // Library songs are keyed the same as uploads, so settings made before a song was in
+            // the library carry over.
+            SongChoice::Uploaded { .. } | SongChoice::Library { .. } => {
+                format!("upload:{:016x}", content_hash(data))
+            }
+        }
+    }
+
+    /// What the song `<select>` has for this song, if it's listed there.
+    fn select_value(&self) -> Option<String> {
+        match self {
+            SongChoice::BuiltIn { name } => Some(format!("mxl:{name}")),
+            SongChoice::BuiltInMidi { name } => Some(format!("mid:{name}")),
+            SongChoice::Uploaded { .. } => None,
+            SongChoice::Library { id } => Some(format!("{LIBRARY_CHOICE_PREFIX}{id}")),
+        }
+    }
+
+    /// A human readable name for the song, eg for naming exported files.
+    fn title(&self, library: &[LibraryEntry]) -> String {
+        match self {
+            SongChoice::BuiltIn { name } | SongChoice::BuiltInMidi { name } => name.clone(),
+            SongChoice::Uploaded { file } => {
+                let name = file.name();
+                match name.rsplit_once('.') {
+                    Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+                    _ => name,
+                }
+            }
+            SongChoice::Library { id } => library
+                .iter()
+                .find(|entry| entry.id == *id)
+                .map(|entry| entry.title.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// The prefix for values in the song `<select>` for songs in the library, followed by their id.
+const LIBRARY_CHOICE_PREFIX: &str = "library:";
 
-    names
-        .into_iter()
-        .map(|name| VoiceState::new(name.to_string()))
+/// The values used in the song `<select>` for the built-in songs.
+fn built_in_song_choices() -> Vec<(String, SongChoice)> {
+    SONGS
+        .iter()
+        .map(|&name| {
+            (
+                format!("mxl:{name}"),
+                SongChoice::BuiltIn {
+                    name: name.to_string(),
+                },
+            )
+        })
+        .chain(MIDI_SONGS.iter().map(|&name| {
+            (
+                format!("mid:{name}"),
+                SongChoice::BuiltInMidi {
+                    name: name.to_string(),
+                },
+            )
+        }))
         .collect_vec()
 }
 
+fn example_url(name: &str, extension: &str) -> String {
+    format!("examples/{name}.{extension}")
+}
+
+async fn fetch_song_bytes(url: &str) -> Vec<u8> {
+    Request::get(url)
+        .send()
+        .await
+        .unwrap()
+        // If we really cared about the extra copy of the data below we could use `.body()`
+        // here instead.
+        .binary()
+        .await
+        .unwrap()
+}
+
+async fn read_file_bytes(file: &File) -> Vec<u8> {
+    let blob: &web_sys::Blob = file.as_ref();
+    let array_buffer = blob.array_buffer().into_future().await.unwrap();
+    let typed_buff: Uint8Array = Uint8Array::new(&array_buffer);
+    let mut data = vec![0; typed_buff.length() as usize];
+    typed_buff.copy_to(&mut data);
+    data
+}
+
+async fn read_file_text(file: &File) -> String {
+    let blob: &web_sys::Blob = file.as_ref();
+    blob.text()
+        .into_future()
+        .await
+        .unwrap()
+        .as_string()
+        .unwrap_or_default()
+}
+
+/// Reads a Scala scale from the given files, which should be a `.scl` and optionally a `.kbm`.
+async fn load_scala_temperament(files: Vec<File>) -> Result<Temperament, String> {
+    let mut scl = None;
+    let mut kbm = None;
+    for file in files {
+        let name = file.name();
+        let contents = read_file_text(&file).await;
+        if name.to_lowercase().ends_with(".kbm") {
+            kbm = Some(contents);
+        } else {
+            scl = Some((name, contents));
+        }
+    }
+    let Some((file_name, scl)) = scl else {
+        return Err("Choose a .scl file, and optionally a .kbm file to go with it".to_string());
+    };
+    let mut temperament = Temperament::from_scala(&scl).map_err(|e| e.to_string())?;
+    if let Some(kbm) = kbm {
+        temperament = temperament
+            .with_keyboard_mapping(&kbm)
+            .map_err(|e| e.to_string())?;
+    }
+    if temperament.name.is_empty() {
+        temperament.name = file_name;
+    }
+    Ok(temperament)
+}
+
+/// Loads a sampled instrument from the given files, which should be an `.sf2`, or an `.sfz` and
+/// the samples it uses. Returns a name and sampler for each instrument found.
+async fn load_instrument_files(
+    ctx: &BaseAudioContext,
+    files: Vec<File>,
+) -> Result<Vec<(String, Sampler)>, String> {
+    let mut sfz = None;
+    let mut other_files = HashMap::new();
+    for file in files {
+        let name = file.name();
+        let data = read_file_bytes(&file).await;
+        let lowercase_name = name.to_lowercase();
+        if lowercase_name.ends_with(".sf2") {
+            let sound_font = SoundFont::parse(&data).map_err(|e| e.to_string())?;
+            return Sampler::from_sound_font(ctx, &sound_font)
+                .map_err(|e| format!("Unable to load {name}: {e:?}"));
+        }
+        if lowercase_name.ends_with(".sfz") {
+            sfz = Some((name, String::from_utf8_lossy(&data).into_owned()));
+        } else {
+            other_files.insert(name, data);
+        }
+    }
+    let Some((name, sfz)) = sfz else {
+        return Err("Choose an .sf2 file, or an .sfz file along with its samples".to_string());
+    };
+    let sampler = Sampler::from_sfz(ctx, &sfz, &other_files)
+        .await
+        .map_err(|e| e.to_string())?;
+    let name = name
+        .rsplit_once('.')
+        .map(|(stem, _)| stem.to_string())
+        .unwrap_or(name);
+    Ok(vec![(name, sampler)])
+}
+
+/// The instrument at `index` in the UI's list, which is `INSTRUMENT_PRESETS` followed by any
+/// loaded instruments.
+fn instrument_preset(index: usize) -> InstrumentPreset {
+    match INSTRUMENT_PRESETS.get(index) {
+        Some((_, preset)) => *preset,
+        None => InstrumentPreset::Loaded(index - INSTRUMENT_PRESETS.len()),
+    }
+}
+
 #[component]
 pub fn App() -> impl IntoView {
     let (song_choice, set_song_choice) = signal_local(SongChoice::BuiltIn {
         name: SONGS[0].to_string(),
     });
+    // Have every example ready for offline use, not just the ones that have been opened.
+    spawn_local(async {
+        let urls = SONGS
+            .iter()
+            .map(|name| example_url(name, "mxl"))
+            .chain(MIDI_SONGS.iter().map(|name| example_url(name, "mid")))
+            .collect_vec();
+        offline_cache::precache(&urls).await;
+    });
+    let library_entries = RwSignal::new(Vec::<LibraryEntry>::new());
+    spawn_local(async move {
+        match song_library::list().await {
+            Ok(entries) => library_entries.set(entries),
+            Err(e) => warn!("Unable to read the song library: {e}"),
+        }
+    });
     let on_reset_song = Trigger::new();
     // Reset whenever the song name changes
     Effect::new(move |_| song_choice.with(|_| on_reset_song.notify()));
@@ -107,62 +336,164 @@ pub fn App() -> impl IntoView {
 
     let (start_cursor_index, set_start_cursor_index) = signal(0);
     let (current_cursor_index, set_current_cursor_index) = signal(0);
-    
+
     let start_song_index = RwSignal::new(0);
     let most_recent_song_index = RwSignal::new(0);
+    // If unset we use whatever the score says.
+    let tempo_override = RwSignal::new(None::<f64>);
+    // Slice indices are only meaningful within a song and playback order, so this is cleared when
+    // either changes.
+    let loop_region = RwSignal::new(None::<LoopRegion>);
+    let keymap = RwSignal::new(Keymap::load());
+    spawn_local(async move {
+        if let Some(labels) = detect_key_labels().await {
+            keymap.update(|keymap| keymap.detected_labels = labels);
+        }
+    });
+    Effect::new(move |_| {
+        on_reset_song.track();
+        loop_region.set(None);
+    });
 
+    // As written in the score, this is what `SheetMusic` gives us.
     let (song_data, set_song_data) = signal::<Option<SongData>>(None);
+    let (playback_order, set_playback_order) = signal(PlaybackOrder::default());
+    // Slice indices change meaning along with the order, so anything playing has to stop.
+    let on_change_order = Trigger::new();
+    // What we actually step through, which may follow the repeats rather than the written order.
+    let timeline_song_data = Memo::new(move |_| {
+        song_data.with(|song_data| {
+            song_data
+                .as_ref()
+                .map(|song_data| song_data.in_order(playback_order.get()))
+        })
+    });
+    let change_playback_order = move |new_order: PlaybackOrder| {
+        // Stay at the same place in the score, which will be a different index in the new order.
+        let start_cursor_index = timeline_song_data.with_untracked(|song_data| {
+            song_data
+                .as_ref()?
+                .slices
+                .get(start_song_index.get_untracked())
+                .map(|s| s.cursor_index)
+        });
+        set_playback_order.set(new_order);
+        loop_region.set(None);
+        on_change_order.notify();
+        let new_start_index = timeline_song_data
+            .with_untracked(|song_data| {
+                song_data
+                    .as_ref()?
+                    .slices
+                    .iter()
+                    .position(|s| Some(s.cursor_index) == start_cursor_index)
+            })
+            .unwrap_or(0);
+        start_song_index.set(new_start_index);
+        most_recent_song_index.set(new_start_index);
+    };
     let song_raw_data = LocalResource::new(move || async move {
         let song_choice = song_choice.get();
-        let data = match song_choice {
-            SongChoice::BuiltIn { name } => {
-                Request::get(&format!("examples/{name}.mxl"))
-                    .send()
-                    .await
-                    .unwrap()
-                    // If we really cared about the extra copy of the data below we could use `.body()`
-                    // here instead.
-                    .binary()
-                    .await
-                    .unwrap()
-            }
-            SongChoice::Uploaded { file } => {
-                // It's probably a bit inefficient to read this data into Rust-land and then
-                // pass it back to JS-land, but oh well.
-                let blob: &web_sys::Blob = file.as_ref();
-                let array_buffer = blob.array_buffer().into_future().await.unwrap();
-                let typed_buff: Uint8Array = Uint8Array::new(&array_buffer);
-                let mut data = vec![0; typed_buff.length() as usize];
-                typed_buff.copy_to(&mut data);
-                data
-            }
-        };
-        // The library takes in the binary data as a string (*shudders*) and JS uses arbitrary 16-bit
-        // character codes (nominally utf-16, but doesn't need to be valid utf-16), so we need to make
-        // these u16's and then shove it into a JsString. Interestingly that means just left-padding
-        // them with 0's, not packing two u8's into a single u16 (which didn't work).
-        let u16_data = data.iter().map(|d| *d as u16).collect_vec();
-        JsString::from_char_code(&u16_data)
+        match song_choice {
+            SongChoice::BuiltIn { name } => fetch_song_bytes(&example_url(&name, "mxl")).await,
+            SongChoice::BuiltInMidi { name } => fetch_song_bytes(&example_url(&name, "mid")).await,
+            SongChoice::Uploaded { file } => read_file_bytes(&file).await,
+            SongChoice::Library { id } => song_library::file_bytes(&id).await.unwrap_or_else(|e| {
+                error!("Unable to open song from the library: {e}");
+                Vec::new()
+            }),
+        }
     });
 
-    let (overall_volume, set_overall_volume) = signal(70u32);
-    let num_voices = Memo::new(move |_| {
-        song_data.with(|song_data| {
+    let (overall_volume, set_overall_volume) = signal(DEFAULT_VOLUME);
+    let zoom = RwSignal::new(DEFAULT_ZOOM);
+    let original_linebreaks = RwSignal::new(false);
+    // Index into `PAN_PRESETS`.
+    let (pan_preset, set_pan_preset) = signal(0usize);
+    // The voice the pan presets treat as the singer's own part.
+    let (focus_voice, set_focus_voice) = signal(0usize);
+    // Names of the instruments the user has loaded, in the order they were added to the playback
+    // manager.
+    let (loaded_instrument_names, set_loaded_instrument_names) = signal(Vec::<String>::new());
+    let instrument_names = Signal::derive(move || {
+        INSTRUMENT_PRESETS
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(loaded_instrument_names.get())
+            .collect_vec()
+    });
+    let (instrument_error, set_instrument_error) = signal::<Option<String>>(None);
+    let instrument_input_ref = NodeRef::new();
+    let (articulation_mode, set_articulation_mode) = signal(ArticulationMode::default());
+    // Index into `TUNING_PRESETS`.
+    let (tuning_preset, set_tuning_preset) = signal(0usize);
+    let (tonic, set_tonic) = signal(0u32);
+    let tuning = Memo::new(move |_| {
+        TUNING_PRESETS[tuning_preset.get()]
+            .1
+            .with_tonic(tonic.get())
+    });
+    let (reference_pitch, set_reference_pitch) = signal(STANDARD_A4_HZ);
+    // `None` for equal temperament.
+    let (temperament, set_temperament) = signal::<Option<Temperament>>(None);
+    // The most recently loaded Scala file, so it can be picked again after trying something else.
+    let (scala_temperament, set_scala_temperament) = signal::<Option<Temperament>>(None);
+    let (scala_error, set_scala_error) = signal::<Option<String>>(None);
+    let scala_input_ref = NodeRef::new();
+    // In semitones. These are separate since eg a quartet might sing a half step down but still
+    // want to read the chart as written.
+    let (playback_transpose, set_playback_transpose) = signal(0i32);
+    let (notation_transpose, set_notation_transpose) = signal(0i32);
+    let song_key = Memo::new(move |_| {
+        let song_raw_data = song_raw_data.read();
+        let song_raw_data = song_raw_data.as_ref()?;
+        Some(song_choice.with(|song_choice| song_choice.song_key(song_raw_data)))
+    });
+    // The user's names for the voices if they've renamed them, otherwise whatever the score says.
+    let voice_names = Memo::new(move |_| {
+        let default_names = song_data.with(|song_data| {
             song_data
                 .as_ref()
-                .map(|sd| sd.voice_index_mapping.len())
-                .unwrap_or(4)
-        })
+                .map(|sd| sd.voice_names.clone())
+                .unwrap_or_else(|| (1..=4).map(|num| format!("Voice {num}")).collect_vec())
+        });
+        let saved_names = song_key
+            .get()
+            .map(|song_key| SongSettings::load(&song_key).voice_names)
+            .unwrap_or_default();
+        if saved_names.len() == default_names.len() {
+            saved_names
+        } else {
+            default_names
+        }
     });
     let voice_states = Memo::new_owning(move |previous_voice_states: Option<Vec<VoiceState>>| {
-        let num_voices = num_voices.get();
+        let voice_names = voice_names.get();
         if let Some(previous_voice_states) = previous_voice_states {
-            if previous_voice_states.len() == num_voices {
-                return (previous_voice_states, false);
+            // Keep the mixer settings if we can, it's likely the same singers.
+            if previous_voice_states.len() == voice_names.len() {
+                let voice_states = previous_voice_states
+                    .iter()
+                    .zip(voice_names)
+                    .map(|(vs, name)| vs.renamed(name))
+                    .collect_vec();
+                return (voice_states, true);
             }
         }
-        (create_voice_states(num_voices), true)
+        (
+            voice_names.into_iter().map(VoiceState::new).collect_vec(),
+            true,
+        )
     });
+    let save_voice_names = move || {
+        let Some(song_key) = song_key.get_untracked() else {
+            return;
+        };
+        let mut song_settings = SongSettings::load(&song_key);
+        song_settings.voice_names = voice_states
+            .with_untracked(|vss| vss.iter().map(|vs| vs.name.get_untracked()).collect());
+        song_settings.save(&song_key);
+    };
     let any_voice_solo =
         Signal::derive(move || voice_states.with(|vss| vss.iter().any(|vs| vs.solo.get())));
 
@@ -182,11 +513,83 @@ pub fn App() -> impl IntoView {
     // Mirror/translate the settings into the playback manager
     Effect::new(move |_| {
         if let (Some(playback_manager), Some(song_data)) =
-            (&*playback_manager.read(), &*song_data.read())
+            (&*playback_manager.read(), &*timeline_song_data.read())
         {
             playback_manager.write().set_song_data(song_data.clone());
         }
     });
+
+    // The song whose settings have been restored, so we don't save over them with whatever the
+    // last song had before they're restored.
+    let restored_song_key = StoredValue::new(None::<String>);
+    let apply_song_settings = move |settings: &SongSettings| {
+        voice_states.with_untracked(|voice_states| {
+            if settings.voice_mixes.len() == voice_states.len() {
+                for (voice_state, mix) in voice_states.iter().zip(&settings.voice_mixes) {
+                    voice_state.set_mix(*mix);
+                }
+            }
+        });
+        set_overall_volume.set(settings.overall_volume);
+        zoom.set(settings.zoom);
+        original_linebreaks.set(settings.original_line_breaks);
+        let start = timeline_song_data
+            .with_untracked(|song_data| {
+                song_data
+                    .as_ref()?
+                    .slices
+                    .iter()
+                    .position(|s| s.cursor_index == settings.start_cursor_index)
+            })
+            .unwrap_or(0);
+        start_song_index.set(start);
+        most_recent_song_index.set(start);
+    };
+    // New song data means a song has finished loading.
+    Effect::new(move |_| {
+        song_data.track();
+        let Some(song_key) = song_key.get_untracked() else {
+            return;
+        };
+        apply_song_settings(&SongSettings::load(&song_key));
+        restored_song_key.set_value(Some(song_key));
+    });
+    Effect::new(move |_| {
+        let voice_mixes = voice_states.with(|vss| vss.iter().map(VoiceState::mix).collect_vec());
+        let overall_volume = overall_volume.get();
+        let start_cursor_index = timeline_song_data.with(|song_data| {
+            song_data
+                .as_ref()?
+                .slices
+                .get(start_song_index.get())
+                .map(|s| s.cursor_index)
+        });
+        let zoom = zoom.get();
+        let original_line_breaks = original_linebreaks.get();
+        let Some(song_key) = song_key.get_untracked() else {
+            return;
+        };
+        if restored_song_key.with_value(|restored| restored.as_ref() != Some(&song_key)) {
+            return;
+        }
+        let mut song_settings = SongSettings::load(&song_key);
+        song_settings.voice_mixes = voice_mixes;
+        song_settings.overall_volume = overall_volume;
+        song_settings.start_cursor_index = start_cursor_index.unwrap_or(0);
+        song_settings.zoom = zoom;
+        song_settings.original_line_breaks = original_line_breaks;
+        song_settings.save(&song_key);
+    });
+    let reset_song_settings = move || {
+        let Some(song_key) = song_key.get_untracked() else {
+            return;
+        };
+        let mut song_settings = SongSettings::load(&song_key);
+        song_settings.reset_to_defaults();
+        song_settings.voice_mixes =
+            vec![VoiceMix::default(); voice_states.with_untracked(Vec::len)];
+        apply_song_settings(&song_settings);
+    };
     Effect::new(move |_| {
         if let Some(playback_manager) = &*playback_manager.read() {
             playback_manager.write().set_overall_gain(volume_to_gain(
@@ -197,6 +600,36 @@ pub fn App() -> impl IntoView {
         }
     });
 
+    Effect::new(move |_| {
+        if let Some(playback_manager) = &*playback_manager.read() {
+            playback_manager
+                .write()
+                .set_articulation_mode(articulation_mode.get());
+        }
+    });
+
+    Effect::new(move |_| {
+        if let Some(playback_manager) = &*playback_manager.read() {
+            playback_manager.write().set_tuning(tuning.get());
+        }
+    });
+
+    Effect::new(move |_| {
+        if let Some(playback_manager) = &*playback_manager.read() {
+            let mut playback_manager = playback_manager.write();
+            playback_manager.set_reference_pitch(reference_pitch.get());
+            playback_manager.set_temperament(temperament.get());
+        }
+    });
+
+    Effect::new(move |_| {
+        if let Some(playback_manager) = &*playback_manager.read() {
+            playback_manager
+                .write()
+                .set_transpose(playback_transpose.get());
+        }
+    });
+
     Effect::new(move |_| {
         for (voice, voice_state) in voice_states.get().into_iter().enumerate() {
             if let Some(playback_manager) = &*playback_manager.read() {
@@ -208,6 +641,55 @@ pub fn App() -> impl IntoView {
         }
     });
 
+    Effect::new(move |_| {
+        for (voice, voice_state) in voice_states.get().into_iter().enumerate() {
+            if let Some(playback_manager) = &*playback_manager.read() {
+                playback_manager
+                    .write()
+                    .set_voice_pan(voice, *voice_state.pan.read() as f32 / 100.0);
+            }
+        }
+    });
+
+    Effect::new(move |_| {
+        for (voice, voice_state) in voice_states.get().into_iter().enumerate() {
+            if let Some(playback_manager) = &*playback_manager.read() {
+                playback_manager
+                    .write()
+                    .set_voice_instrument(voice, instrument_preset(voice_state.instrument.get()));
+            }
+        }
+    });
+
+    let sample_load_warning = Signal::derive(move || {
+        let playback_manager = (*playback_manager.read())?;
+        let playback_manager = playback_manager.read();
+        let report = playback_manager.sample_load_report();
+        if report.failures.is_empty() {
+            None
+        } else if playback_manager.using_fallback_piano() {
+            Some(
+                "Unable to load the piano samples, playing the piano with a basic synth instead."
+                    .to_string(),
+            )
+        } else {
+            let ranges = report
+                .degraded_ranges
+                .iter()
+                .map(|range| {
+                    format!(
+                        "{}-{}",
+                        midi_note_name(*range.start()),
+                        midi_note_name(*range.end())
+                    )
+                })
+                .join(", ");
+            Some(format!(
+                "Some piano samples failed to load, notes in {ranges} may not sound right."
+            ))
+        }
+    });
+
     let is_loading = Signal::derive(move || {
         playback_manager.with(|pm| pm.is_none()) || song_data.with(|song_data| song_data.is_none())
     });
@@ -221,8 +703,21 @@ pub fn App() -> impl IntoView {
                 most_recent_song_index=most_recent_song_index
                 set_start_cursor_index=set_start_cursor_index
                 set_current_cursor_index=set_current_cursor_index
+                loop_region=loop_region
+                keymap=keymap
                 on_reset_song=on_reset_song
             />
+            <MidiListener
+                playback_manager=playback_manager
+                active_voices=active_voices
+                start_song_index=start_song_index
+                loop_region=loop_region
+                most_recent_song_index=most_recent_song_index
+                set_current_cursor_index=set_current_cursor_index
+            />
+            {move || {
+                sample_load_warning.get().map(|warning| view! { <p class="text-red-600">{warning}</p> })
+            }}
             <br />
             <div class="flex flex-row items-baseline space-x-1">
                 <p>"Pick a song:"</p>
@@ -230,23 +725,74 @@ pub fn App() -> impl IntoView {
                     class="border"
                     on:change:target=move |ev| {
                         let new_value = ev.target().value();
-                        set_song_choice
-                            .set(SongChoice::BuiltIn {
-                                name: new_value,
-                            });
+                        if let Some(id) = new_value.strip_prefix(LIBRARY_CHOICE_PREFIX) {
+                            set_song_choice
+                                .set(SongChoice::Library {
+                                    id: id.to_string(),
+                                });
+                        } else if let Some((_, choice)) = built_in_song_choices()
+                            .into_iter()
+                            .find(|(value, _)| *value == new_value)
+                        {
+                            set_song_choice.set(choice);
+                        }
                     }
                 >
 
-                    {SONGS
-                        .iter()
-                        .map(|&song_option| {
+                    {built_in_song_choices()
+                        .into_iter()
+                        .map(|(value, choice)| {
+                            let label = match &choice {
+                                SongChoice::BuiltInMidi { name } => format!("{name} (MIDI)"),
+                                SongChoice::BuiltIn { name } => name.clone(),
+                                SongChoice::Uploaded { .. } | SongChoice::Library { .. } => {
+                                    unreachable!()
+                                }
+                            };
+                            let selected_value = Some(value.clone());
                             view! {
-                                <option selected={SONGS[0] == song_option} value=song_option>
-                                    {song_option}
+                                <option
+                                    selected=move || {
+                                        song_choice.with(SongChoice::select_value) == selected_value
+                                    }
+                                    value=value
+                                >
+                                    {label}
                                 </option>
                             }
                         })
                         .collect_vec()}
+                    {move || {
+                        (!library_entries.with(Vec::is_empty))
+                            .then(|| {
+                                view! {
+                                    <optgroup label="Your library">
+                                        {library_entries
+                                            .get()
+                                            .into_iter()
+                                            .map(|entry| {
+                                                let value = SongChoice::Library {
+                                                    id: entry.id,
+                                                }
+                                                    .select_value();
+                                                let selected_value = value.clone();
+                                                view! {
+                                                    <option
+                                                        selected=move || {
+                                                            song_choice.with(SongChoice::select_value)
+                                                                == selected_value
+                                                        }
+                                                        value=value
+                                                    >
+                                                        {entry.title}
+                                                    </option>
+                                                }
+                                            })
+                                            .collect_vec()}
+                                    </optgroup>
+                                }
+                            })
+                    }}
                 </select>
             </div>
             <div class="flex flex-row items-baseline space-x-1">
@@ -254,29 +800,139 @@ pub fn App() -> impl IntoView {
                 <input
                     node_ref=file_input_ref
                     type="file"
-                    accept=".xml,.mxl,.musicxml"
+                    accept=".xml,.mxl,.musicxml,.mid,.midi"
                     multiple=false
                     on:change=move |_| {
                         let input = file_input_ref.get().unwrap();
                         let Some(files) = input.files() else { return };
                         let Some(file) = files.get(0) else { return };
-                        set_song_choice.set(SongChoice::Uploaded { file })
+                        spawn_local(async move {
+                            // Keep scores in the library so they're there next time. MIDI files
+                            // don't have much to list them by, so they're just played.
+                            let data = read_file_bytes(&file).await;
+                            if !data.starts_with(MIDI_MAGIC) {
+                                match song_library::add(&file.name(), &data).await {
+                                    Ok(entry) => {
+                                        let id = entry.id.clone();
+                                        library_entries
+                                            .update(|entries| {
+                                                entries.retain(|e| e.id != entry.id);
+                                                entries.push(entry);
+                                                entries
+                                                    .sort_by_key(|e| e.title.to_lowercase());
+                                            });
+                                        set_song_choice.set(SongChoice::Library { id });
+                                        return;
+                                    }
+                                    Err(e) => warn!("Not adding the song to the library: {e}"),
+                                }
+                            }
+                            set_song_choice.set(SongChoice::Uploaded { file });
+                        });
                     }
                 />
 
             </div>
+            <LibraryControls
+                entries=library_entries
+                on_open=Callback::new(move |id: String| {
+                    set_song_choice.set(SongChoice::Library { id })
+                })
+            />
             <div class="flex flex-row space-x-1">
                 {move || {
                     voice_states
                         .get()
                         .into_iter()
                         .map(|vs| {
-                            view! { <VoiceControl voice_state=vs any_voice_solo=any_voice_solo /> }
+                            view! {
+                                <VoiceControl
+                                    voice_state=vs
+                                    any_voice_solo=any_voice_solo
+                                    instrument_names=instrument_names
+                                    on_rename=save_voice_names
+                                />
+                            }
                         })
                         .collect_vec()
                 }}
 
             </div>
+            <div class="flex flex-row items-baseline space-x-1">
+                <p>"Stereo placement:"</p>
+                <select
+                    class="border"
+                    on:change:target=move |ev| {
+                        if let Ok(preset) = ev.target().value().parse() {
+                            set_pan_preset.set(preset);
+                        }
+                    }
+                >
+                    {PAN_PRESETS
+                        .iter()
+                        .enumerate()
+                        .map(|(index, (name, _))| {
+                            view! {
+                                <option value=index selected=move || pan_preset.get() == index>
+                                    {*name}
+                                </option>
+                            }
+                        })
+                        .collect_vec()}
+                </select>
+                {move || {
+                    PAN_PRESETS[pan_preset.get()]
+                        .1
+                        .uses_focus_voice()
+                        .then(|| {
+                            view! {
+                                <p>"My part:"</p>
+                                <select
+                                    class="border"
+                                    on:change:target=move |ev| {
+                                        if let Ok(voice) = ev.target().value().parse() {
+                                            set_focus_voice.set(voice);
+                                        }
+                                    }
+                                >
+                                    {move || {
+                                        voice_states
+                                            .get()
+                                            .into_iter()
+                                            .enumerate()
+                                            .map(|(voice, vs)| {
+                                                view! {
+                                                    <option
+                                                        value=voice
+                                                        selected=move || focus_voice.get() == voice
+                                                    >
+                                                        {vs.name}
+                                                    </option>
+                                                }
+                                            })
+                                            .collect_vec()
+                                    }}
+                                </select>
+                            }
+                        })
+                }}
+                <button
+                    class="border border-black rounded-sm px-1"
+                    on:click=move |_| {
+                        voice_states
+                            .with(|voice_states| {
+                                let positions = PAN_PRESETS[pan_preset.get()]
+                                    .1
+                                    .positions(voice_states.len(), focus_voice.get());
+                                for (voice_state, pan) in voice_states.iter().zip(positions) {
+                                    voice_state.pan.set((pan * 100.0).round() as i32);
+                                }
+                            });
+                    }
+                >
+                    "Apply"
+                </button>
+            </div>
             <div class="flex flex-row space-x-1">
                 <p>"Overall Volume:"</p>
                 <input
@@ -289,16 +945,330 @@ pub fn App() -> impl IntoView {
                 />
 
             </div>
+            <div class="flex flex-row items-baseline space-x-1">
+                <p>"This song's volumes, start position, zoom and line breaks are remembered."</p>
+                <button
+                    class="border border-black rounded-sm px-1"
+                    on:click=move |_| reset_song_settings()
+                >
+                    "Reset to defaults"
+                </button>
+            </div>
+            <div class="flex flex-row items-baseline space-x-1">
+                <p>"Load an instrument (.sf2, or .sfz with its samples):"</p>
+                <input
+                    node_ref=instrument_input_ref
+                    type="file"
+                    accept=".sf2,.sfz,.wav,.flac,.ogg,.mp3"
+                    multiple=true
+                    on:change=move |_| {
+                        let input: web_sys::HtmlInputElement = instrument_input_ref.get().unwrap();
+                        let Some(files) = input.files() else { return };
+                        let files = (0..files.length())
+                            .filter_map(|index| files.get(index))
+                            .collect_vec();
+                        let Some(playback_manager) = *playback_manager.read() else { return };
+                        spawn_local(async move {
+                            let ctx = playback_manager.read_untracked().audio_context();
+                            match load_instrument_files(&ctx, files).await {
+                                Ok(instruments) => {
+                                    for (name, sampler) in instruments {
+                                        playback_manager.write().add_instrument(Rc::new(sampler));
+                                        set_loaded_instrument_names
+                                            .update(|names| names.push(name));
+                                    }
+                                    set_instrument_error.set(None);
+                                }
+                                Err(e) => set_instrument_error.set(Some(e)),
+                            }
+                        });
+                    }
+                />
+                {move || instrument_error.get().map(|e| view! { <p class="text-red-600">{e}</p> })}
+            </div>
+            <label class="flex flex-row items-baseline space-x-1">
+                <input
+                    type="checkbox"
+                    prop:checked=move || articulation_mode.get() == ArticulationMode::NewOnsetsOnly
+                    on:change:target=move |ev| {
+                        set_articulation_mode
+                            .set(
+                                if ev.target().checked() {
+                                    ArticulationMode::NewOnsetsOnly
+                                } else {
+                                    ArticulationMode::RestrikeAll
+                                },
+                            );
+                    }
+                />
+                <p>"Let held notes ring instead of replaying them"</p>
+            </label>
+            <label class="flex flex-row items-baseline space-x-1">
+                <input
+                    type="checkbox"
+                    prop:checked=move || playback_order.get() == PlaybackOrder::Performed
+                    on:change:target=move |ev| {
+                        change_playback_order(
+                            if ev.target().checked() {
+                                PlaybackOrder::Performed
+                            } else {
+                                PlaybackOrder::Written
+                            },
+                        );
+                    }
+                />
+                <p>"Follow repeats and jumps (D.C., D.S., Coda)"</p>
+            </label>
+            <div class="flex flex-row items-baseline space-x-1">
+                <p>"Tuning:"</p>
+                <select
+                    class="border"
+                    on:change:target=move |ev| {
+                        if let Ok(preset) = ev.target().value().parse() {
+                            set_tuning_preset.set(preset);
+                        }
+                    }
+                >
+                    {TUNING_PRESETS
+                        .iter()
+                        .enumerate()
+                        .map(|(index, (name, _))| {
+                            view! {
+                                <option value=index selected=move || tuning_preset.get() == index>
+                                    {*name}
+                                </option>
+                            }
+                        })
+                        .collect_vec()}
+                </select>
+                {move || {
+                    matches!(
+                        tuning.get(),
+                        Tuning::Just { reference: TuningReference::FixedTonic(_), .. }
+                    )
+                        .then(|| {
+                            view! {
+                                <p>"Tonic:"</p>
+                                <select
+                                    class="border"
+                                    on:change:target=move |ev| {
+                                        if let Ok(tonic) = ev.target().value().parse() {
+                                            set_tonic.set(tonic);
+                                        }
+                                    }
+                                >
+                                    {PITCH_CLASS_NAMES
+                                        .iter()
+                                        .enumerate()
+                                        .map(|(pitch_class, name)| {
+                                            view! {
+                                                <option
+                                                    value=pitch_class
+                                                    selected=move || tonic.get() == pitch_class as u32
+                                                >
+                                                    {*name}
+                                                </option>
+                                            }
+                                        })
+                                        .collect_vec()}
+                                </select>
+                            }
+                        })
+                }}
+            </div>
+            <div class="flex flex-row items-baseline space-x-1">
+                <p>"A4 ="</p>
+                <input
+                    class="border w-20"
+                    type="number"
+                    min=380
+                    max=480
+                    step=0.1
+                    prop:value=reference_pitch
+                    on:change:target=move |ev| {
+                        if let Ok(hz) = ev.target().value().parse::<f64>() {
+                            if hz > 0.0 {
+                                set_reference_pitch.set(hz);
+                            }
+                        }
+                    }
+                />
+                <p>"Hz"</p>
+                {move || {
+                    (tuning.get() == Tuning::Equal)
+                        .then(|| {
+                            view! {
+                                <p>"Temperament:"</p>
+                                <select
+                                    class="border"
+                                    on:change:target=move |ev| {
+                                        let value = ev.target().value();
+                                        set_temperament
+                                            .set(
+                                                match value.as_str() {
+                                                    "scala" => scala_temperament.get_untracked(),
+                                                    index => {
+                                                        index
+                                                            .parse::<usize>()
+                                                            .ok()
+                                                            .and_then(|index| HISTORICAL_TEMPERAMENTS.get(index))
+                                                            .map(|(name, cents)| {
+                                                                Temperament::from_cents_above_c(name, cents)
+                                                            })
+                                                    }
+                                                },
+                                            );
+                                    }
+                                >
+                                    <option value="equal" selected=move || temperament.read().is_none()>
+                                        "Equal temperament"
+                                    </option>
+                                    {HISTORICAL_TEMPERAMENTS
+                                        .iter()
+                                        .enumerate()
+                                        .map(|(index, (name, _))| {
+                                            view! {
+                                                <option
+                                                    value=index
+                                                    selected=move || {
+                                                        temperament
+                                                            .read()
+                                                            .as_ref()
+                                                            .is_some_and(|t| t.name == *name)
+                                                    }
+                                                >
+                                                    {*name}
+                                                </option>
+                                            }
+                                        })
+                                        .collect_vec()}
+                                    {move || {
+                                        scala_temperament
+                                            .get()
+                                            .map(|scala_temperament| {
+                                                let selected = temperament.get().as_ref()
+                                                    == Some(&scala_temperament);
+                                                view! {
+                                                    <option value="scala" selected=selected>
+                                                        {scala_temperament.name}
+                                                    </option>
+                                                }
+                                            })
+                                    }}
+                                </select>
+                                <p>"Load Scala file (.scl, .kbm):"</p>
+                                <input
+                                    node_ref=scala_input_ref
+                                    type="file"
+                                    accept=".scl,.kbm"
+                                    multiple=true
+                                    on:change=move |_| {
+                                        let input: web_sys::HtmlInputElement = scala_input_ref
+                                            .get()
+                                            .unwrap();
+                                        let Some(files) = input.files() else { return };
+                                        let files = (0..files.length())
+                                            .filter_map(|index| files.get(index))
+                                            .collect_vec();
+                                        spawn_local(async move {
+                                            match load_scala_temperament(files).await {
+                                                Ok(loaded) => {
+                                                    set_scala_temperament.set(Some(loaded.clone()));
+                                                    set_temperament.set(Some(loaded));
+                                                    set_scala_error.set(None);
+                                                }
+                                                Err(e) => set_scala_error.set(Some(e)),
+                                            }
+                                        });
+                                    }
+                                />
+                                {move || scala_error.get().map(|e| view! { <p class="text-red-600">{e}</p> })}
+                            }
+                        })
+                }}
+            </div>
+            <div class="flex flex-row items-baseline space-x-1">
+                <p>"Transpose playback by"</p>
+                <input
+                    class="border w-14"
+                    type="number"
+                    min=-12
+                    max=12
+                    prop:value=playback_transpose
+                    on:change:target=move |ev| {
+                        if let Ok(semitones) = ev.target().value().parse::<i32>() {
+                            set_playback_transpose.set(semitones.clamp(-12, 12));
+                        }
+                    }
+                />
+                <p>"semitones, and the sheet music by"</p>
+                <input
+                    class="border w-14"
+                    type="number"
+                    min=-12
+                    max=12
+                    prop:value=notation_transpose
+                    on:change:target=move |ev| {
+                        if let Ok(semitones) = ev.target().value().parse::<i32>() {
+                            set_notation_transpose.set(semitones.clamp(-12, 12));
+                        }
+                    }
+                />
+                <p>"semitones"</p>
+                <button
+                    class="border border-black rounded-sm px-1"
+                    on:click=move |_| set_notation_transpose.set(playback_transpose.get_untracked())
+                >
+                    "Match the sheet music to playback"
+                </button>
+            </div>
+            <TransportControls
+                playback_manager=playback_manager
+                active_voices=active_voices
+                song_data=timeline_song_data
+                start_song_index=start_song_index
+                most_recent_song_index=most_recent_song_index
+                tempo_override=tempo_override
+                loop_region=loop_region
+                set_current_cursor_index=set_current_cursor_index
+                on_reset_song=on_reset_song
+                on_change_order=on_change_order
+            />
+            <ExportControls
+                playback_manager=playback_manager
+                active_voices=active_voices
+                song_data=timeline_song_data
+                start_song_index=start_song_index
+                tempo_override=tempo_override
+                transpose=playback_transpose
+                song_title=Signal::derive(move || {
+                    library_entries.with(|library| song_choice.with(|choice| choice.title(library)))
+                })
+            />
             <div class="relative w-full h-full">
                 // We always want this to be here so it can layout properly in the background,
                 // but sometimes we overlay it with a loading div.
                 <SheetMusic
                     active_voices=active_voices
-                    song_data=song_data
+                    song_data=timeline_song_data
+                    start_song_index=start_song_index
+                    current_song_index=most_recent_song_index
                     start_cursor_index=start_cursor_index
                     current_cursor_index=current_cursor_index
                     song_raw_data=song_raw_data
                     set_song_data=set_song_data
+                    keymap=keymap
+                    notation_transpose=notation_transpose
+                    zoom=zoom
+                    original_linebreaks=original_linebreaks
+                    on_click_position=Callback::new(move |song_index: usize| {
+                        start_song_index.set(song_index);
+                        most_recent_song_index.set(song_index);
+                    })
+                    on_shift_click_position=Callback::new(move |song_index: usize| {
+                        loop_region
+                            .set(Some(LoopRegion::new(start_song_index.get_untracked(), song_index)));
+                    })
                 />
 
                 {move || {
@@ -320,6 +1290,7 @@ pub fn App() -> impl IntoView {
                 start_song_index=start_song_index
                 most_recent_song_index=most_recent_song_index
                 set_current_cursor_index=set_current_cursor_index
+                loop_region=loop_region
             />
         </div>
     }
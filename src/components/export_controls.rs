@@ -0,0 +1,217 @@
+use bit_set::BitSet;
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+
+use crate::html_util::download_bytes;
+use crate::playback_manager::PlaybackManager;
+use crate::render::{song_range_for_measures, zip_files, Excerpt, OfflineRender};
+use crate::song_data::SongData;
+
+/// Renders a range of measures to WAV, either mixed down or as a stem per voice, or exports the
+/// whole song as MIDI.
+#[component]
+pub fn ExportControls(
+    playback_manager: LocalResource<RwSignal<PlaybackManager, LocalStorage>>,
+    #[prop(into)] active_voices: Signal<BitSet>,
+    #[prop(into)] song_data: Signal<Option<SongData>>,
+    #[prop(into)] start_song_index: Signal<usize>,
+    #[prop(into)] tempo_override: Signal<Option<f64>>,
+    /// In semitones, the same as playback.
+    #[prop(into)]
+    transpose: Signal<i32>,
+    /// Used to name the exported files.
+    #[prop(into)]
+    song_title: Signal<String>,
+) -> impl IntoView {
+    // If unset, we go from the start position through to the end of the song.
+    let (from_measure, set_from_measure) = signal::<Option<usize>>(None);
+    let (to_measure, set_to_measure) = signal::<Option<usize>>(None);
+    let (is_rendering, set_is_rendering) = signal(false);
+    let (export_error, set_export_error) = signal::<Option<String>>(None);
+    // Measure numbers from one song don't mean anything in the next.
+    Effect::new(move |_| {
+        song_data.track();
+        set_from_measure.set(None);
+        set_to_measure.set(None);
+    });
+
+    let default_measures = Memo::new(move |_| {
+        song_data.with(|song_data| {
+            let song_data = song_data.as_ref()?;
+            let measure_of = |song_index: usize| {
+                let slice = song_data.slices.get(song_index)?;
+                Some(song_data.measure_and_beat(slice.timestamp).0)
+            };
+            Some((
+                measure_of(start_song_index.get())?,
+                measure_of(song_data.slices.len().checked_sub(1)?)?,
+            ))
+        })
+    });
+
+    let export = move |stems: bool| {
+        let Some(playback_manager) = *playback_manager.read_untracked() else {
+            return;
+        };
+        let Some((default_from, default_to)) = default_measures.get_untracked() else {
+            return;
+        };
+        let from = from_measure.get_untracked().unwrap_or(default_from);
+        let to = to_measure.get_untracked().unwrap_or(default_to);
+        let Some((song_range, voice_names)) = song_data.with_untracked(|song_data| {
+            song_data.as_ref().map(|song_data| {
+                (
+                    song_range_for_measures(song_data, from..=to),
+                    song_data.voice_names.clone(),
+                )
+            })
+        }) else {
+            return;
+        };
+        if song_range.is_empty() {
+            set_export_error.set(Some(format!("There are no notes in measures {from}-{to}")));
+            return;
+        }
+        let excerpt = Excerpt {
+            song_range,
+            tempo_bpm: tempo_override.get_untracked(),
+        };
+        let active_voices = active_voices.get_untracked();
+        let file_stem = format!("{} (mm. {from}-{to})", song_title.get_untracked());
+
+        let render =
+            OfflineRender::new(&playback_manager.read_untracked(), &excerpt, &active_voices);
+        let render = match render {
+            Ok(render) => render,
+            Err(e) => {
+                set_export_error.set(Some(format!("Unable to export: {e:?}")));
+                return;
+            }
+        };
+
+        set_is_rendering.set(true);
+        set_export_error.set(None);
+        spawn_local(async move {
+            let rendered = if stems {
+                render.render_stems().await
+            } else {
+                render.render_mix().await.map(|mix| vec![(0, mix)])
+            };
+            let rendered = match rendered {
+                Ok(rendered) if !stems && rendered.iter().all(|(_, mix)| mix.peak() == 0.0) => {
+                    set_export_error.set(Some(
+                        "Nothing to export, check which voices are muted".to_string(),
+                    ));
+                    set_is_rendering.set(false);
+                    return;
+                }
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    set_export_error.set(Some(format!("Unable to render: {e:?}")));
+                    set_is_rendering.set(false);
+                    return;
+                }
+            };
+            let files = rendered
+                .into_iter()
+                .map(|(voice, rendered)| {
+                    let file_name = if stems {
+                        let voice_name = voice_names
+                            .get(voice)
+                            .cloned()
+                            .unwrap_or_else(|| format!("Voice {}", voice + 1));
+                        format!("{file_stem} - {voice_name}.wav")
+                    } else {
+                        format!("{file_stem}.wav")
+                    };
+                    (file_name, rendered.to_wav())
+                })
+                .collect::<Vec<_>>();
+
+            let downloaded = if stems {
+                zip_files(&files)
+                    .map_err(|e| format!("Unable to zip the stems: {e}"))
+                    .and_then(|zip| {
+                        download_bytes(&format!("{file_stem}.zip"), "application/zip", &zip)
+                            .map_err(|e| format!("Unable to download: {e:?}"))
+                    })
+            } else {
+                files
+                    .first()
+                    .map(|(name, wav)| download_bytes(name, "audio/wav", wav))
+                    .transpose()
+                    .map(|_| ())
+                    .map_err(|e| format!("Unable to download: {e:?}"))
+            };
+            if let Err(e) = downloaded {
+                set_export_error.set(Some(e));
+            }
+            set_is_rendering.set(false);
+        });
+    };
+
+    let export_midi = move || {
+        let Some(midi) = song_data.with_untracked(|song_data| {
+            song_data.as_ref().map(|song_data| {
+                song_data.to_midi(
+                    &active_voices.get_untracked(),
+                    transpose.get_untracked(),
+                    tempo_override.get_untracked(),
+                )
+            })
+        }) else {
+            return;
+        };
+        let file_name = format!("{}.mid", song_title.get_untracked());
+        if let Err(e) = download_bytes(&file_name, "audio/midi", &midi) {
+            set_export_error.set(Some(format!("Unable to download: {e:?}")));
+        }
+    };
+
+    let measure_input = move |value: ReadSignal<Option<usize>>,
+                              set_value: WriteSignal<Option<usize>>,
+                              default: fn((usize, usize)) -> usize| {
+        view! {
+            <input
+                class="border w-16"
+                type="number"
+                min="1"
+                placeholder=move || {
+                    default_measures.get().map(|measures| default(measures).to_string())
+                }
+                prop:value=move || value.get().map(|measure| measure.to_string())
+                on:change:target=move |ev| {
+                    set_value.set(ev.target().value().parse::<usize>().ok().filter(|m| *m > 0));
+                }
+            />
+        }
+    };
+
+    view! {
+        <div class="flex flex-row items-baseline space-x-1">
+            <p>"Export measures"</p>
+            {measure_input(from_measure, set_from_measure, |(from, _)| from)}
+            <p>"to"</p>
+            {measure_input(to_measure, set_to_measure, |(_, to)| to)}
+            <button
+                class="border border-black rounded-sm px-1"
+                disabled=is_rendering
+                on:click=move |_| export(false)
+            >
+                "WAV"
+            </button>
+            <button
+                class="border border-black rounded-sm px-1"
+                disabled=is_rendering
+                on:click=move |_| export(true)
+            >
+                "Stems (zip)"
+            </button>
+            <button class="border border-black rounded-sm px-1" on:click=move |_| export_midi()>
+                "Whole song as MIDI"
+            </button>
+            {move || is_rendering.get().then(|| view! { <p>"Rendering..."</p> })}
+        </div>
+        {move || export_error.get().map(|e| view! { <p class="text-red-600">{e}</p> })}
+    }
+}
@@ -1,16 +1,18 @@
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use bit_set::BitSet;
+use itertools::Itertools;
 use leptos::ev;
 use leptos::prelude::codee::string::FromToStringCodec;
 use leptos::prelude::*;
 use leptos_use::storage::use_local_storage;
 
+use crate::components::keymap_editor::KeymapEditor;
+use crate::instrument::PlaybackGuard;
+use crate::keymap::{Keymap, KeymapAction};
+use crate::loop_region::{wrap_song_index, LoopRegion};
 use crate::playback_manager::PlaybackManager;
-use crate::sampler::SamplerPlaybackGuard;
-
-pub const LETTERS: &str = "qwerasdfzxcvuiopjkl;m,./";
-// pub const LETTERS: &str = "qwertyuiopasdfghjkl;zxcvbnm,./";
 
 #[component]
 pub fn KeyboardListener(
@@ -20,11 +22,16 @@ pub fn KeyboardListener(
     most_recent_song_index: RwSignal<usize>,
     set_start_cursor_index: WriteSignal<usize>,
     set_current_cursor_index: WriteSignal<usize>,
+    loop_region: RwSignal<Option<LoopRegion>>,
+    keymap: RwSignal<Keymap>,
     // Lets us know when to reset things.
     #[prop(into)] on_reset_song: Trigger,
 ) -> impl IntoView {
+    // Keyed by `KeyboardEvent.code`, so they're released by the same key that started them.
     let (_, set_held_notes) =
-        signal_local::<HashMap<String, Vec<SamplerPlaybackGuard>>>(HashMap::new());
+        signal_local::<HashMap<String, Vec<Rc<PlaybackGuard>>>>(HashMap::new());
+    // The binding waiting for a key press to change it, if any, from the editor.
+    let rebinding = RwSignal::new(None::<usize>);
     // Reset the indices when we have a new song.
     Effect::new(move |_| {
         on_reset_song.track(); // This will re-trigger the effect.
@@ -54,6 +61,25 @@ pub fn KeyboardListener(
         });
     });
 
+    let play_at_index = move |song_index: usize, code: String| {
+        let playback_manager = playback_manager.read();
+        let active_voices = active_voices.read();
+        let Some(playback_manager) = &*playback_manager else {
+            return;
+        };
+        let Some((cursor_index, newly_held_notes)) = playback_manager
+            .write()
+            .start_notes_at_relative_index(song_index, &active_voices, 1.0)
+        else {
+            return;
+        };
+        set_current_cursor_index.set(cursor_index);
+
+        set_held_notes.update(|held_notes| {
+            held_notes.insert(code, newly_held_notes);
+        });
+    };
+
     let keydown_handle = window_event_listener(ev::keydown, move |event| {
         let has_modifier =
             event.meta_key() || event.ctrl_key() || event.shift_key() || event.alt_key();
@@ -61,18 +87,34 @@ pub fn KeyboardListener(
             return;
         }
 
-        // First check if this is a key press that we want to do something with
-        let Some(action) = get_no_modifiers_key_action(
-            event.key(),
-            playback_manager,
-            active_voices,
-            set_held_notes,
-            start_song_index,
-            most_recent_song_index,
-            set_current_cursor_index,
-        ) else {
+        // While the editor is waiting for a key, the key goes to it instead.
+        if let Some(index) = rebinding.get_untracked() {
+            event.prevent_default();
+            if event.code() != "Escape" {
+                keymap.update(|keymap| {
+                    keymap.rebind(index, event.code());
+                    keymap.save();
+                });
+            }
+            rebinding.set(None);
+            return;
+        }
+
+        // First check if this is a key press that we want to do something with. This goes by where
+        // the key is rather than what it types, so the keys stay in the same place on any layout and
+        // Caps Lock doesn't matter.
+        let Some(action) = keymap.with_untracked(|keymap| keymap.action_for_code(&event.code()))
+        else {
             return;
         };
+        let action = get_key_action(
+            action,
+            event.code(),
+            play_at_index,
+            start_song_index,
+            most_recent_song_index,
+            loop_region,
+        );
 
         // If so, prevent default so we don't do things like scroll the page with space
         event.prevent_default();
@@ -88,19 +130,24 @@ pub fn KeyboardListener(
     });
     on_cleanup(move || keydown_handle.remove());
 
+    // Modifiers don't matter here, eg pressing shift while holding a note still needs to release it.
     let keyup_handle = window_event_listener(ev::keyup, move |event| {
-        let has_modifier =
-            event.meta_key() || event.ctrl_key() || event.shift_key() || event.alt_key();
-        if has_modifier {
-            return;
-        }
-
         set_held_notes.update(|held_notes| {
-            held_notes.remove(&event.key());
+            held_notes.remove(&event.code());
         });
     });
     on_cleanup(move || keyup_handle.remove());
 
+    let position_label = move |offset: usize| {
+        keymap.with(|keymap| {
+            keymap
+                .position_labels()
+                .into_iter()
+                .find(|(o, _)| *o == offset)
+                .map(|(_, label)| label.to_uppercase())
+        })
+    };
+
     let details_node_ref = NodeRef::new();
     let (has_seen_controls, set_has_seen_controls, _) =
         use_local_storage::<bool, FromToStringCodec>("has_seen_controls");
@@ -126,42 +173,78 @@ pub fn KeyboardListener(
             </div>
             <p>
                 "Each key on your keyboard will play the notes at a position in a song. "
-                <code class="bg-slate-200">Q</code> " is the first position, "
-                <code class="bg-slate-200">W</code> ", the second, "
-                <code class="bg-slate-200">E</code> ", the third, and so on."
-            </p>
-            <p>"The keys are designed to be played with one hand:"</p>
-            <p>
-                <code class="bg-slate-200">"Q W E R"</code>
-            </p>
-            <p>
-                <code class="bg-slate-200">"A S D F"</code>
-            </p>
-            <p>
-                <code class="bg-slate-200">"Z X C V"</code>
-            </p>
-            <p>"And then the same on the right hand:"</p>
-            <p>
-                <code class="bg-slate-200">"U I O P"</code>
-            </p>
-            <p>
-                <code class="bg-slate-200">"J K L ;"</code>
-            </p>
-            <p>
-                <code class="bg-slate-200">"M , . /"</code>
+                <code class="bg-slate-200">{move || position_label(0)}</code>
+                " is the first position, "
+                <code class="bg-slate-200">{move || position_label(1)}</code>
+                ", the second, "
+                <code class="bg-slate-200">{move || position_label(2)}</code>
+                ", the third, and so on."
             </p>
+            <Show
+                when=move || keymap.with(Keymap::matches_qwerty_picture)
+                fallback=move || {
+                    view! {
+                        <p>
+                            "The keys, in order: "
+                            <code class="bg-slate-200">
+                                {move || {
+                                    keymap
+                                        .with(|keymap| {
+                                            keymap
+                                                .position_labels()
+                                                .into_iter()
+                                                .sorted()
+                                                .map(|(_, label)| label.to_uppercase())
+                                                .join(" ")
+                                        })
+                                }}
+                            </code>
+                        </p>
+                    }
+                }
+            >
+                <p>"The keys are designed to be played with one hand:"</p>
+                <p>
+                    <code class="bg-slate-200">"Q W E R"</code>
+                </p>
+                <p>
+                    <code class="bg-slate-200">"A S D F"</code>
+                </p>
+                <p>
+                    <code class="bg-slate-200">"Z X C V"</code>
+                </p>
+                <p>"And then the same on the right hand:"</p>
+                <p>
+                    <code class="bg-slate-200">"U I O P"</code>
+                </p>
+                <p>
+                    <code class="bg-slate-200">"J K L ;"</code>
+                </p>
+                <p>
+                    <code class="bg-slate-200">"M , . /"</code>
+                </p>
+            </Show>
             <div class="flex flex-row items-baseline space-x-1">
                 <div class="size-3 h-4 w-4 rounded-full bg-startCursor"></div>
                 <h2 class="text-lg font-medium">Changing starting position</h2>
             </div>
 
             <p>
-                "The positions are relative to the current start (indicated in teal). This can be "
-                "moved to just after the most recently played note by pressing the space bar, or "
-                "can be adjusted with the left/right arrows. It can be reset with backtick ("
-                <code class="bg-slate-200">"`"</code> ")."
+                "The positions are relative to the current start (indicated in teal). By default "
+                "this can be moved to just after the most recently played note by pressing the "
+                "space bar, or can be adjusted with the left/right arrows. It can be reset with "
+                "backtick (" <code class="bg-slate-200">"`"</code> "), and enter loops from the "
+                "start to the most recently played note."
+            </p>
+            <p>
+                "You can also click a note in the score to start from there, or shift-click one to "
+                "loop from the start to that note."
             </p>
-            <img class="my-1" src="examples/keyboard.png" />
+            <Show when=move || keymap.with(Keymap::matches_qwerty_picture)>
+                <img class="my-1" src="examples/keyboard.png" />
+            </Show>
+            <h2 class="text-lg font-medium">Changing the keys</h2>
+            <KeymapEditor keymap=keymap rebinding=rebinding />
             <p>"(you can collapse these instructions by clicking on \"Controls\" above)"</p>
         </details>
     }
@@ -187,59 +270,49 @@ impl KeyAction {
     }
 }
 
-/// Returns whether this key was used/valid.
-fn get_no_modifiers_key_action(
-    key: String,
-    playback_manager: LocalResource<RwSignal<PlaybackManager, LocalStorage>>,
-    active_voices: Signal<BitSet>,
-    set_held_notes: WriteSignal<HashMap<String, Vec<SamplerPlaybackGuard>>, LocalStorage>,
+fn get_key_action(
+    action: KeymapAction,
+    code: String,
+    // Plays the given position, holding the notes until the key is released.
+    play_at_index: impl Fn(usize, String) + 'static,
     start_song_index: RwSignal<usize>,
     most_recent_song_index: RwSignal<usize>,
-    set_current_cursor_index: WriteSignal<usize>,
-) -> Option<KeyAction> {
-    let action = if key == " " {
-        KeyAction::new(move || {
-            let new_start_song_index = most_recent_song_index.get() + 1;
+    loop_region: RwSignal<Option<LoopRegion>>,
+) -> KeyAction {
+    let run = move || match action {
+        KeymapAction::PlayOffset(offset) => {
+            let song_index = wrap_song_index(loop_region.get(), start_song_index.get() + offset);
+            most_recent_song_index.set(song_index);
+            play_at_index(song_index, code);
+        }
+        KeymapAction::SetStart => {
+            let new_start_song_index =
+                wrap_song_index(loop_region.get(), most_recent_song_index.get() + 1);
             start_song_index.set(new_start_song_index);
-        })
-    } else if key == "ArrowLeft" || key == "ArrowRight" {
-        KeyAction::new_with_repeats(move || {
-            let new_start_song_index = if key == "ArrowLeft" {
-                start_song_index.get().saturating_sub(1)
-            } else {
-                start_song_index.get() + 1
-            };
+        }
+        KeymapAction::NudgeStartBack => {
+            start_song_index.set(start_song_index.get().saturating_sub(1));
+        }
+        KeymapAction::NudgeStartForward => {
+            let new_start_song_index =
+                wrap_song_index(loop_region.get(), start_song_index.get() + 1);
             start_song_index.set(new_start_song_index);
-        })
-    } else if key == "`" {
-        KeyAction::new(move || {
-            start_song_index.set(0);
-        })
-    } else if let Some(offset) = LETTERS.find(key.as_str()) {
-        KeyAction::new(move || {
-            let playback_manager = playback_manager.read();
-            let active_voices = active_voices.read();
-            let start_song_index = start_song_index.read();
-            let song_index = *start_song_index + offset;
-            most_recent_song_index.set(song_index);
-            let Some(playback_manager) = &*playback_manager else {
-                return;
-            };
-            let Some((cursor_index, newly_held_notes)) = playback_manager
-                .write()
-                .start_notes_at_relative_index(song_index, &*active_voices)
-            else {
-                return;
-            };
-            set_current_cursor_index.set(cursor_index);
-
-            set_held_notes.update(|held_notes| {
-                held_notes.insert(key, newly_held_notes);
-            });
-        })
-    } else {
-        return None;
+        }
+        KeymapAction::ResetStart => start_song_index.set(0),
+        KeymapAction::ToggleLoop => {
+            if loop_region.get().is_some() {
+                loop_region.set(None);
+            } else {
+                loop_region.set(Some(LoopRegion::new(
+                    start_song_index.get(),
+                    most_recent_song_index.get(),
+                )));
+            }
+        }
     };
-
-    Some(action)
+    if action.allows_repeats() {
+        KeyAction::new_with_repeats(run)
+    } else {
+        KeyAction::new(run)
+    }
 }
@@ -0,0 +1,127 @@
+use itertools::Itertools;
+use leptos::prelude::*;
+
+use crate::keymap::{KeyBinding, Keymap, KeymapAction, KEYMAP_PRESETS};
+
+/// Lets the user pick a preset keymap or change individual keys. The key press itself is picked up
+/// by `KeyboardListener`, which binds it to the `rebinding` index.
+#[component]
+pub fn KeymapEditor(
+    keymap: RwSignal<Keymap>,
+    /// The binding waiting for a key press.
+    rebinding: RwSignal<Option<usize>>,
+) -> impl IntoView {
+    // Index into `KEYMAP_PRESETS`.
+    let (preset, set_preset) = signal(0usize);
+    let change_keymap = move |change: &dyn Fn(&mut Keymap)| {
+        rebinding.set(None);
+        keymap.update(|keymap| {
+            change(keymap);
+            keymap.save();
+        });
+    };
+
+    view! {
+        <div class="flex flex-row items-baseline space-x-1">
+            <p>"Start from:"</p>
+            <select
+                class="border"
+                on:change:target=move |ev| {
+                    if let Ok(preset) = ev.target().value().parse() {
+                        set_preset.set(preset);
+                    }
+                }
+            >
+                {KEYMAP_PRESETS
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (name, _))| {
+                        view! {
+                            <option value=index selected=move || preset.get() == index>
+                                {*name}
+                            </option>
+                        }
+                    })
+                    .collect_vec()}
+            </select>
+            <button
+                class="border border-black rounded-sm px-1"
+                on:click=move |_| {
+                    let preset = KEYMAP_PRESETS[preset.get_untracked()].1;
+                    change_keymap(&|keymap| keymap.apply_preset(preset));
+                }
+            >
+                "Use these keys"
+            </button>
+        </div>
+        <Show when=move || keymap.with(|keymap| !keymap.detected_labels.is_empty())>
+            <p>"(The keys are labelled the way your keyboard is set up.)"</p>
+        </Show>
+        <table class="my-1">
+            {move || {
+                keymap
+                    .get()
+                    .bindings
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, KeyBinding { code, action })| {
+                        let label = if code.is_empty() {
+                            "(none)".to_string()
+                        } else {
+                            keymap.with_untracked(|keymap| keymap.label(&code))
+                        };
+                        view! {
+                            <tr>
+                                <td class="pr-2">{action.description()}</td>
+                                <td class="pr-2">
+                                    <code class="bg-slate-200">{label}</code>
+                                </td>
+                                <td>
+                                    <button
+                                        class="border border-black rounded-sm px-1"
+                                        on:click=move |_| rebinding.set(Some(index))
+                                    >
+                                        {move || {
+                                            if rebinding.get() == Some(index) {
+                                                "Press a key (escape to cancel)"
+                                            } else {
+                                                "Change"
+                                            }
+                                        }}
+                                    </button>
+                                    <button
+                                        class="border border-black rounded-sm px-1 ml-1"
+                                        on:click=move |_| {
+                                            change_keymap(&|keymap| {
+                                                keymap.bindings.remove(index);
+                                            })
+                                        }
+                                    >
+                                        "Remove"
+                                    </button>
+                                </td>
+                            </tr>
+                        }
+                    })
+                    .collect_vec()
+            }}
+        </table>
+        <button
+            class="border border-black rounded-sm px-1"
+            on:click=move |_| {
+                change_keymap(&|keymap| {
+                    keymap
+                        .bindings
+                        .push(KeyBinding {
+                            code: String::new(),
+                            action: KeymapAction::PlayOffset(keymap.next_offset()),
+                        })
+                });
+                let new_index = keymap.with_untracked(|keymap| keymap.bindings.len() - 1);
+                rebinding.set(Some(new_index));
+            }
+        >
+            "Add a key for the next position"
+        </button>
+    }
+}
@@ -0,0 +1,137 @@
+use itertools::Itertools;
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+
+use crate::html_util::download_bytes;
+use crate::song_library::{self, LibraryEntry};
+
+/// Lists the songs in the library, for opening, renaming, exporting or deleting them.
+#[component]
+pub fn LibraryControls(
+    entries: RwSignal<Vec<LibraryEntry>>,
+    /// Called with the id of the song to open.
+    #[prop(into)]
+    on_open: Callback<String>,
+) -> impl IntoView {
+    let (library_error, set_library_error) = signal::<Option<String>>(None);
+
+    let rename = move |id: String, title: String| {
+        spawn_local(async move {
+            match song_library::rename(&id, &title).await {
+                Ok(renamed) => {
+                    entries.update(|entries| {
+                        if let Some(entry) = entries.iter_mut().find(|e| e.id == renamed.id) {
+                            *entry = renamed;
+                        }
+                    });
+                    set_library_error.set(None);
+                }
+                Err(e) => set_library_error.set(Some(format!("Unable to rename: {e}"))),
+            }
+        });
+    };
+    let export = move |entry: LibraryEntry| {
+        spawn_local(async move {
+            let exported = match song_library::file_bytes(&entry.id).await {
+                Ok(data) => {
+                    // `.mxl` files are zipped, everything else is plain XML.
+                    let mime_type = if data.starts_with(b"PK") {
+                        "application/vnd.recordare.musicxml"
+                    } else {
+                        "application/vnd.recordare.musicxml+xml"
+                    };
+                    download_bytes(&entry.file_name, mime_type, &data)
+                        .map_err(|e| format!("Unable to download: {e:?}"))
+                }
+                Err(e) => Err(format!("Unable to export: {e}")),
+            };
+            set_library_error.set(exported.err());
+        });
+    };
+    let delete = move |entry: LibraryEntry| {
+        let confirmed = window()
+            .confirm_with_message(&format!("Remove \"{}\" from your library?", entry.title))
+            .unwrap_or(false);
+        if !confirmed {
+            return;
+        }
+        spawn_local(async move {
+            match song_library::delete(&entry.id).await {
+                Ok(()) => {
+                    entries.update(|entries| entries.retain(|e| e.id != entry.id));
+                    set_library_error.set(None);
+                }
+                Err(e) => set_library_error.set(Some(format!("Unable to delete: {e}"))),
+            }
+        });
+    };
+
+    view! {
+        {move || {
+            (!entries.with(Vec::is_empty))
+                .then(|| {
+                    view! {
+                        <details>
+                            <summary>"Your library"</summary>
+                            <table>
+                                {move || {
+                                    entries
+                                        .get()
+                                        .into_iter()
+                                        .map(|entry| {
+                                            let id = entry.id.clone();
+                                            let open_id = entry.id.clone();
+                                            let export_entry = entry.clone();
+                                            let delete_entry = entry.clone();
+                                            view! {
+                                                <tr>
+                                                    <td class="pr-2">
+                                                        <input
+                                                            class="bg-transparent"
+                                                            title="Click to rename"
+                                                            prop:value=entry.title.clone()
+                                                            on:change:target=move |ev| {
+                                                                let title = ev.target().value();
+                                                                let title = title.trim();
+                                                                if !title.is_empty() {
+                                                                    rename(id.clone(), title.to_string());
+                                                                }
+                                                            }
+                                                        />
+                                                    </td>
+                                                    <td class="pr-2 text-slate-600">
+                                                        {entry.credits()}
+                                                    </td>
+                                                    <td>
+                                                        <button
+                                                            class="border border-black rounded-sm px-1"
+                                                            on:click=move |_| on_open.run(open_id.clone())
+                                                        >
+                                                            "Open"
+                                                        </button>
+                                                        <button
+                                                            class="border border-black rounded-sm px-1 ml-1"
+                                                            on:click=move |_| export(export_entry.clone())
+                                                        >
+                                                            "Export"
+                                                        </button>
+                                                        <button
+                                                            class="border border-black rounded-sm px-1 ml-1"
+                                                            on:click=move |_| delete(delete_entry.clone())
+                                                        >
+                                                            "Delete"
+                                                        </button>
+                                                    </td>
+                                                </tr>
+                                            }
+                                        })
+                                        .collect_vec()
+                                }}
+                            </table>
+                        </details>
+                    }
+                })
+        }}
+        {move || library_error.get().map(|e| view! { <p class="text-red-600">{e}</p> })}
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use bit_set::BitSet;
+use itertools::Itertools;
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{MidiAccess, MidiInput, MidiMessageEvent, MidiOutput};
+
+use crate::future_util::PromiseAsFuture;
+use crate::instrument::PlaybackGuard;
+use crate::loop_region::{wrap_song_index, LoopRegion};
+use crate::midi_input::{action_for_message, MidiInputAction, MidiInputMode, MidiTrigger};
+use crate::playback_manager::PlaybackManager;
+
+/// Plays the song from a MIDI keyboard or foot controller, once the user connects one, and lets
+/// the notes be sent out to a MIDI device instead of played here.
+#[component]
+pub fn MidiListener(
+    playback_manager: LocalResource<RwSignal<PlaybackManager, LocalStorage>>,
+    #[prop(into)] active_voices: Signal<BitSet>,
+    start_song_index: RwSignal<usize>,
+    #[prop(into)] loop_region: Signal<Option<LoopRegion>>,
+    most_recent_song_index: RwSignal<usize>,
+    set_current_cursor_index: WriteSignal<usize>,
+) -> impl IntoView {
+    let (mode, set_mode) = signal(MidiInputMode::default());
+    // `None` until the user asks to connect.
+    let (device_names, set_device_names) = signal::<Option<Vec<String>>>(None);
+    let (midi_error, set_midi_error) = signal::<Option<String>>(None);
+    // (id, name) for each output port.
+    let (output_ports, set_output_ports) = signal(Vec::<(String, String)>::new());
+    // `None` to play through the browser.
+    let (selected_output, set_selected_output) = signal::<Option<String>>(None);
+    let (_, set_held_notes) =
+        signal_local::<HashMap<MidiTrigger, Vec<Rc<PlaybackGuard>>>>(HashMap::new());
+    // Where `PlayNext` carries on from, or `None` to begin at the start.
+    let next_song_index = RwSignal::new(None::<usize>);
+    Effect::new(move |_| {
+        start_song_index.track();
+        next_song_index.set(None);
+    });
+
+    // Returns whether there was anything there to play.
+    let play = move |song_index: usize, trigger: MidiTrigger, gain: f32| {
+        let playback_manager = playback_manager.read_untracked();
+        let Some(playback_manager) = &*playback_manager else {
+            return false;
+        };
+        let Some((cursor_index, newly_held_notes)) = playback_manager
+            .read_untracked()
+            .start_notes_at_relative_index(song_index, &active_voices.read_untracked(), gain)
+        else {
+            return false;
+        };
+        most_recent_song_index.set(song_index);
+        set_current_cursor_index.set(cursor_index);
+        set_held_notes.update(|held_notes| {
+            held_notes.insert(trigger, newly_held_notes);
+        });
+        true
+    };
+
+    let on_message = move |event: MidiMessageEvent| {
+        let Ok(data) = event.data() else {
+            return;
+        };
+        let Some(action) = action_for_message(mode.get_untracked(), &data) else {
+            return;
+        };
+        match action {
+            MidiInputAction::PlayNext { trigger, gain } => {
+                let song_index = next_song_index
+                    .get_untracked()
+                    .unwrap_or_else(|| start_song_index.get_untracked());
+                if play(song_index, trigger, gain) {
+                    next_song_index.set(Some(wrap_song_index(
+                        loop_region.get_untracked(),
+                        song_index + 1,
+                    )));
+                }
+            }
+            MidiInputAction::PlayOffset {
+                trigger,
+                offset,
+                gain,
+            } => {
+                let song_index = wrap_song_index(
+                    loop_region.get_untracked(),
+                    start_song_index.get_untracked() + offset,
+                );
+                play(song_index, trigger, gain);
+            }
+            MidiInputAction::Release { trigger } => {
+                set_held_notes.update(|held_notes| {
+                    held_notes.remove(&trigger);
+                });
+            }
+        }
+    };
+    let on_message = StoredValue::new_local(
+        Closure::<dyn FnMut(MidiMessageEvent)>::new(on_message).into_js_value(),
+    );
+    let midi_access = StoredValue::new_local(None::<MidiAccess>);
+
+    // Listens to every input and lists every output, including any plugged in since we last looked.
+    let connect_ports = move || {
+        let Some(access) = midi_access.get_value() else {
+            return;
+        };
+        let mut names = Vec::new();
+        for input in access.inputs().values().into_iter().flatten() {
+            let input = input.unchecked_into::<MidiInput>();
+            on_message
+                .with_value(|on_message| input.set_onmidimessage(Some(on_message.unchecked_ref())));
+            names.push(input.name().unwrap_or_else(|| "Unnamed device".to_string()));
+        }
+        set_device_names.set(Some(names));
+
+        let outputs = access
+            .outputs()
+            .values()
+            .into_iter()
+            .flatten()
+            .map(|output| {
+                let output = output.unchecked_into::<MidiOutput>();
+                let name = output
+                    .name()
+                    .unwrap_or_else(|| "Unnamed device".to_string());
+                (output.id(), name)
+            })
+            .collect::<Vec<_>>();
+        // Go back to playing here if the selected device went away.
+        if let Some(selected) = selected_output.get_untracked() {
+            if !outputs.iter().any(|(id, _)| *id == selected) {
+                set_selected_output.set(None);
+            }
+        }
+        set_output_ports.set(outputs);
+    };
+    let on_state_change =
+        StoredValue::new_local(Closure::<dyn FnMut()>::new(connect_ports).into_js_value());
+
+    Effect::new(move |_| {
+        let Some(playback_manager) = *playback_manager.read() else {
+            return;
+        };
+        let port = selected_output.get().and_then(|id| {
+            let access = midi_access.get_value()?;
+            access.outputs().get(&id)
+        });
+        playback_manager.write().set_midi_output(port);
+    });
+
+    let connect = move || {
+        let Some(navigator) = web_sys::window().map(|window| window.navigator()) else {
+            return;
+        };
+        let supported =
+            js_sys::Reflect::has(&navigator, &"requestMIDIAccess".into()).unwrap_or(false);
+        if !supported {
+            set_midi_error.set(Some(
+                "This browser doesn't support MIDI devices".to_string(),
+            ));
+            return;
+        }
+        spawn_local(async move {
+            let access = match navigator.request_midi_access() {
+                Ok(promise) => promise.into_future().await,
+                Err(e) => Err(e),
+            };
+            match access.and_then(|access| access.dyn_into::<MidiAccess>()) {
+                Ok(access) => {
+                    on_state_change.with_value(|on_state_change| {
+                        access.set_onstatechange(Some(on_state_change.unchecked_ref()))
+                    });
+                    midi_access.set_value(Some(access));
+                    set_midi_error.set(None);
+                    connect_ports();
+                }
+                Err(e) => set_midi_error.set(Some(format!("Unable to use MIDI devices: {e:?}"))),
+            }
+        });
+    };
+
+    on_cleanup(move || {
+        if let Some(access) = midi_access.get_value() {
+            access.set_onstatechange(None);
+            for input in access.inputs().values().into_iter().flatten() {
+                input.unchecked_into::<MidiInput>().set_onmidimessage(None);
+            }
+        }
+    });
+
+    view! {
+        <div class="flex flex-row items-baseline space-x-1">
+            <p>"MIDI input:"</p>
+            {move || match device_names.get() {
+                None => {
+                    view! {
+                        <button class="border border-black rounded-sm px-1" on:click=move |_| connect()>
+                            "Connect"
+                        </button>
+                    }
+                        .into_any()
+                }
+                Some(names) if names.is_empty() => {
+                    view! { <p>"No devices found, try plugging one in"</p> }.into_any()
+                }
+                Some(names) => view! { <p>{names.join(", ")}</p> }.into_any(),
+            }}
+            <p>"Play through:"</p>
+            <select
+                class="border"
+                on:change:target=move |ev| {
+                    let id = ev.target().value();
+                    set_selected_output.set((!id.is_empty()).then_some(id));
+                }
+            >
+                <option value="" selected=move || selected_output.get().is_none()>
+                    "This device"
+                </option>
+                {move || {
+                    output_ports
+                        .get()
+                        .into_iter()
+                        .map(|(id, name)| {
+                            let is_selected = {
+                                let id = id.clone();
+                                move || selected_output.get().as_ref() == Some(&id)
+                            };
+                            view! {
+                                <option value=id selected=is_selected>
+                                    {name}
+                                </option>
+                            }
+                        })
+                        .collect_vec()
+                }}
+            </select>
+            <label class="flex flex-row items-baseline space-x-1">
+                <input
+                    type="checkbox"
+                    prop:checked=move || mode.get() == MidiInputMode::Keys
+                    on:change:target=move |ev| {
+                        set_mode
+                            .set(
+                                if ev.target().checked() {
+                                    MidiInputMode::Keys
+                                } else {
+                                    MidiInputMode::Advance
+                                },
+                            );
+                    }
+                />
+                <span>
+                    "Keys from middle C up play positions like the letter keys, rather than any key playing the next one"
+                </span>
+            </label>
+        </div>
+        {move || midi_error.get().map(|e| view! { <p class="text-red-600">{e}</p> })}
+    }
+}
@@ -1,10 +1,12 @@
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use bit_set::BitSet;
 use leptos::prelude::*;
 
+use crate::instrument::PlaybackGuard;
+use crate::loop_region::{wrap_song_index, LoopRegion};
 use crate::playback_manager::PlaybackManager;
-use crate::sampler::SamplerPlaybackGuard;
 
 #[component]
 pub fn MobileControls(
@@ -13,8 +15,10 @@ pub fn MobileControls(
     start_song_index: RwSignal<usize>,
     most_recent_song_index: RwSignal<usize>,
     set_current_cursor_index: WriteSignal<usize>,
+    #[prop(into)] loop_region: Signal<Option<LoopRegion>>,
 ) -> impl IntoView {
-    let (playing_notes, set_playing_notes) = signal_local::<HashMap<String, Vec<SamplerPlaybackGuard>>>(HashMap::new());
+    let (_, set_playing_notes) =
+        signal_local::<HashMap<String, Vec<Rc<PlaybackGuard>>>>(HashMap::new());
     let (has_moved_next, set_has_moved_next) = signal_local(false);
 
     let handle_reset = move |_| {
@@ -46,7 +50,7 @@ pub fn MobileControls(
         };
         let Some((cursor_index, newly_held_notes)) = playback_manager
             .write()
-            .start_notes_at_relative_index(song_index, &*active_voices)
+            .start_notes_at_relative_index(song_index, &active_voices, 1.0)
         else {
             return;
         };
@@ -77,7 +81,7 @@ pub fn MobileControls(
         let current = most_recent_song_index.get();
         if has_moved_next.get() {
             // If we've moved next before, increment normally
-            let new_index = current + 1;
+            let new_index = wrap_song_index(loop_region.get(), current + 1);
             play_note_at_index(new_index, "next".to_string());
             most_recent_song_index.set(new_index);
         } else {
@@ -141,4 +145,4 @@ pub fn MobileControls(
             </button>
         </div>
     }
-}
\ No newline at end of file
+}
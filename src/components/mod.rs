@@ -1,5 +1,10 @@
 pub mod app;
+mod export_controls;
 mod keyboard_listener;
+mod keymap_editor;
+mod library_controls;
+mod midi_listener;
 mod mobile_controls;
 mod sheet_music;
+mod transport_controls;
 mod voice_control;
@@ -1,18 +1,23 @@
 use std::collections::HashMap;
 
 use bit_set::BitSet;
+use fraction::ToPrimitive;
 use itertools::Itertools;
-use js_sys::JsString;
+use js_sys::{JsString, Object};
 use leptos::prelude::*;
 use leptos::task::spawn_local;
+use log::{error, warn};
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::JsCast;
 use web_sys::{ScrollBehavior, ScrollIntoViewOptions, ScrollLogicalPosition};
 
-use crate::components::keyboard_listener::LETTERS;
 use crate::future_util::PromiseAsFuture;
 use crate::html_util::HtmlCollectionIntoIterator;
-use crate::opensheetmusicdisplay_bindings::{CursorOptions, OpenSheetMusicDisplay};
+use crate::keymap::Keymap;
+use crate::midi_import::MIDI_MAGIC;
+use crate::opensheetmusicdisplay_bindings::{
+    CursorOptions, OpenSheetMusicDisplay, PointF2D, TransposeCalculator,
+};
 use crate::song_data::SongData;
 
 const KEY_HINT_CONTAINER_ID: &str = "magicPianoKeyHintContainer";
@@ -20,42 +25,64 @@ const KEY_HINT_CONTAINER_ID: &str = "magicPianoKeyHintContainer";
 #[component]
 pub fn SheetMusic(
     #[prop(into)] active_voices: Signal<BitSet>,
-    #[prop(into)] song_data: Signal<Option<SongData>>,
+    /// The song in the order we're stepping through it, which may not be the written order.
+    #[prop(into)]
+    song_data: Signal<Option<SongData>>,
+    #[prop(into)] start_song_index: Signal<usize>,
+    #[prop(into)] current_song_index: Signal<usize>,
     #[prop(into)] start_cursor_index: Signal<usize>,
     #[prop(into)] current_cursor_index: Signal<usize>,
-    #[prop(into)] song_raw_data: LocalResource<JsString>,
+    #[prop(into)] song_raw_data: LocalResource<Vec<u8>>,
     #[prop(into)] set_song_data: WriteSignal<Option<SongData>>,
+    /// For labelling the notes with the keys that play them.
+    #[prop(into)]
+    keymap: Signal<Keymap>,
+    /// Semitones to transpose the notation by. This is only how it's drawn, playback is separate.
+    #[prop(into)]
+    notation_transpose: Signal<i32>,
+    /// As a percentage.
+    zoom: RwSignal<f64>,
+    /// Whether to keep the score's own line breaks rather than fitting as much as we can per line.
+    original_linebreaks: RwSignal<bool>,
+    /// Called with the position of a note that's clicked on.
+    #[prop(into)]
+    on_click_position: Callback<usize>,
+    /// Called with the position of a note that's shift-clicked on.
+    #[prop(into)]
+    on_shift_click_position: Callback<usize>,
 ) -> impl IntoView {
     let (osmd, set_osmd) = signal_local::<Option<OpenSheetMusicDisplay>>(None);
     let container_ref = NodeRef::new();
     let on_render = Trigger::new();
+    // Set when the song can't be shown as sheet music (eg it's a MIDI file), in which case we
+    // show a simple position readout instead.
+    let (is_scoreless, set_is_scoreless) = signal(false);
+    // Whether OSMD has a score loaded that we can re-render.
+    let (is_score_loaded, set_is_score_loaded) = signal(false);
 
     // Sync the indices to show to the cursor
-    create_sync_cursor_effect(osmd, start_cursor_index, 1);
-    create_sync_cursor_effect(osmd, current_cursor_index, 0);
+    create_sync_cursor_effect(osmd, is_scoreless, start_cursor_index, 1);
+    create_sync_cursor_effect(osmd, is_scoreless, current_cursor_index, 0);
     // When the start cursor is moved, update the key hints
     Effect::new(move |_| {
-        let start_cursor_index = start_cursor_index.get();
+        let start_song_index = start_song_index.get();
         // Rerun this if we re-render
         on_render.track();
+        if is_scoreless.get() {
+            return None;
+        }
 
+        let position_labels = keymap.with(Keymap::position_labels);
         let letter_cursor_index_pairs = song_data.with(|song_data| {
             let song_data = song_data.as_ref()?;
-            // First find the starting song index. This is kinda silly to have to look up, maybe
-            // we should plumb it in directly.
-            let start_song_index = song_data
-                .slices
-                .iter()
-                .position(|s| s.cursor_index == start_cursor_index)?;
             Some(
-                LETTERS
-                    .chars()
-                    .enumerate()
-                    .flat_map(|(idx, c)| {
+                position_labels
+                    .into_iter()
+                    .flat_map(|(offset, label)| {
                         song_data
                             .slices
-                            .get(start_song_index + idx)
-                            .map(|s| (c, s.cursor_index))
+                            .get(start_song_index + offset)
+                            .map(|s| (label, s.cursor_index))
                     })
                     .collect_vec(),
             )
@@ -131,7 +158,7 @@ pub fn SheetMusic(
                                 x=x
                                 y=y
                             >
-                                {*l}
+                                {l.clone()}
                             </text>
                         }
                     })
@@ -147,6 +174,9 @@ pub fn SheetMusic(
     // Sync the note colors with the active voices
     Effect::new(move |_| {
         on_render.track();
+        if is_scoreless.get() {
+            return;
+        }
 
         (|| -> Option<()> {
             let osmd = osmd.read();
@@ -283,6 +313,7 @@ pub fn SheetMusic(
         // let options = serde_wasm_bindgen::to_value(&options).unwrap();
         // let osmd = OpenSheetMusicDisplay::new_with_options(container, options);
         let osmd = OpenSheetMusicDisplay::new(container);
+        osmd.set_transpose_calculator(&TransposeCalculator::new());
         osmd.set_cursors_options(vec![
             CursorOptions::from_color("#33e02f".into())
                 .to_js_value()
@@ -303,8 +334,62 @@ pub fn SheetMusic(
         set_osmd.set(Some(osmd));
     });
 
+    let on_score_click = move |ev: web_sys::MouseEvent| {
+        let song_index = osmd.with_untracked(|osmd| {
+            song_data.with_untracked(|song_data| {
+                song_index_at_point(
+                    osmd.as_ref()?,
+                    song_data.as_ref()?,
+                    start_song_index.get_untracked(),
+                    ev.client_x() as f32,
+                    ev.client_y() as f32,
+                )
+            })
+        });
+        let Some(song_index) = song_index else {
+            return;
+        };
+        if ev.shift_key() {
+            on_shift_click_position.run(song_index);
+        } else {
+            on_click_position.run(song_index);
+        }
+    };
+
+    // Only the drawing changes, the song data (and so playback) stays as written.
+    Effect::new(move |_| {
+        let semitones = notation_transpose.get();
+        if !is_score_loaded.get_untracked() {
+            return;
+        }
+        osmd.with_untracked(|osmd| {
+            let Some(osmd) = osmd.as_ref() else { return };
+            osmd.sheet().set_transpose(semitones);
+            osmd.update_graphic();
+            osmd.render();
+            on_render.notify();
+        });
+    });
+
+    // Zoom and line breaks can be changed from outside too, eg when restoring a song's settings.
+    Effect::new(move |previous: Option<(f64, bool)>| {
+        let layout = (zoom.get(), original_linebreaks.get());
+        if previous == Some(layout) || !is_score_loaded.get_untracked() {
+            return layout;
+        }
+        osmd.with_untracked(|osmd| {
+            let Some(osmd) = osmd.as_ref() else { return };
+            apply_layout(osmd, layout.0, layout.1);
+            osmd.render();
+            on_render.notify();
+        });
+        layout
+    });
+
     let zoom_input_node_ref = NodeRef::new();
-    let (zoom_value, set_zoom_value) = signal(75f64);
+    // Follows the slider as it's dragged, `zoom` only changes once it's let go.
+    let (zoom_value, set_zoom_value) = signal(zoom.get_untracked());
+    Effect::new(move |_| set_zoom_value.set(zoom.get()));
     let original_linebreaks_node_ref = NodeRef::new();
 
     // Load the song into osmd and extract the SongData.
@@ -312,21 +397,64 @@ pub fn SheetMusic(
     // needed for Resource inputs.
     Effect::new(move |_| {
         // Important that we track the usage of both of these before we enter `spawn_local`
-        let load_promise =
-            if let (Some(osmd), Some(song_raw_data)) = (&*osmd.read(), &*song_raw_data.read()) {
-                osmd.load(song_raw_data)
+        let song_bytes =
+            if let (Some(_), Some(song_raw_data)) = (&*osmd.read(), &*song_raw_data.read()) {
+                song_raw_data.clone()
             } else {
                 return;
             };
 
+        set_is_score_loaded.set(false);
+        if song_bytes.starts_with(MIDI_MAGIC) {
+            // There's no score to render, so go straight to the song data.
+            set_is_scoreless.set(true);
+            osmd.with_untracked(|osmd| osmd.as_ref().unwrap().clear());
+            match SongData::from_midi(&song_bytes) {
+                Ok(song_data) => set_song_data.set(Some(song_data)),
+                Err(e) => error!("Unable to parse the song: {e}"),
+            }
+            return;
+        }
+        set_is_scoreless.set(false);
+
+        // The library takes in the binary data as a string (*shudders*) and JS uses arbitrary 16-bit
+        // character codes (nominally utf-16, but doesn't need to be valid utf-16), so we need to make
+        // these u16's and then shove it into a JsString. Interestingly that means just left-padding
+        // them with 0's, not packing two u8's into a single u16 (which didn't work).
+        let u16_data = song_bytes.iter().map(|d| *d as u16).collect_vec();
+        let load_promise = osmd.with_untracked(|osmd| {
+            osmd.as_ref()
+                .unwrap()
+                .load(&JsString::from_char_code(&u16_data))
+        });
+
         spawn_local(async move {
-            load_promise.into_future().await.unwrap();
+            if let Err(e) = load_promise.into_future().await {
+                // OSMD couldn't handle it, but we may still be able to play it back even without
+                // the rendered score.
+                warn!("OSMD was unable to load the song, falling back to native parsing: {e:?}");
+                match SongData::from_musicxml(&song_bytes) {
+                    Ok(song_data) => set_song_data.set(Some(song_data)),
+                    Err(e) => error!("Unable to parse the song: {e}"),
+                }
+                return;
+            }
             // We tracked this usage above
             osmd.with_untracked(|osmd| {
                 // We shouldn't be able to get here without this set.
                 let osmd = osmd.as_ref().unwrap();
-                osmd.set_zoom((zoom_value.get_untracked() / 100f64) as f32);
+                apply_layout(
+                    osmd,
+                    zoom.get_untracked(),
+                    original_linebreaks.get_untracked(),
+                );
+                let semitones = notation_transpose.get_untracked();
+                if semitones != 0 {
+                    osmd.sheet().set_transpose(semitones);
+                    osmd.update_graphic();
+                }
                 osmd.render();
+                set_is_score_loaded.set(true);
                 // Now load the song data
                 let cursor = osmd.cursor().unwrap();
                 cursor.reset();
@@ -362,14 +490,7 @@ pub fn SheetMusic(
                     }
                 }
 
-                on:change=move |_| {
-                    osmd.with(move |osmd| {
-                        let Some(osmd) = osmd.as_ref() else { return };
-                        osmd.set_zoom((zoom_value.get() / 100f64) as f32);
-                        osmd.render();
-                        on_render.notify();
-                    });
-                }
+                on:change=move |_| zoom.set(zoom_value.get_untracked())
             />
 
             <datalist id="zoom_values">
@@ -382,34 +503,113 @@ pub fn SheetMusic(
             <input
                 node_ref=original_linebreaks_node_ref
                 type="checkbox"
+                prop:checked=original_linebreaks
                 on:change=move |_| {
                     if let Some(original_linebreaks_input) = original_linebreaks_node_ref.get() {
-                        let use_original_linebreaks = original_linebreaks_input.checked();
-                        osmd.with(move |osmd| {
-                            let Some(osmd) = osmd.as_ref() else { return };
-                            osmd.rules()
-                                .set_new_system_at_xml_new_page_attribute(use_original_linebreaks);
-                            osmd.rules()
-                                .set_new_system_at_xml_new_system_attribute(
-                                    use_original_linebreaks,
-                                );
-                            osmd.render();
-                            on_render.notify();
-                        });
+                        original_linebreaks.set(original_linebreaks_input.checked());
                     }
                 }
             />
 
         </div>
+        {move || {
+            is_scoreless
+                .get()
+                .then(|| {
+                    view! {
+                        <ScorelessPosition song_data=song_data current_song_index=current_song_index />
+                    }
+                })
+        }}
         <div
             class="w-full h-full img-height-revert-layer img-scroll-margin-block-5em"
+            class:hidden=is_scoreless
             node_ref=container_ref
+            on:click=on_score_click
+            on:mousedown=move |ev| {
+                // Otherwise shift-clicking selects text.
+                if ev.shift_key() {
+                    ev.prevent_default();
+                }
+            }
         ></div>
     }
 }
 
+/// Sets up the zoom (as a percentage) and line breaks for the next render.
+fn apply_layout(osmd: &OpenSheetMusicDisplay, zoom: f64, use_original_linebreaks: bool) {
+    osmd.set_zoom((zoom / 100f64) as f32);
+    osmd.rules()
+        .set_new_system_at_xml_new_page_attribute(use_original_linebreaks);
+    osmd.rules()
+        .set_new_system_at_xml_new_system_attribute(use_original_linebreaks);
+}
+
+/// Stands in for the sheet music when there isn't any, so you can at least tell where you are.
+#[component]
+fn ScorelessPosition(
+    #[prop(into)] song_data: Signal<Option<SongData>>,
+    #[prop(into)] current_song_index: Signal<usize>,
+) -> impl IntoView {
+    let position_text = move || {
+        song_data.with(|song_data| {
+            let song_data = song_data.as_ref()?;
+            let slice = song_data.slices.get(current_song_index.get())?;
+            let (measure, beat) = song_data.measure_and_beat(slice.timestamp);
+            let beat = beat.to_f64().unwrap_or(1.0);
+            let tempo = song_data
+                .tempo_at(slice.timestamp)
+                .map(|bpm| format!(" (♩ = {bpm:.0})"))
+                .unwrap_or_default();
+            Some(format!("Measure {measure}, beat {beat:.2}{tempo}"))
+        })
+    };
+
+    view! {
+        <div class="flex flex-col items-start">
+            <p>"This song doesn't have any sheet music, but you can still play through it."</p>
+            <p>{position_text}</p>
+        </div>
+    }
+}
+
+/// Finds the note nearest to a point on the page, in client coordinates, and returns its position
+/// in the song. A note can be at more than one position if the song follows repeats, in which case
+/// we pick the one closest to `near_song_index`.
+fn song_index_at_point(
+    osmd: &OpenSheetMusicDisplay,
+    song_data: &SongData,
+    near_song_index: usize,
+    client_x: f32,
+    client_y: f32,
+) -> Option<usize> {
+    let graphical_music_sheet = osmd.graphic()?;
+    let svg_point = graphical_music_sheet.dom_to_svg(&PointF2D::from_xy(client_x, client_y));
+    let point = graphical_music_sheet.svg_to_osmd(&svg_point);
+    let staff_entry = graphical_music_sheet.get_nearest_staff_entry(&point)?;
+
+    // The containers are in cursor order, so the index of the one holding the entry is its cursor
+    // index.
+    let cursor_index = graphical_music_sheet
+        .vertical_graphical_staff_entry_containers()
+        .into_iter()
+        .position(|vgsec| {
+            vgsec
+                .staff_entries()
+                .into_iter()
+                .filter(|se| !se.is_undefined())
+                .any(|se| Object::is(&se, &staff_entry))
+        })?;
+    song_data
+        .slices
+        .iter()
+        .positions(|slice| slice.cursor_index == cursor_index)
+        .min_by_key(|song_index| song_index.abs_diff(near_song_index))
+}
+
 fn create_sync_cursor_effect(
     osmd: ReadSignal<Option<OpenSheetMusicDisplay>, LocalStorage>,
+    is_scoreless: ReadSignal<bool>,
     desired_index: Signal<usize>,
     nth_cursor: usize,
 ) {
@@ -419,7 +619,7 @@ fn create_sync_cursor_effect(
         let to_show = desired_index.get() as i32;
         let index_last_shown = index_last_shown.get();
 
-        if to_show == index_last_shown {
+        if to_show == index_last_shown || is_scoreless.get() {
             return;
         }
 
@@ -0,0 +1,286 @@
+use bit_set::BitSet;
+use leptos::prelude::*;
+use leptos_use::use_interval_fn;
+
+use crate::loop_region::LoopRegion;
+use crate::playback_manager::PlaybackManager;
+use crate::song_data::{SongData, DEFAULT_BPM};
+use crate::transport::Transport;
+
+/// How often we check in with the transport to schedule upcoming notes and move the cursor.
+const TICK_INTERVAL_MS: u64 = 25;
+
+#[component]
+pub fn TransportControls(
+    playback_manager: LocalResource<RwSignal<PlaybackManager, LocalStorage>>,
+    #[prop(into)] active_voices: Signal<BitSet>,
+    #[prop(into)] song_data: Signal<Option<SongData>>,
+    start_song_index: RwSignal<usize>,
+    most_recent_song_index: RwSignal<usize>,
+    /// Replaces the score's tempo if set.
+    tempo_override: RwSignal<Option<f64>>,
+    loop_region: RwSignal<Option<LoopRegion>>,
+    set_current_cursor_index: WriteSignal<usize>,
+    // Lets us know when to stop playing.
+    #[prop(into)] on_reset_song: Trigger,
+    // Also stops playback, but leaves the indices alone.
+    #[prop(into)] on_change_order: Trigger,
+) -> impl IntoView {
+    let transport = StoredValue::new_local(None::<Transport>);
+    let (is_playing, set_is_playing) = signal(false);
+    // Which time through the loop we're on, while playing one.
+    let (loop_pass, set_loop_pass) = signal::<Option<u32>>(None);
+
+    let score_tempo = Memo::new(move |_| {
+        song_data.with(|song_data| {
+            let song_data = song_data.as_ref()?;
+            let slice = song_data.slices.get(start_song_index.get())?;
+            Some(song_data.tempo_at(slice.timestamp).unwrap_or(DEFAULT_BPM))
+        })
+    });
+
+    let interval = use_interval_fn(
+        move || {
+            let playback_manager = playback_manager.read();
+            let Some(playback_manager) = &*playback_manager else {
+                return;
+            };
+            let playback_manager = playback_manager.read();
+            let tick = transport.try_update_value(|transport| {
+                transport
+                    .as_mut()
+                    .map(|transport| transport.tick(&*playback_manager, &active_voices.read()))
+            });
+            let Some(Some(tick)) = tick else {
+                return;
+            };
+            if let Some(song_index) = tick.current_song_index {
+                most_recent_song_index.set(song_index);
+            }
+            if let Some(cursor_index) = tick.cursor_index {
+                set_current_cursor_index.set(cursor_index);
+            }
+            set_loop_pass.set(tick.pass);
+            if tick.finished {
+                transport.set_value(None);
+                set_is_playing.set(false);
+            }
+        },
+        TICK_INTERVAL_MS,
+    );
+    let pause_interval = interval.pause.clone();
+    Effect::new(move |_| {
+        if is_playing.get() {
+            (interval.resume)();
+        } else {
+            pause_interval();
+        }
+    });
+
+    let play = move || {
+        let playback_manager = playback_manager.read();
+        let Some(playback_manager) = &*playback_manager else {
+            return;
+        };
+        let new_transport = Transport::start(
+            &*playback_manager.read(),
+            most_recent_song_index.get_untracked(),
+            tempo_override.get_untracked(),
+        )
+        .map(|transport| match loop_region.get_untracked() {
+            Some(loop_region) => transport.with_loop(loop_region),
+            None => transport,
+        });
+        let started = new_transport.is_some();
+        transport.set_value(new_transport);
+        set_is_playing.set(started);
+    };
+    // Pausing leaves us wherever we got to, so playing again picks up from there.
+    let pause = move || {
+        transport.set_value(None);
+        set_is_playing.set(false);
+        set_loop_pass.set(None);
+    };
+    // Restart so changes take effect straight away.
+    let restart_if_playing = move || {
+        if is_playing.get_untracked() {
+            play();
+        }
+    };
+    let update_loop = move |update: &dyn Fn(&mut LoopRegion)| {
+        loop_region.update(|loop_region| {
+            if let Some(loop_region) = loop_region {
+                update(loop_region);
+            }
+        });
+        restart_if_playing();
+    };
+    let loop_measures = Memo::new(move |_| {
+        let loop_region = loop_region.get()?;
+        song_data.with(|song_data| {
+            let song_data = song_data.as_ref()?;
+            let measure_of = |song_index: usize| {
+                let slice = song_data.slices.get(song_index)?;
+                Some(song_data.measure_and_beat(slice.timestamp).0)
+            };
+            Some((measure_of(loop_region.start)?, measure_of(loop_region.end)?))
+        })
+    });
+    let stop = move || {
+        pause();
+        let start_index = start_song_index.get_untracked();
+        most_recent_song_index.set(start_index);
+        let playback_manager = playback_manager.read();
+        if let Some(playback_manager) = &*playback_manager {
+            if let Some(cursor_index) = playback_manager
+                .read()
+                .cursor_index_for_song_index(start_index)
+            {
+                set_current_cursor_index.set(cursor_index);
+            }
+        }
+    };
+
+    Effect::new(move |_| {
+        on_reset_song.track();
+        on_change_order.track();
+        pause();
+    });
+
+    view! {
+        <div class="flex flex-row items-baseline space-x-1">
+            <button
+                class="border border-black rounded-sm px-1"
+                on:click=move |_| {
+                    if is_playing.get_untracked() {
+                        pause();
+                    } else {
+                        play();
+                    }
+                }
+            >
+                {move || if is_playing.get() { "Pause" } else { "Play" }}
+            </button>
+            <button class="border border-black rounded-sm px-1" on:click=move |_| stop()>
+                Stop
+            </button>
+            <p>"Tempo (♩ = "</p>
+            <input
+                class="border w-16"
+                type="number"
+                min="1"
+                placeholder=move || {
+                    score_tempo.get().map(|bpm| format!("{bpm:.0}")).unwrap_or_default()
+                }
+                prop:value=move || {
+                    tempo_override.get().map(|bpm| bpm.to_string()).unwrap_or_default()
+                }
+                on:change:target=move |ev| {
+                    let bpm = ev.target().value().parse::<f64>().ok().filter(|bpm| *bpm > 0.0);
+                    tempo_override.set(bpm);
+                    restart_if_playing();
+                }
+            />
+            <p>")"</p>
+        </div>
+        <div class="flex flex-row items-baseline space-x-1">
+            <p>"Loop:"</p>
+            {move || match loop_region.get() {
+                None => {
+                    view! {
+                        <button
+                            class="border border-black rounded-sm px-1"
+                            on:click=move |_| {
+                                loop_region
+                                    .set(
+                                        Some(
+                                            LoopRegion::new(
+                                                start_song_index.get_untracked(),
+                                                most_recent_song_index.get_untracked(),
+                                            ),
+                                        ),
+                                    );
+                                restart_if_playing();
+                            }
+                        >
+                            "From the start to the last note played"
+                        </button>
+                    }
+                        .into_any()
+                }
+                Some(current_loop) => {
+                    let speed_up_percent = if current_loop.speed_up_per_pass > 0.0 {
+                        format!("{}", current_loop.speed_up_per_pass * 100.0)
+                    } else {
+                        String::new()
+                    };
+                    view! {
+                        <p>
+                            {move || match loop_measures.get() {
+                                Some((from, to)) if from == to => format!("m. {from}"),
+                                Some((from, to)) => format!("mm. {from}-{to}"),
+                                None => String::new(),
+                            }}
+                        </p>
+                        <button
+                            class="border border-black rounded-sm px-1"
+                            on:click=move |_| {
+                                loop_region.set(None);
+                                restart_if_playing();
+                            }
+                        >
+                            "Clear"
+                        </button>
+                        <p>"Play"</p>
+                        <input
+                            class="border w-12"
+                            type="number"
+                            min="1"
+                            placeholder="∞"
+                            prop:value=current_loop.repeats.map(|r| r.to_string()).unwrap_or_default()
+                            on:change:target=move |ev| {
+                                let repeats = ev
+                                    .target()
+                                    .value()
+                                    .parse::<u32>()
+                                    .ok()
+                                    .filter(|r| *r > 0);
+                                update_loop(&|loop_region| loop_region.repeats = repeats);
+                            }
+                        />
+                        <p>"times, speeding up"</p>
+                        <input
+                            class="border w-12"
+                            type="number"
+                            min="0"
+                            placeholder="0"
+                            prop:value=speed_up_percent
+                            on:change:target=move |ev| {
+                                let percent = ev
+                                    .target()
+                                    .value()
+                                    .parse::<f64>()
+                                    .ok()
+                                    .filter(|p| *p >= 0.0)
+                                    .unwrap_or(0.0);
+                                update_loop(&|loop_region| {
+                                    loop_region.speed_up_per_pass = percent / 100.0
+                                });
+                            }
+                        />
+                        <p>"% each pass"</p>
+                        {move || {
+                            loop_pass
+                                .get()
+                                .map(|pass| match current_loop.repeats {
+                                    Some(repeats) => format!("(pass {pass} of {repeats})"),
+                                    None => format!("(pass {pass})"),
+                                })
+                        }}
+                    }
+                        .into_any()
+                }
+            }}
+        </div>
+    }
+}
@@ -1,23 +1,55 @@
+use itertools::Itertools;
 use leptos::prelude::*;
 
+use crate::song_settings::{VoiceMix, DEFAULT_VOLUME};
+
 #[derive(Clone)]
 pub struct VoiceState {
-    pub name: String,
+    pub name: RwSignal<String>,
     pub mute: RwSignal<bool>,
     pub solo: RwSignal<bool>,
     pub volume: RwSignal<u32>,
+    /// From -100 (left) to 100 (right).
+    pub pan: RwSignal<i32>,
+    /// Index into the instruments offered by the app, `INSTRUMENT_PRESETS` first.
+    pub instrument: RwSignal<usize>,
 }
 
 impl VoiceState {
     pub fn new(name: String) -> Self {
         Self {
-            name,
+            name: RwSignal::new(name),
             mute: RwSignal::new(false),
             solo: RwSignal::new(false),
-            volume: RwSignal::new(70),
+            volume: RwSignal::new(DEFAULT_VOLUME),
+            pan: RwSignal::new(0),
+            instrument: RwSignal::new(0),
+        }
+    }
+
+    /// Same mixer settings as `self`, under a new name.
+    pub fn renamed(&self, name: String) -> Self {
+        Self {
+            name: RwSignal::new(name),
+            ..self.clone()
+        }
+    }
+
+    /// The settings we save for the song. Tracked, so saving can follow changes.
+    pub fn mix(&self) -> VoiceMix {
+        VoiceMix {
+            volume: self.volume.get(),
+            mute: self.mute.get(),
+            solo: self.solo.get(),
         }
     }
 
+    pub fn set_mix(&self, mix: VoiceMix) {
+        self.volume.set(mix.volume);
+        self.mute.set(mix.mute);
+        self.solo.set(mix.solo);
+    }
+
     pub fn mute_playback_signal(&self, any_voice_solo: Signal<bool>) -> Signal<bool> {
         let mute = self.mute;
         let solo = self.solo;
@@ -29,11 +61,25 @@ impl VoiceState {
 pub fn VoiceControl(
     voice_state: VoiceState,
     #[prop(into)] any_voice_solo: Signal<bool>,
+    /// Everything the voice can be played with, `voice_state.instrument` indexes into this.
+    #[prop(into)]
+    instrument_names: Signal<Vec<String>>,
+    /// Called after the user renames the voice.
+    #[prop(into)]
+    on_rename: Callback<()>,
 ) -> impl IntoView {
     view! {
         <div class="flex flex-col items-center p-4 border border-black border-solid rounded-sm">
-            <p class:text-red-600=voice_state
-                .mute_playback_signal(any_voice_solo)>{voice_state.name.clone()}</p>
+            <input
+                class="w-24 text-center bg-transparent"
+                class:text-red-600=voice_state.mute_playback_signal(any_voice_solo)
+                title="Click to rename"
+                prop:value=voice_state.name
+                on:change:target=move |ev| {
+                    voice_state.name.set(ev.target().value());
+                    on_rename.run(());
+                }
+            />
             <div class="flex flex-row space-x-1">
                 <button
                     class="border border-black rounded-sm px-1"
@@ -59,6 +105,48 @@ pub fn VoiceControl(
                     voice_state.volume.set(ev.target().value().parse().unwrap());
                 }
             />
+            <p>"Pan"</p>
+            <div class="flex flex-row items-center space-x-1">
+                <span>"L"</span>
+                <input
+                    type="range"
+                    min="-100"
+                    max="100"
+                    title="Double-click to center"
+                    prop:value=voice_state.pan
+                    on:input:target=move |ev| {
+                        voice_state.pan.set(ev.target().value().parse().unwrap());
+                    }
+                    on:dblclick=move |_| voice_state.pan.set(0)
+                />
+                <span>"R"</span>
+            </div>
+            <select
+                class="border"
+                on:change:target=move |ev| {
+                    if let Ok(instrument) = ev.target().value().parse() {
+                        voice_state.instrument.set(instrument);
+                    }
+                }
+            >
+                {move || {
+                    instrument_names
+                        .get()
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, name)| {
+                            view! {
+                                <option
+                                    value=index
+                                    selected=move || voice_state.instrument.get() == index
+                                >
+                                    {name}
+                                </option>
+                            }
+                        })
+                        .collect_vec()
+                }}
+            </select>
         </div>
     }
 }
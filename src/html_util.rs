@@ -1,4 +1,6 @@
-use web_sys::{Element, HtmlCollection};
+use js_sys::{Array, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{Blob, BlobPropertyBag, Element, HtmlAnchorElement, HtmlCollection, Url};
 
 struct HtmlCollectionIterator {
     collection: HtmlCollection,
@@ -27,3 +29,24 @@ impl HtmlCollectionIntoIterator for HtmlCollection {
         }
     }
 }
+
+/// Has the browser save `data` as a file called `file_name`.
+pub fn download_bytes(file_name: &str, mime_type: &str, data: &[u8]) -> Result<(), JsValue> {
+    let options = BlobPropertyBag::new();
+    options.set_type(mime_type);
+    let parts = Array::of1(&Uint8Array::from(data));
+    let blob = Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or_else(|| JsValue::from_str("No document to download from"))?;
+    let anchor = document
+        .create_element("a")?
+        .dyn_into::<HtmlAnchorElement>()?;
+    anchor.set_href(&url);
+    anchor.set_download(file_name);
+    anchor.click();
+
+    Url::revoke_object_url(&url)
+}
@@ -0,0 +1,245 @@
+use std::cell::Cell;
+use std::fmt::Debug;
+
+use log::error;
+use wasm_bindgen::JsValue;
+use web_sys::{AudioNode, AudioScheduledSourceNode, BaseAudioContext, GainNode};
+
+use crate::midi_output::MidiNote;
+use crate::synth::{Envelope, SynthPatch};
+
+/// Something that can play notes, eg the piano `Sampler` or a `Synth`.
+///
+/// Instruments create their nodes from `output_node`'s context rather than holding onto one, so the
+/// same instrument can play live or into an offline render.
+pub trait Instrument: Debug {
+    /// Starts playing a note at the given frequency at `when` (in `AudioContext` time), or
+    /// immediately if that's in the past. `gain` is how hard it was struck (1 being the usual),
+    /// which scales the note's envelope and picks the layer on instruments with velocity layers.
+    fn start_note(
+        &self,
+        frequency: f64,
+        gain: f32,
+        output_node: &AudioNode,
+        when: f64,
+    ) -> Result<PlaybackGuard, JsValue>;
+}
+
+/// Holds onto the playback nodes that were started by an `Instrument`, or a note sent out over
+/// MIDI, allowing you to stop them before they finish on their own.
+///
+/// Dropping the object will stop the playback, unless it was already scheduled to stop (via
+/// `release_at`) at some point in the past.
+// TODO - If we need to get around this behavior we can add a `.forget()`.
+pub struct PlaybackGuard {
+    ctx: BaseAudioContext,
+    start_time: f64,
+    release_time: Cell<Option<f64>>,
+    output: GuardedOutput,
+}
+
+enum GuardedOutput {
+    Audio {
+        sources: Vec<AudioScheduledSourceNode>,
+        /// The note's envelope, everything in `sources` goes through this.
+        gain: GainNode,
+        peak_level: f32,
+        /// The shape `gain` was scheduled with, so a release can fade out from wherever it's got
+        /// to.
+        envelope: Envelope,
+    },
+    Midi(MidiNote),
+}
+
+impl PlaybackGuard {
+    /// `gain` should already be scheduled to follow `envelope` from `start_time`, peaking at
+    /// `peak_level`. The release should stay under a second, since that's how long the transport
+    /// holds onto notes after their release.
+    pub fn new(
+        ctx: BaseAudioContext,
+        sources: Vec<AudioScheduledSourceNode>,
+        gain: GainNode,
+        start_time: f64,
+        peak_level: f32,
+        envelope: Envelope,
+    ) -> Self {
+        Self {
+            ctx,
+            start_time,
+            release_time: Cell::new(None),
+            output: GuardedOutput::Audio {
+                sources,
+                gain,
+                peak_level,
+                envelope,
+            },
+        }
+    }
+
+    /// Guards a note that was sent to a MIDI port. `ctx` is the clock `start_time` and any releases
+    /// are measured against.
+    pub fn for_midi(ctx: BaseAudioContext, note: MidiNote, start_time: f64) -> Self {
+        Self {
+            ctx,
+            start_time,
+            release_time: Cell::new(None),
+            output: GuardedOutput::Midi(note),
+        }
+    }
+
+    /// Schedules the note to fade out starting at `when` (in `AudioContext` time). Replaces any
+    /// previously scheduled release, apart from for MIDI notes, where the earliest release wins.
+    pub fn release_at(&self, when: f64) -> Result<(), JsValue> {
+        match &self.output {
+            GuardedOutput::Audio {
+                sources,
+                gain,
+                peak_level,
+                envelope,
+            } => {
+                let level = self.level_at(*peak_level, envelope, when);
+                let end_time = when + envelope.release;
+                gain.gain().cancel_scheduled_values(when)?;
+                // Cancelling drops any ramp that was still under way at `when`, so finish it off
+                // there rather than jumping.
+                gain.gain().linear_ramp_to_value_at_time(level, when)?;
+                gain.gain().linear_ramp_to_value_at_time(0.0, end_time)?;
+                for source in sources {
+                    source.stop_with_when(end_time)?;
+                }
+            }
+            GuardedOutput::Midi(note) => note.release_at(&self.ctx, when)?,
+        }
+        self.release_time.set(Some(when));
+
+        Ok(())
+    }
+
+    /// Where the envelope is at `time`, taking into account any release scheduled before then.
+    fn level_at(&self, peak_level: f32, envelope: &Envelope, time: f64) -> f32 {
+        let held_level = |time: f64| envelope.level_at(peak_level, time - self.start_time);
+        match self.release_time.get() {
+            Some(release_time) if release_time < time => {
+                let released = ((time - release_time) / envelope.release).min(1.0);
+                held_level(release_time) * (1.0 - released) as f32
+            }
+            _ => held_level(time),
+        }
+    }
+}
+
+impl Drop for PlaybackGuard {
+    fn drop(&mut self) {
+        let current_time = self.ctx.current_time();
+        if self
+            .release_time
+            .get()
+            .is_some_and(|release_time| release_time <= current_time)
+        {
+            // Already on its way out
+            return;
+        }
+        if self.start_time > current_time {
+            match &self.output {
+                // Never got going, so there's nothing to fade out.
+                GuardedOutput::Audio { sources, .. } => {
+                    for source in sources {
+                        if let Err(e) = source.stop_with_when(0.0) {
+                            error!("Failed to cancel playback: {e:?}");
+                        }
+                    }
+                }
+                // The note-on has already been sent, so it needs a note-off to follow it.
+                GuardedOutput::Midi(note) => {
+                    if let Err(e) = note.release_at(&self.ctx, self.start_time) {
+                        error!("Failed to cancel playback: {e:?}");
+                    }
+                }
+            }
+            return;
+        }
+        if let Err(e) = self.release_at(current_time) {
+            error!("Failed to stop playback: {e:?}");
+        }
+    }
+}
+
+/// The instruments a voice can be played with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstrumentPreset {
+    Piano,
+    Synth(SynthPatch),
+    /// An instrument the user loaded (eg from a SoundFont), by the order it was added to the
+    /// `PlaybackManager`.
+    Loaded(usize),
+}
+
+/// Stands in for the piano if none of its samples could be loaded. Decays like a struck string so
+/// it's at least piano-ish.
+pub const FALLBACK_PIANO: SynthPatch = SynthPatch {
+    harmonics: &[1.0, 0.5, 0.3, 0.15, 0.08, 0.04],
+    envelope: Envelope {
+        attack: 0.005,
+        decay: 2.0,
+        sustain: 0.05,
+        release: 0.3,
+    },
+    vibrato_cents: 0.0,
+};
+
+/// The instruments offered in the UI, by name. The first is the default.
+pub const INSTRUMENT_PRESETS: &[(&str, InstrumentPreset)] = &[
+    ("Piano", InstrumentPreset::Piano),
+    (
+        "Ooh",
+        InstrumentPreset::Synth(SynthPatch {
+            harmonics: &[1.0, 0.25, 0.08, 0.03],
+            envelope: Envelope {
+                attack: 0.12,
+                decay: 0.2,
+                sustain: 0.8,
+                release: 0.3,
+            },
+            vibrato_cents: 12.0,
+        }),
+    ),
+    (
+        "Aah",
+        InstrumentPreset::Synth(SynthPatch {
+            harmonics: &[1.0, 0.7, 0.5, 0.35, 0.4, 0.2, 0.12, 0.08, 0.05],
+            envelope: Envelope {
+                attack: 0.08,
+                decay: 0.2,
+                sustain: 0.75,
+                release: 0.3,
+            },
+            vibrato_cents: 15.0,
+        }),
+    ),
+    (
+        "Organ",
+        InstrumentPreset::Synth(SynthPatch {
+            harmonics: &[1.0, 0.6, 0.0, 0.4, 0.0, 0.0, 0.0, 0.25],
+            envelope: Envelope {
+                attack: 0.02,
+                decay: 0.0,
+                sustain: 1.0,
+                release: 0.08,
+            },
+            vibrato_cents: 0.0,
+        }),
+    ),
+    (
+        "Sine",
+        InstrumentPreset::Synth(SynthPatch {
+            harmonics: &[1.0],
+            envelope: Envelope {
+                attack: 0.03,
+                decay: 0.0,
+                sustain: 1.0,
+                release: 0.1,
+            },
+            vibrato_cents: 0.0,
+        }),
+    ),
+];
@@ -0,0 +1,477 @@
+//! Which keys do what. Keys are identified by `KeyboardEvent.code`, ie where they physically are
+//! rather than what's printed on them, so the layout of the keys stays the same on any keyboard.
+
+use std::collections::HashMap;
+
+use gloo::storage::{LocalStorage, Storage};
+use js_sys::{Array, Function, Promise, Reflect};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+
+use crate::future_util::PromiseAsFuture;
+
+const STORAGE_KEY: &str = "keymap";
+
+/// The keys from left to right, then top to bottom, for one hand on the left and then the right.
+const ONE_HAND_CODES: [&str; 24] = [
+    "KeyQ",
+    "KeyW",
+    "KeyE",
+    "KeyR",
+    "KeyA",
+    "KeyS",
+    "KeyD",
+    "KeyF",
+    "KeyZ",
+    "KeyX",
+    "KeyC",
+    "KeyV",
+    "KeyU",
+    "KeyI",
+    "KeyO",
+    "KeyP",
+    "KeyJ",
+    "KeyK",
+    "KeyL",
+    "Semicolon",
+    "KeyM",
+    "Comma",
+    "Period",
+    "Slash",
+];
+/// The three letter rows, each all the way across.
+const TWO_HAND_CODES: [&str; 30] = [
+    "KeyQ",
+    "KeyW",
+    "KeyE",
+    "KeyR",
+    "KeyT",
+    "KeyY",
+    "KeyU",
+    "KeyI",
+    "KeyO",
+    "KeyP",
+    "KeyA",
+    "KeyS",
+    "KeyD",
+    "KeyF",
+    "KeyG",
+    "KeyH",
+    "KeyJ",
+    "KeyK",
+    "KeyL",
+    "Semicolon",
+    "KeyZ",
+    "KeyX",
+    "KeyC",
+    "KeyV",
+    "KeyB",
+    "KeyN",
+    "KeyM",
+    "Comma",
+    "Period",
+    "Slash",
+];
+
+/// Something a key can do.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum KeymapAction {
+    /// Play the position this many slices past the start.
+    PlayOffset(usize),
+    /// Move the start to just after the most recently played position.
+    SetStart,
+    NudgeStartBack,
+    NudgeStartForward,
+    /// Move the start back to the beginning of the song.
+    ResetStart,
+    /// Loop from the start to the most recently played position, or stop looping if we already
+    /// are.
+    ToggleLoop,
+}
+
+impl KeymapAction {
+    pub fn description(self) -> String {
+        match self {
+            KeymapAction::PlayOffset(offset) => format!("Play position {}", offset + 1),
+            KeymapAction::SetStart => "Start after the last note played".to_string(),
+            KeymapAction::NudgeStartBack => "Move the start back".to_string(),
+            KeymapAction::NudgeStartForward => "Move the start forward".to_string(),
+            KeymapAction::ResetStart => "Start from the beginning".to_string(),
+            KeymapAction::ToggleLoop => "Loop from the start to the last note played".to_string(),
+        }
+    }
+
+    /// Whether holding the key down should keep doing it.
+    pub fn allows_repeats(self) -> bool {
+        matches!(
+            self,
+            KeymapAction::NudgeStartBack | KeymapAction::NudgeStartForward
+        )
+    }
+}
+
+/// What's printed on the keys, so hints can show the key the user will actually look for.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum KeyboardLayout {
+    #[default]
+    Qwerty,
+    Azerty,
+    Dvorak,
+}
+
+impl KeyboardLayout {
+    /// The legend on the key with the given code.
+    pub fn label(self, code: &str) -> String {
+        let overridden = match self {
+            KeyboardLayout::Qwerty => None,
+            KeyboardLayout::Azerty => azerty_label(code),
+            KeyboardLayout::Dvorak => dvorak_label(code),
+        };
+        overridden.map_or_else(|| qwerty_label(code), str::to_string)
+    }
+}
+
+fn qwerty_label(code: &str) -> String {
+    if let Some(letter) = code.strip_prefix("Key") {
+        return letter.to_lowercase();
+    }
+    if let Some(digit) = code.strip_prefix("Digit") {
+        return digit.to_string();
+    }
+    let label = match code {
+        "Semicolon" => ";",
+        "Comma" => ",",
+        "Period" => ".",
+        "Slash" => "/",
+        "Quote" => "'",
+        "Backquote" => "`",
+        "BracketLeft" => "[",
+        "BracketRight" => "]",
+        "Backslash" => "\\",
+        "Minus" => "-",
+        "Equal" => "=",
+        "Space" => "Space",
+        "ArrowLeft" => "←",
+        "ArrowRight" => "→",
+        "ArrowUp" => "↑",
+        "ArrowDown" => "↓",
+        _ => code,
+    };
+    label.to_string()
+}
+
+fn azerty_label(code: &str) -> Option<&'static str> {
+    let label = match code {
+        "KeyQ" => "a",
+        "KeyW" => "z",
+        "KeyA" => "q",
+        "KeyZ" => "w",
+        "Semicolon" => "m",
+        "KeyM" => ",",
+        "Comma" => ";",
+        "Period" => ":",
+        "Slash" => "!",
+        "Backquote" => "²",
+        _ => return None,
+    };
+    Some(label)
+}
+
+fn dvorak_label(code: &str) -> Option<&'static str> {
+    let label = match code {
+        "KeyQ" => "'",
+        "KeyW" => ",",
+        "KeyE" => ".",
+        "KeyR" => "p",
+        "KeyT" => "y",
+        "KeyY" => "f",
+        "KeyU" => "g",
+        "KeyI" => "c",
+        "KeyO" => "r",
+        "KeyP" => "l",
+        "KeyA" => "a",
+        "KeyS" => "o",
+        "KeyD" => "e",
+        "KeyF" => "u",
+        "KeyG" => "i",
+        "KeyH" => "d",
+        "KeyJ" => "h",
+        "KeyK" => "t",
+        "KeyL" => "n",
+        "Semicolon" => "s",
+        "KeyZ" => ";",
+        "KeyX" => "q",
+        "KeyC" => "j",
+        "KeyV" => "k",
+        "KeyB" => "x",
+        "KeyN" => "b",
+        "KeyM" => "m",
+        "Comma" => "w",
+        "Period" => "v",
+        "Slash" => "z",
+        _ => return None,
+    };
+    Some(label)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KeyBinding {
+    /// A `KeyboardEvent.code`.
+    pub code: String,
+    pub action: KeymapAction,
+}
+
+/// Every key the app responds to, saved in local storage once the user changes anything.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Keymap {
+    pub layout: KeyboardLayout,
+    pub bindings: Vec<KeyBinding>,
+    /// What's printed on each key by code, if the browser can tell us. This is the keyboard the
+    /// user actually has, so it's used for labels ahead of `layout`.
+    #[serde(skip)]
+    pub detected_labels: HashMap<String, String>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        KeymapPreset::OneHand.keymap()
+    }
+}
+
+impl Keymap {
+    /// The user's keymap, or the default if they haven't changed it.
+    pub fn load() -> Self {
+        LocalStorage::get(STORAGE_KEY).unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Err(e) = LocalStorage::set(STORAGE_KEY, self) {
+            warn!("Unable to save the keymap: {e}");
+        }
+    }
+
+    pub fn action_for_code(&self, code: &str) -> Option<KeymapAction> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.code == code)
+            .map(|binding| binding.action)
+    }
+
+    /// The label of the key that plays each position past the start, by offset, for hints.
+    pub fn position_labels(&self) -> Vec<(usize, String)> {
+        self.bindings
+            .iter()
+            .filter_map(|binding| match binding.action {
+                KeymapAction::PlayOffset(offset) => Some((offset, self.label(&binding.code))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn label(&self, code: &str) -> String {
+        match self.detected_labels.get(code) {
+            Some(label) => label.clone(),
+            None => self.layout.label(code),
+        }
+    }
+
+    /// Switches to the preset's keys, keeping what we know about the user's keyboard.
+    pub fn apply_preset(&mut self, preset: KeymapPreset) {
+        let detected_labels = std::mem::take(&mut self.detected_labels);
+        *self = Keymap {
+            detected_labels,
+            ..preset.keymap()
+        };
+    }
+
+    /// Whether this is the default keymap on a QWERTY keyboard, which is what the instructions'
+    /// picture shows.
+    pub fn matches_qwerty_picture(&self) -> bool {
+        self.bindings == KeymapPreset::OneHand.keymap().bindings
+            && ONE_HAND_CODES
+                .iter()
+                .all(|code| self.label(code) == qwerty_label(code))
+    }
+
+    /// Binds `code` to the action at `index`, taking it away from anything else it was bound to.
+    pub fn rebind(&mut self, index: usize, code: String) {
+        let Some(action) = self.bindings.get(index).map(|binding| binding.action) else {
+            return;
+        };
+        self.bindings.retain(|binding| binding.code != code);
+        // Removing other bindings may have moved this one.
+        if let Some(binding) = self
+            .bindings
+            .iter_mut()
+            .find(|binding| binding.action == action)
+        {
+            binding.code = code;
+        } else {
+            self.bindings.push(KeyBinding { code, action });
+        }
+    }
+
+    /// The position just after the furthest one any key plays, for adding another key.
+    pub fn next_offset(&self) -> usize {
+        self.bindings
+            .iter()
+            .filter_map(|binding| match binding.action {
+                KeymapAction::PlayOffset(offset) => Some(offset + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// A starting point for the keymap.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeymapPreset {
+    /// Four by three blocks under each hand, which is what the app has always used.
+    OneHand,
+    /// The letter rows all the way across, for more positions at once.
+    TwoHandRow,
+    /// `OneHand`'s keys, labelled as they're printed on an AZERTY keyboard.
+    Azerty,
+    /// `OneHand`'s keys, labelled as they're printed on a Dvorak keyboard.
+    Dvorak,
+}
+
+/// The keymaps offered in the UI, by name. The first is the default.
+pub const KEYMAP_PRESETS: &[(&str, KeymapPreset)] = &[
+    ("One hand", KeymapPreset::OneHand),
+    ("Two hands, full rows", KeymapPreset::TwoHandRow),
+    ("AZERTY", KeymapPreset::Azerty),
+    ("Dvorak", KeymapPreset::Dvorak),
+];
+
+impl KeymapPreset {
+    pub fn keymap(self) -> Keymap {
+        let (layout, position_codes): (_, &[&str]) = match self {
+            KeymapPreset::OneHand => (KeyboardLayout::Qwerty, &ONE_HAND_CODES),
+            KeymapPreset::TwoHandRow => (KeyboardLayout::Qwerty, &TWO_HAND_CODES),
+            KeymapPreset::Azerty => (KeyboardLayout::Azerty, &ONE_HAND_CODES),
+            KeymapPreset::Dvorak => (KeyboardLayout::Dvorak, &ONE_HAND_CODES),
+        };
+        let bindings = position_codes
+            .iter()
+            .enumerate()
+            .map(|(offset, code)| (*code, KeymapAction::PlayOffset(offset)))
+            .chain([
+                ("Space", KeymapAction::SetStart),
+                ("ArrowLeft", KeymapAction::NudgeStartBack),
+                ("ArrowRight", KeymapAction::NudgeStartForward),
+                ("Backquote", KeymapAction::ResetStart),
+                ("Enter", KeymapAction::ToggleLoop),
+            ])
+            .map(|(code, action)| KeyBinding {
+                code: code.to_string(),
+                action,
+            })
+            .collect();
+        Keymap {
+            layout,
+            bindings,
+            detected_labels: HashMap::new(),
+        }
+    }
+}
+
+/// What's printed on each key of the user's keyboard, by code. Only some browsers (currently
+/// Chromium based ones) will tell us this, otherwise this gives `None`.
+pub async fn detect_key_labels() -> Option<HashMap<String, String>> {
+    let navigator = web_sys::window()?.navigator();
+    let keyboard = Reflect::get(&navigator, &"keyboard".into())
+        .ok()
+        .filter(|keyboard| !keyboard.is_undefined())?;
+    let get_layout_map = Reflect::get(&keyboard, &"getLayoutMap".into())
+        .ok()?
+        .dyn_into::<Function>()
+        .ok()?;
+    let layout_map = get_layout_map
+        .call0(&keyboard)
+        .ok()?
+        .dyn_into::<Promise>()
+        .ok()?
+        .into_future()
+        .await
+        .ok()?;
+    // The layout map is map-like, so iterating it gives [code, label] pairs.
+    let entries = js_sys::try_iter(&layout_map).ok()??;
+    let labels = entries
+        .filter_map(|entry| {
+            let entry = Array::from(&entry.ok()?);
+            Some((entry.get(0).as_string()?, entry.get(1).as_string()?))
+        })
+        .collect::<HashMap<_, _>>();
+    (!labels.is_empty()).then_some(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+
+    #[test]
+    fn one_hand_matches_the_original_letters() {
+        let keymap = KeymapPreset::OneHand.keymap();
+        let letters: String = keymap
+            .position_labels()
+            .into_iter()
+            .sorted()
+            .map(|(_, label)| label)
+            .collect();
+        assert_eq!(letters, "qwerasdfzxcvuiopjkl;m,./");
+        assert_eq!(
+            keymap.action_for_code("Space"),
+            Some(KeymapAction::SetStart)
+        );
+        assert_eq!(keymap.action_for_code("KeyT"), None);
+    }
+
+    #[test]
+    fn layout_presets_label_the_same_keys_differently() {
+        let qwerty = KeymapPreset::OneHand.keymap();
+        let azerty = KeymapPreset::Azerty.keymap();
+        assert_eq!(qwerty.bindings, azerty.bindings);
+        assert_eq!(azerty.label("KeyQ"), "a");
+        assert_eq!(azerty.label("KeyE"), "e");
+        assert_eq!(KeymapPreset::Dvorak.keymap().label("KeyS"), "o");
+    }
+
+    #[test]
+    fn detected_labels_win_and_survive_presets() {
+        let mut keymap = KeymapPreset::OneHand.keymap();
+        assert!(keymap.matches_qwerty_picture());
+        // QWERTZ swaps Y and Z.
+        keymap.detected_labels = HashMap::from([
+            ("KeyY".to_string(), "z".to_string()),
+            ("KeyZ".to_string(), "y".to_string()),
+        ]);
+        assert_eq!(keymap.label("KeyZ"), "y");
+        assert_eq!(keymap.label("KeyQ"), "q");
+        assert!(!keymap.matches_qwerty_picture());
+        keymap.apply_preset(KeymapPreset::Dvorak);
+        assert_eq!(keymap.label("KeyZ"), "y");
+        assert_eq!(keymap.label("KeyS"), "o");
+    }
+
+    #[test]
+    fn rebinding_takes_the_key_from_anything_else() {
+        let mut keymap = KeymapPreset::OneHand.keymap();
+        // Move the first position onto the second position's key.
+        keymap.rebind(0, "KeyW".to_string());
+        assert_eq!(
+            keymap.action_for_code("KeyW"),
+            Some(KeymapAction::PlayOffset(0))
+        );
+        assert_eq!(keymap.action_for_code("KeyQ"), None);
+        assert!(!keymap
+            .bindings
+            .iter()
+            .any(|binding| binding.action == KeymapAction::PlayOffset(1)));
+        assert_eq!(keymap.next_offset(), 24);
+    }
+}
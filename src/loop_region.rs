@@ -0,0 +1,70 @@
+/// A stretch of the song to practice over and over. Stepping or playing past the end goes back to
+/// the start.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopRegion {
+    /// The first slice in the loop.
+    pub start: usize,
+    /// The last slice in the loop, inclusive.
+    pub end: usize,
+    /// How many times to play through before stopping, or `None` to keep going until stopped.
+    pub repeats: Option<u32>,
+    /// How much faster each pass plays than the one before, eg 0.05 for 5%.
+    pub speed_up_per_pass: f64,
+}
+
+impl LoopRegion {
+    /// A loop between the two slices, in either order, which repeats until stopped.
+    pub fn new(a: usize, b: usize) -> Self {
+        Self {
+            start: a.min(b),
+            end: a.max(b),
+            repeats: None,
+            speed_up_per_pass: 0.0,
+        }
+    }
+
+    /// The number of slices in the loop.
+    pub fn num_slices(&self) -> usize {
+        self.end - self.start + 1
+    }
+
+    /// Brings anything past the end back around into the loop, as if the loop were repeated
+    /// end to end. Anything before the end is left alone.
+    pub fn wrap(&self, song_index: usize) -> usize {
+        if song_index > self.end {
+            self.start + (song_index - self.start) % self.num_slices()
+        } else {
+            song_index
+        }
+    }
+}
+
+/// Wraps `song_index` into the loop if there is one.
+pub fn wrap_song_index(loop_region: Option<LoopRegion>, song_index: usize) -> usize {
+    loop_region.map_or(song_index, |loop_region| loop_region.wrap(song_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ends_can_be_given_in_either_order() {
+        assert_eq!(LoopRegion::new(8, 4), LoopRegion::new(4, 8));
+        assert_eq!(LoopRegion::new(8, 4).num_slices(), 5);
+        assert_eq!(LoopRegion::new(3, 3).num_slices(), 1);
+    }
+
+    #[test]
+    fn stepping_past_the_end_wraps_to_the_start() {
+        let loop_region = LoopRegion::new(4, 7);
+        assert_eq!(loop_region.wrap(2), 2);
+        assert_eq!(loop_region.wrap(4), 4);
+        assert_eq!(loop_region.wrap(7), 7);
+        assert_eq!(loop_region.wrap(8), 4);
+        assert_eq!(loop_region.wrap(9), 5);
+        assert_eq!(loop_region.wrap(12), 4);
+        assert_eq!(LoopRegion::new(3, 3).wrap(10), 3);
+        assert_eq!(wrap_song_index(None, 10), 10);
+    }
+}
@@ -1,21 +1,42 @@
 use std::panic;
 
-use leptos::prelude::*;
 use leptos::mount::mount_to_body;
+use leptos::prelude::*;
+use leptos::task::spawn_local;
 
 use crate::components::app::App;
 
 mod components;
 mod future_util;
 mod html_util;
+mod instrument;
+mod keymap;
+mod loop_region;
+mod midi_export;
+mod midi_import;
+mod midi_input;
+mod midi_output;
+mod musicxml;
+mod offline_cache;
 mod opensheetmusicdisplay_bindings;
+mod panning;
 mod playback_manager;
+mod render;
 mod sampler;
 mod song_data;
+mod song_library;
+mod song_settings;
+mod soundfont;
+mod synth;
+mod temperament;
+mod timeline;
+mod transport;
+mod tuning;
 
 fn main() {
     console_log::init_with_level(log::Level::Info).unwrap();
     panic::set_hook(Box::new(console_error_panic_hook::hook));
+    spawn_local(offline_cache::initialize());
 
     mount_to_body(|| view! { <App /> });
 }
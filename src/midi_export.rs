@@ -0,0 +1,332 @@
+use bit_set::BitSet;
+use fraction::Fraction;
+use itertools::Itertools;
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::midi_import::PERCUSSION_CHANNEL;
+use crate::song_data::{SongData, DEFAULT_BPM};
+
+/// Used when the song's timestamps don't all land on a whole number of ticks at any resolution
+/// MIDI allows, eg with deeply nested tuplets. Notes are rounded to the nearest tick.
+const FALLBACK_TICKS_PER_QUARTER: u64 = 960;
+const NOTE_VELOCITY: u8 = 80;
+
+/// Something to write into a track, at a tick.
+enum TrackItem {
+    NoteOff(u8),
+    NoteOn(u8),
+}
+
+impl SongData {
+    /// Writes the song out as a Type 1 Standard MIDI File. The first track holds the tempo and
+    /// time signatures, followed by a track for each of `active_voices` named after the voice.
+    ///
+    /// * `transpose_semitones`: Shifts every note, any that fall off the keyboard are dropped.
+    /// * `tempo_bpm`: Replaces the tempo at the start of the song, with any later changes scaled to
+    ///   match, the same as when playing it.
+    pub fn to_midi(
+        &self,
+        active_voices: &BitSet,
+        transpose_semitones: i32,
+        tempo_bpm: Option<f64>,
+    ) -> Vec<u8> {
+        let ticks_per_quarter = self.ticks_per_quarter();
+        let to_tick = |timestamp: Fraction| -> u64 {
+            let numer = *timestamp.numer().unwrap_or(&0) as u128;
+            let denom = *timestamp.denom().unwrap_or(&1) as u128;
+            let ticks_per_whole = ticks_per_quarter as u128 * 4;
+            ((numer * ticks_per_whole * 2 + denom) / (denom * 2)) as u64
+        };
+
+        let first_timestamp = self
+            .slices
+            .first()
+            .map_or(Fraction::from(0), |slice| slice.timestamp);
+        let tempo_scale = match tempo_bpm {
+            Some(tempo_bpm) => tempo_bpm / self.tempo_at(first_timestamp).unwrap_or(DEFAULT_BPM),
+            None => 1.0,
+        };
+        let tempos = if self.tempo_changes.is_empty() {
+            vec![(0, DEFAULT_BPM * tempo_scale)]
+        } else {
+            self.tempo_changes
+                .iter()
+                .map(|tc| (to_tick(tc.timestamp), tc.bpm * tempo_scale))
+                .collect_vec()
+        };
+        let mut conductor = tempos
+            .into_iter()
+            .map(|(tick, bpm)| {
+                let micros_per_quarter = (60_000_000.0 / bpm).round().clamp(1.0, 0xFF_FFFF as f64);
+                (
+                    tick,
+                    TrackEventKind::Meta(MetaMessage::Tempo(u24::new(micros_per_quarter as u32))),
+                )
+            })
+            .collect_vec();
+        conductor.extend(self.time_signatures.iter().map(|ts| {
+            (
+                to_tick(ts.timestamp),
+                TrackEventKind::Meta(MetaMessage::TimeSignature(
+                    ts.numerator as u8,
+                    ts.denominator.trailing_zeros() as u8,
+                    // MIDI clocks per metronome click, and 32nds per quarter. Everyone uses these.
+                    24,
+                    8,
+                )),
+            )
+        }));
+        let mut tracks = vec![into_track(
+            conductor.into_iter().sorted_by_key(|(tick, _)| *tick),
+        )];
+
+        // Each voice gets its own channel, skipping percussion, so they can be told apart even
+        // if the file gets flattened to Type 0.
+        let channels = (0..16u8).filter(|c| *c != PERCUSSION_CHANNEL).collect_vec();
+        for (track_number, voice) in active_voices
+            .iter()
+            .filter(|voice| *voice < self.voice_index_mapping.len())
+            .enumerate()
+        {
+            let channel = u4::new(channels[track_number % channels.len()]);
+            let mut items = Vec::new();
+            for slice in &self.slices {
+                let Some(notes) = slice.notes_by_voice.get(voice) else {
+                    continue;
+                };
+                for note in notes.iter().filter(|note| slice.is_newly_struck(note)) {
+                    let Ok(pitch) = u8::try_from(note.pitch as i32 + transpose_semitones) else {
+                        continue;
+                    };
+                    if pitch > 127 {
+                        continue;
+                    }
+                    let start_tick = to_tick(note.onset);
+                    // Don't let rounding swallow the note entirely.
+                    let end_tick = to_tick(note.end_timestamp).max(start_tick + 1);
+                    items.push((start_tick, TrackItem::NoteOn(pitch)));
+                    items.push((end_tick, TrackItem::NoteOff(pitch)));
+                }
+            }
+            // Stop notes before starting new ones at the same tick, so repeated notes of the same
+            // pitch don't cut each other off.
+            items.sort_by_key(|(tick, item)| (*tick, matches!(item, TrackItem::NoteOn(_))));
+
+            let name = self
+                .voice_names
+                .get(voice)
+                .map(|name| name.as_bytes())
+                .unwrap_or_default();
+            let events = std::iter::once((0, TrackEventKind::Meta(MetaMessage::TrackName(name))))
+                .chain(items.into_iter().map(|(tick, item)| {
+                    let message = match item {
+                        TrackItem::NoteOn(key) => MidiMessage::NoteOn {
+                            key: u7::new(key),
+                            vel: u7::new(NOTE_VELOCITY),
+                        },
+                        TrackItem::NoteOff(key) => MidiMessage::NoteOff {
+                            key: u7::new(key),
+                            vel: u7::new(0),
+                        },
+                    };
+                    (tick, TrackEventKind::Midi { channel, message })
+                }));
+            tracks.push(into_track(events));
+        }
+
+        let smf = Smf {
+            header: Header::new(
+                Format::Parallel,
+                Timing::Metrical(u15::new(ticks_per_quarter as u16)),
+            ),
+            tracks,
+        };
+        let mut data = Vec::new();
+        smf.write_std(&mut data)
+            .expect("Writing to a Vec can't fail");
+        data
+    }
+
+    /// The coarsest resolution that puts every timestamp in the song on a whole tick.
+    fn ticks_per_quarter(&self) -> u64 {
+        let timestamps = self
+            .slices
+            .iter()
+            .flat_map(|slice| {
+                slice
+                    .notes_by_voice
+                    .iter()
+                    .flatten()
+                    .flat_map(|note| [note.onset, note.end_timestamp])
+            })
+            .chain(self.tempo_changes.iter().map(|tc| tc.timestamp))
+            .chain(self.time_signatures.iter().map(|ts| ts.timestamp));
+
+        let mut ticks_per_whole = 4u64;
+        for timestamp in timestamps {
+            let denom = *timestamp.denom().unwrap_or(&1);
+            ticks_per_whole = ticks_per_whole / gcd(ticks_per_whole, denom) * denom;
+            if ticks_per_whole / 4 > u15::max_value().as_int() as u64 {
+                return FALLBACK_TICKS_PER_QUARTER;
+            }
+        }
+        ticks_per_whole / 4
+    }
+}
+
+/// Converts events at absolute ticks, which must be in order, into a track.
+fn into_track<'a>(
+    events: impl IntoIterator<Item = (u64, TrackEventKind<'a>)>,
+) -> Vec<TrackEvent<'a>> {
+    let mut previous_tick = 0;
+    let mut track = Vec::new();
+    for (tick, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::new((tick - previous_tick) as u32),
+            kind,
+        });
+        previous_tick = tick;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLES: [(&str, &[u8]); 3] = [
+        (
+            "A Million Stars",
+            include_bytes!("../examples/A Million Stars.mid"),
+        ),
+        (
+            "Lone Prairie",
+            include_bytes!("../examples/Lone Prairie.mid"),
+        ),
+        ("Mam'selle", include_bytes!("../examples/Mam'selle.mid")),
+    ];
+
+    /// Every note in each voice as (onset, end, pitch), in a canonical order.
+    fn notes_by_voice(song_data: &SongData) -> Vec<Vec<(Fraction, Fraction, u32)>> {
+        (0..song_data.voice_index_mapping.len())
+            .map(|voice| {
+                song_data
+                    .slices
+                    .iter()
+                    .flat_map(|slice| {
+                        slice.notes_by_voice[voice]
+                            .iter()
+                            .filter(|note| slice.is_newly_struck(note))
+                            .map(|note| (note.onset, note.end_timestamp, note.pitch))
+                    })
+                    .sorted()
+                    .collect_vec()
+            })
+            .collect_vec()
+    }
+
+    fn all_voices(song_data: &SongData) -> BitSet {
+        (0..song_data.voice_index_mapping.len()).collect()
+    }
+
+    #[test]
+    fn examples_round_trip() {
+        for (name, data) in EXAMPLES {
+            let original = SongData::from_midi(data).unwrap();
+            let exported = original.to_midi(&all_voices(&original), 0, None);
+            let round_tripped = SongData::from_midi(&exported).unwrap();
+
+            assert_eq!(
+                notes_by_voice(&round_tripped),
+                notes_by_voice(&original),
+                "{name}"
+            );
+            assert_eq!(round_tripped.voice_names, original.voice_names, "{name}");
+            assert_eq!(
+                round_tripped.end_timestamp, original.end_timestamp,
+                "{name}"
+            );
+            for (round_tripped, original) in round_tripped
+                .tempo_changes
+                .iter()
+                .zip_eq(&original.tempo_changes)
+            {
+                assert_eq!(round_tripped.timestamp, original.timestamp, "{name}");
+                // Tempos are stored to the nearest microsecond per beat.
+                assert!((round_tripped.bpm - original.bpm).abs() < 0.01, "{name}");
+            }
+            assert_eq!(
+                round_tripped.time_signatures, original.time_signatures,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_active_voices_are_exported() {
+        let original = SongData::from_midi(EXAMPLES[0].1).unwrap();
+        assert!(original.voice_index_mapping.len() > 2);
+        let exported = original.to_midi(&BitSet::from_iter([0, 2]), 0, None);
+        let round_tripped = SongData::from_midi(&exported).unwrap();
+
+        let original_notes = notes_by_voice(&original);
+        assert_eq!(
+            notes_by_voice(&round_tripped),
+            vec![original_notes[0].clone(), original_notes[2].clone()]
+        );
+        assert_eq!(
+            round_tripped.voice_names,
+            vec![
+                original.voice_names[0].clone(),
+                original.voice_names[2].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn transposition_shifts_every_note() {
+        let original = SongData::from_midi(EXAMPLES[1].1).unwrap();
+        let exported = original.to_midi(&all_voices(&original), -3, None);
+        let round_tripped = SongData::from_midi(&exported).unwrap();
+
+        let transposed = notes_by_voice(&original)
+            .into_iter()
+            .map(|notes| {
+                notes
+                    .into_iter()
+                    .map(|(onset, end, pitch)| (onset, end, pitch - 3))
+                    .collect_vec()
+            })
+            .collect_vec();
+        assert_eq!(notes_by_voice(&round_tripped), transposed);
+    }
+
+    #[test]
+    fn tempo_override_scales_every_tempo_change() {
+        let original = SongData::from_midi(EXAMPLES[0].1).unwrap();
+        let first_bpm = original.tempo_at(Fraction::from(0)).unwrap();
+        let exported = original.to_midi(&all_voices(&original), 0, Some(first_bpm * 2.0));
+        let round_tripped = SongData::from_midi(&exported).unwrap();
+
+        for (round_tripped, original) in round_tripped
+            .tempo_changes
+            .iter()
+            .zip_eq(&original.tempo_changes)
+        {
+            assert!((round_tripped.bpm - original.bpm * 2.0).abs() < 0.01);
+        }
+    }
+}
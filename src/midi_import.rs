@@ -0,0 +1,406 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+
+use fraction::Fraction;
+use itertools::Itertools;
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::song_data::{
+    NamedPart, SliceBuilder, SongData, TempoChange, TimeSignature, VoiceIndexMapping,
+};
+
+/// Channel 10 (zero-indexed 9) is reserved for percussion in General MIDI, which isn't anything
+/// anyone would want to sing.
+pub const PERCUSSION_CHANNEL: u8 = 9;
+/// What every Standard MIDI File starts with.
+pub const MIDI_MAGIC: &[u8] = b"MThd";
+/// Timecode-based files don't have beats, so pretend they're at this tempo.
+const TIMECODE_BPM: u64 = 120;
+
+#[derive(Debug)]
+pub enum MidiImportError {
+    Parse(midly::Error),
+    /// Type 2 (sequential) files are a series of independent patterns, not a single song.
+    UnsupportedFormat,
+    /// The header says a beat (or frame) is zero ticks long, so nothing in the file has a time.
+    ZeroTicksPerBeat,
+}
+
+impl Display for MidiImportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MidiImportError::Parse(e) => write!(f, "Unable to parse MIDI file: {e}"),
+            MidiImportError::UnsupportedFormat => {
+                write!(f, "Sequential (type 2) MIDI files aren't supported")
+            }
+            MidiImportError::ZeroTicksPerBeat => {
+                write!(f, "The MIDI file's header has no ticks per beat")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MidiImportError {}
+
+impl From<midly::Error> for MidiImportError {
+    fn from(e: midly::Error) -> Self {
+        MidiImportError::Parse(e)
+    }
+}
+
+/// A note with a known start and end, in ticks.
+struct MidiNote {
+    start_tick: u64,
+    end_tick: u64,
+    voice_key: (u32, u32),
+    pitch: u32,
+}
+
+impl SongData {
+    /// Builds the song data from a Standard MIDI File (type 0 or 1). Each (track, channel) pair
+    /// that has notes becomes a voice.
+    pub fn from_midi(data: &[u8]) -> Result<Self, MidiImportError> {
+        let smf = Smf::parse(data)?;
+        if smf.header.format == Format::Sequential {
+            return Err(MidiImportError::UnsupportedFormat);
+        }
+
+        // Ticks per whole note, which is what all of our timestamps are in.
+        let ticks_per_whole = match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int() as u64 * 4,
+            Timing::Timecode(fps, subframes) => {
+                let ticks_per_second = fps.as_int() as u64 * subframes as u64;
+                ticks_per_second * 60 * 4 / TIMECODE_BPM
+            }
+        };
+        if ticks_per_whole == 0 {
+            return Err(MidiImportError::ZeroTicksPerBeat);
+        }
+        let to_timestamp = |tick: u64| Fraction::new(tick, ticks_per_whole);
+
+        let mut notes = Vec::new();
+        let mut tempo_changes = Vec::new();
+        let mut time_signatures = Vec::new();
+        let mut parts = Vec::new();
+        if let Timing::Timecode(..) = smf.header.timing {
+            tempo_changes.push(TempoChange {
+                timestamp: Fraction::from(0),
+                bpm: TIMECODE_BPM as f64,
+            });
+        }
+
+        for (track_index, track) in smf.tracks.iter().enumerate() {
+            // Notes that have started but not stopped yet. A queue per key so that overlapping
+            // notes of the same pitch are closed in the order they were opened.
+            let mut open_notes: HashMap<((u32, u32), u32), VecDeque<u64>> = HashMap::new();
+            let mut tick = 0u64;
+            for event in track.iter() {
+                tick += event.delta.as_int() as u64;
+                match event.kind {
+                    TrackEventKind::Midi { channel, message } => {
+                        let channel = channel.as_int();
+                        if channel == PERCUSSION_CHANNEL {
+                            continue;
+                        }
+                        let voice_key = (track_index as u32, channel as u32);
+                        match message {
+                            MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                                open_notes
+                                    .entry((voice_key, key.as_int() as u32))
+                                    .or_default()
+                                    .push_back(tick);
+                            }
+                            // A note-on with 0 velocity is a note-off by convention.
+                            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                                let pitch = key.as_int() as u32;
+                                let start_tick = open_notes
+                                    .get_mut(&(voice_key, pitch))
+                                    .and_then(|starts| starts.pop_front());
+                                if let Some(start_tick) = start_tick {
+                                    notes.push(MidiNote {
+                                        start_tick,
+                                        end_tick: tick,
+                                        voice_key,
+                                        pitch,
+                                    });
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                        // LilyPond names tracks "staff:voice", where either half may be empty.
+                        let name = String::from_utf8_lossy(name);
+                        let name = name
+                            .rsplit(':')
+                            .find(|n| !n.trim().is_empty())
+                            .unwrap_or_default();
+                        parts.push(NamedPart {
+                            name: name.to_string(),
+                            alternate_names: Vec::new(),
+                            staff_ids: vec![track_index as u32],
+                        });
+                    }
+                    TrackEventKind::Meta(MetaMessage::Tempo(micros_per_beat)) => {
+                        // Timecode files are timed in seconds, so tempo doesn't apply. A beat
+                        // lasting no time at all is corrupt, so skip it rather than playing
+                        // infinitely fast.
+                        if matches!(smf.header.timing, Timing::Metrical(_))
+                            && micros_per_beat.as_int() > 0
+                        {
+                            tempo_changes.push(TempoChange {
+                                timestamp: to_timestamp(tick),
+                                bpm: 60_000_000.0 / micros_per_beat.as_int() as f64,
+                            });
+                        }
+                    }
+                    TrackEventKind::Meta(MetaMessage::TimeSignature(
+                        numerator,
+                        denominator_power,
+                        _,
+                        _,
+                    )) => {
+                        // The denominator is stored as a power of two. Anything too big to be a
+                        // real note value is corrupt, so skip it rather than overflowing.
+                        let Some(denominator) = 2u32.checked_pow(denominator_power as u32) else {
+                            continue;
+                        };
+                        time_signatures.push(TimeSignature {
+                            timestamp: to_timestamp(tick),
+                            numerator: numerator as u32,
+                            denominator,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            // Anything still held gets cut off at the end of the track.
+            for ((voice_key, pitch), starts) in open_notes {
+                notes.extend(starts.into_iter().map(|start_tick| MidiNote {
+                    start_tick,
+                    end_tick: tick,
+                    voice_key,
+                    pitch,
+                }));
+            }
+        }
+
+        // Type 1 files tend to repeat the conductor track's events, so keep the last one at any
+        // given time.
+        let tempo_changes = tempo_changes
+            .into_iter()
+            .rev()
+            .unique_by(|tc| tc.timestamp)
+            .sorted_by_key(|tc| tc.timestamp)
+            .collect_vec();
+        let time_signatures = time_signatures
+            .into_iter()
+            .rev()
+            .unique_by(|ts| ts.timestamp)
+            .sorted_by_key(|ts| ts.timestamp)
+            .collect_vec();
+
+        let voice_index_mapping = notes
+            .iter()
+            .map(|n| n.voice_key)
+            .unique()
+            .sorted()
+            .collect::<VoiceIndexMapping>();
+
+        // Every distinct note start is a position, there's no score to tell us otherwise.
+        let mut notes_by_tick: BTreeMap<u64, Vec<MidiNote>> = BTreeMap::new();
+        for note in notes {
+            notes_by_tick.entry(note.start_tick).or_default().push(note);
+        }
+        let mut slice_builder = SliceBuilder::new(voice_index_mapping.len());
+        for (cursor_index, (tick, notes)) in notes_by_tick.into_iter().enumerate() {
+            slice_builder.advance_to(to_timestamp(tick));
+            for note in notes {
+                let voice = voice_index_mapping
+                    .index_for_key(note.voice_key)
+                    .expect("Voice keys were built from these notes");
+                // Zero-length notes would never be heard, give them a tick so they still sound.
+                let duration_ticks = (note.end_tick - note.start_tick).max(1);
+                slice_builder.add_note(
+                    voice,
+                    note.pitch,
+                    Fraction::new(duration_ticks, ticks_per_whole),
+                );
+            }
+            slice_builder.finish_position(cursor_index);
+        }
+
+        let (slices, end_timestamp) = slice_builder.build();
+        Ok(Self {
+            voice_names: voice_index_mapping.voice_names(&parts),
+            voice_index_mapping,
+            slices,
+            end_timestamp,
+            tempo_changes,
+            time_signatures,
+            // There's nothing in a MIDI file to say how it's laid out into measures, let alone
+            // any repeats.
+            measures: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn parse_example(name: &str) -> SongData {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("examples")
+            .join(format!("{name}.mid"));
+        SongData::from_midi(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn example_songs_match_the_file() {
+        // (name, voice names, slices, end_timestamp)
+        let expected = [
+            (
+                "A Million Stars",
+                vec!["Voice 1", "Voice 2", "Voice 3", "Voice 4"],
+                18,
+                Fraction::from(7),
+            ),
+            (
+                "Lone Prairie",
+                vec!["Voice 1", "lead", "Voice 3", "bass"],
+                22,
+                Fraction::from(8),
+            ),
+            (
+                "Mam'selle",
+                vec!["Voice 1", "lead", "Voice 3", "bass"],
+                26,
+                Fraction::new(31u64, 4u64),
+            ),
+        ];
+        for (name, voice_names, slices, end_timestamp) in expected {
+            let song_data = parse_example(name);
+            assert_eq!(song_data.voice_index_mapping.len(), 4, "{name}");
+            assert_eq!(song_data.voice_names, voice_names, "{name}");
+            assert_eq!(song_data.slices.len(), slices, "{name}");
+            assert_eq!(song_data.end_timestamp, end_timestamp, "{name}");
+            assert_eq!(
+                song_data.tempo_changes,
+                vec![TempoChange {
+                    timestamp: Fraction::from(0),
+                    bpm: 120.0,
+                }],
+                "{name}"
+            );
+            assert_eq!(
+                song_data.time_signatures,
+                vec![TimeSignature {
+                    timestamp: Fraction::from(0),
+                    numerator: 4,
+                    denominator: 4,
+                }],
+                "{name}"
+            );
+            assert!(song_data.measures.is_empty(), "{name}");
+        }
+    }
+
+    /// A type 0 file with a single track made of `events`, at the given timing (the header's
+    /// division field).
+    fn single_track_file(division: [u8; 2], events: &[u8]) -> Vec<u8> {
+        let mut data = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01".to_vec();
+        data.extend(division);
+        data.extend(b"MTrk");
+        data.extend((events.len() as u32 + 4).to_be_bytes());
+        data.extend(events);
+        // End of track
+        data.extend([0x00, 0xFF, 0x2F, 0x00]);
+        data
+    }
+
+    /// 96 ticks per beat.
+    const DIVISION: [u8; 2] = [0x00, 0x60];
+    /// A middle C lasting a beat.
+    const NOTE: [u8; 8] = [0x00, 0x90, 0x3C, 0x40, 0x60, 0x80, 0x3C, 0x40];
+
+    #[test]
+    fn skips_impossible_time_signatures() {
+        // A 4/2^255 time signature followed by a single note.
+        let mut events = vec![0x00, 0xFF, 0x58, 0x04, 0x04, 0xFF, 0x18, 0x08];
+        events.extend(NOTE);
+
+        let song_data = SongData::from_midi(&single_track_file(DIVISION, &events)).unwrap();
+        assert!(song_data.time_signatures.is_empty());
+        assert_eq!(song_data.slices.len(), 1);
+    }
+
+    #[test]
+    fn skips_zero_tempos() {
+        let mut events = vec![0x00, 0xFF, 0x51, 0x03, 0x00, 0x00, 0x00];
+        events.extend(NOTE);
+
+        let song_data = SongData::from_midi(&single_track_file(DIVISION, &events)).unwrap();
+        assert!(song_data.tempo_changes.is_empty());
+        assert_eq!(song_data.slices.len(), 1);
+    }
+
+    #[test]
+    fn rejects_zero_ticks_per_beat() {
+        // Metrical, and then 24fps timecode with no subframes.
+        for division in [[0x00, 0x00], [0xE8, 0x00]] {
+            assert!(matches!(
+                SongData::from_midi(&single_track_file(division, &NOTE)),
+                Err(MidiImportError::ZeroTicksPerBeat)
+            ));
+        }
+    }
+
+    #[test]
+    fn splits_type_0_files_by_channel() {
+        // The same note on channels 1 and 2, then on the percussion channel.
+        let mut events = NOTE.to_vec();
+        events.extend([0x00, 0x91, 0x3C, 0x40, 0x60, 0x81, 0x3C, 0x40]);
+        events.extend([0x00, 0x99, 0x3C, 0x40, 0x60, 0x89, 0x3C, 0x40]);
+
+        let song_data = SongData::from_midi(&single_track_file(DIVISION, &events)).unwrap();
+        assert_eq!(song_data.voice_index_mapping.len(), 2);
+        assert_eq!(song_data.voice_index_mapping.index_for_key((0, 0)), Some(0));
+        assert_eq!(song_data.voice_index_mapping.index_for_key((0, 1)), Some(1));
+        assert_eq!(song_data.voice_names, vec!["Voice 1", "Voice 2"]);
+        assert_eq!(song_data.slices.len(), 2);
+    }
+
+    #[test]
+    fn overlapping_notes_of_the_same_pitch_end_in_order() {
+        // A middle C struck again half a beat in, with the first release ending the first note.
+        let events = [
+            0x00, 0x90, 0x3C, 0x40, // On at 0
+            0x30, 0x90, 0x3C, 0x40, // On at 48
+            0x30, 0x80, 0x3C, 0x40, // Off at 96
+            0x60, 0x80, 0x3C, 0x40, // Off at 192
+        ];
+
+        let song_data = SongData::from_midi(&single_track_file(DIVISION, &events)).unwrap();
+        let struck = song_data
+            .slices
+            .iter()
+            .flat_map(|slice| {
+                slice.notes_by_voice[0]
+                    .iter()
+                    .filter(|note| slice.is_newly_struck(note))
+                    .map(|note| (note.onset, note.end_timestamp))
+            })
+            .collect_vec();
+        assert_eq!(
+            struck,
+            vec![
+                (Fraction::from(0), Fraction::new(1u64, 4u64)),
+                (Fraction::new(1u64, 8u64), Fraction::new(1u64, 2u64)),
+            ]
+        );
+        assert_eq!(song_data.end_timestamp, Fraction::new(1u64, 2u64));
+    }
+}
@@ -0,0 +1,214 @@
+//! Turns messages from a MIDI keyboard or foot controller into playback actions, so the song can be
+//! stepped through without the computer keyboard.
+
+use midly::live::LiveEvent;
+use midly::MidiMessage;
+
+use crate::midi_import::PERCUSSION_CHANNEL;
+
+/// In `MidiInputMode::Keys`, this key plays the start position and each key above it the next.
+pub const FIRST_POSITION_KEY: u8 = 60;
+const SUSTAIN_PEDAL_CONTROLLER: u8 = 64;
+/// The velocity that plays at the same level as the computer keyboard.
+const FULL_GAIN_VELOCITY: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MidiInputMode {
+    /// Every key plays the next position, so it doesn't matter which one is pressed.
+    #[default]
+    Advance,
+    /// Keys play the position they're at relative to `FIRST_POSITION_KEY`, like the letter keys.
+    Keys,
+}
+
+/// What was pressed, so the notes it started can be stopped when it's released.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MidiTrigger {
+    Key(u8),
+    SustainPedal,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MidiInputAction {
+    /// Play the position after the one most recently played.
+    PlayNext {
+        trigger: MidiTrigger,
+        gain: f32,
+    },
+    /// Play the position this far past the start.
+    PlayOffset {
+        trigger: MidiTrigger,
+        offset: usize,
+        gain: f32,
+    },
+    Release {
+        trigger: MidiTrigger,
+    },
+}
+
+/// Works out what to do with a raw MIDI message, if anything. The sustain pedal always plays the
+/// next position, since it's the one thing a foot is guaranteed to be able to reach.
+pub fn action_for_message(mode: MidiInputMode, data: &[u8]) -> Option<MidiInputAction> {
+    let LiveEvent::Midi { channel, message } = LiveEvent::parse(data).ok()? else {
+        return None;
+    };
+    // Drum pads tend to be on the percussion channel, and hitting one shouldn't play anything.
+    if channel.as_int() == PERCUSSION_CHANNEL {
+        return None;
+    }
+    let action = match message {
+        MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+            let trigger = MidiTrigger::Key(key.as_int());
+            let gain = velocity_to_gain(vel.as_int());
+            match mode {
+                MidiInputMode::Advance => MidiInputAction::PlayNext { trigger, gain },
+                MidiInputMode::Keys => MidiInputAction::PlayOffset {
+                    trigger,
+                    offset: key.as_int().checked_sub(FIRST_POSITION_KEY)? as usize,
+                    gain,
+                },
+            }
+        }
+        // A note-on with 0 velocity is a note-off by convention.
+        MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+            MidiInputAction::Release {
+                trigger: MidiTrigger::Key(key.as_int()),
+            }
+        }
+        MidiMessage::Controller { controller, value }
+            if controller.as_int() == SUSTAIN_PEDAL_CONTROLLER =>
+        {
+            let trigger = MidiTrigger::SustainPedal;
+            if value.as_int() >= 64 {
+                MidiInputAction::PlayNext { trigger, gain: 1.0 }
+            } else {
+                MidiInputAction::Release { trigger }
+            }
+        }
+        _ => return None,
+    };
+    Some(action)
+}
+
+/// Loudness goes roughly with the square of velocity, scaled so that a typical medium-hard press
+/// matches the computer keyboard.
+pub fn velocity_to_gain(velocity: u8) -> f32 {
+    (velocity as f32 / FULL_GAIN_VELOCITY).powi(2)
+}
+
+/// The reverse of `velocity_to_gain`, for when something else needs to know how hard to play.
+pub fn gain_to_velocity(gain: f32) -> u8 {
+    (FULL_GAIN_VELOCITY * gain.max(0.0).sqrt())
+        .round()
+        .min(127.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE_ON: u8 = 0x90;
+    const NOTE_OFF: u8 = 0x80;
+    const CONTROL_CHANGE: u8 = 0xB0;
+
+    #[test]
+    fn any_key_advances() {
+        for key in [21, 60, 108] {
+            assert_eq!(
+                action_for_message(MidiInputMode::Advance, &[NOTE_ON, key, 100]),
+                Some(MidiInputAction::PlayNext {
+                    trigger: MidiTrigger::Key(key),
+                    gain: 1.0
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn keys_are_relative_to_the_first_position() {
+        let offset = |key| match action_for_message(MidiInputMode::Keys, &[NOTE_ON, key, 100]) {
+            Some(MidiInputAction::PlayOffset { offset, .. }) => Some(offset),
+            _ => None,
+        };
+        assert_eq!(offset(FIRST_POSITION_KEY), Some(0));
+        assert_eq!(offset(FIRST_POSITION_KEY + 1), Some(1));
+        assert_eq!(offset(FIRST_POSITION_KEY + 23), Some(23));
+        assert_eq!(offset(FIRST_POSITION_KEY - 1), None);
+    }
+
+    #[test]
+    fn releases_come_from_note_offs_on_any_channel() {
+        let release = Some(MidiInputAction::Release {
+            trigger: MidiTrigger::Key(64),
+        });
+        assert_eq!(
+            action_for_message(MidiInputMode::Keys, &[NOTE_OFF, 64, 40]),
+            release
+        );
+        assert_eq!(
+            action_for_message(MidiInputMode::Keys, &[NOTE_OFF | 3, 64, 0]),
+            release
+        );
+        // Lots of keyboards send note-ons with no velocity instead.
+        assert_eq!(
+            action_for_message(MidiInputMode::Advance, &[NOTE_ON, 64, 0]),
+            release
+        );
+    }
+
+    #[test]
+    fn sustain_pedal_advances_in_any_mode() {
+        for mode in [MidiInputMode::Advance, MidiInputMode::Keys] {
+            assert_eq!(
+                action_for_message(mode, &[CONTROL_CHANGE, 64, 127]),
+                Some(MidiInputAction::PlayNext {
+                    trigger: MidiTrigger::SustainPedal,
+                    gain: 1.0
+                })
+            );
+            assert_eq!(
+                action_for_message(mode, &[CONTROL_CHANGE, 64, 0]),
+                Some(MidiInputAction::Release {
+                    trigger: MidiTrigger::SustainPedal
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn velocity_carries_into_gain() {
+        let gain =
+            |velocity| match action_for_message(MidiInputMode::Advance, &[NOTE_ON, 60, velocity]) {
+                Some(MidiInputAction::PlayNext { gain, .. }) => gain,
+                action => panic!("Unexpected action {action:?}"),
+            };
+        assert_eq!(gain(100), 1.0);
+        assert!(gain(1) < gain(50));
+        assert!(gain(50) < gain(100));
+        assert!(gain(100) < gain(127));
+        for velocity in [1, 64, 100, 127] {
+            assert_eq!(gain_to_velocity(gain(velocity)), velocity);
+        }
+        assert_eq!(gain_to_velocity(0.0), 0);
+    }
+
+    #[test]
+    fn everything_else_is_ignored() {
+        let ignored = [
+            // Percussion channel
+            vec![NOTE_ON | PERCUSSION_CHANNEL, 36, 100],
+            // Modulation wheel
+            vec![CONTROL_CHANGE, 1, 127],
+            // Pitch bend
+            vec![0xE0, 0, 64],
+            // Timing clock
+            vec![0xF8],
+            // Truncated
+            vec![NOTE_ON, 60],
+            vec![],
+        ];
+        for data in ignored {
+            assert_eq!(action_for_message(MidiInputMode::Advance, &data), None);
+        }
+    }
+}
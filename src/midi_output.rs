@@ -0,0 +1,174 @@
+//! Plays the song out through a MIDI port, eg to a hardware synth or a DAW, rather than through
+//! WebAudio.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use itertools::Itertools;
+use wasm_bindgen::JsValue;
+use web_sys::{BaseAudioContext, MidiOutput};
+
+use crate::midi_import::PERCUSSION_CHANNEL;
+use crate::midi_input::gain_to_velocity;
+use crate::temperament::midi_note_for_frequency;
+
+const NOTE_ON: u8 = 0x90;
+const NOTE_OFF: u8 = 0x80;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PITCH_BEND: u8 = 0xE0;
+const ALL_NOTES_OFF_CONTROLLER: u8 = 123;
+/// How far a full pitch bend goes either way. This is the General MIDI default, so we don't try to
+/// change it.
+const PITCH_BEND_RANGE_SEMITONES: f64 = 2.0;
+const PITCH_BEND_CENTER: u16 = 0x2000;
+
+/// Which note was most recently started on each (channel, key), by its id.
+type KeyOwners = Rc<RefCell<HashMap<(u8, u8), u64>>>;
+
+/// Sends notes to a MIDI output port, with each voice on its own channel.
+#[derive(Debug)]
+pub struct MidiOutputTarget {
+    port: MidiOutput,
+    key_owners: KeyOwners,
+    next_note_id: Cell<u64>,
+}
+
+impl MidiOutputTarget {
+    pub fn new(port: MidiOutput) -> Self {
+        Self {
+            port,
+            key_owners: Rc::new(RefCell::new(HashMap::new())),
+            next_note_id: Cell::new(0),
+        }
+    }
+
+    /// Starts a note at `when` (in `ctx` time), or immediately if that's in the past. Any part of
+    /// `frequency` that's between keys is sent as a pitch bend, which applies to the whole channel,
+    /// so notes in the same voice that are tuned differently will pull each other around.
+    pub fn start_note(
+        &self,
+        ctx: &BaseAudioContext,
+        voice: usize,
+        frequency: f64,
+        gain: f32,
+        when: f64,
+    ) -> Result<MidiNote, JsValue> {
+        let channel = voice_channel(voice);
+        let semitones = midi_note_for_frequency(frequency);
+        let key = semitones.round().clamp(0.0, 127.0);
+        let velocity = gain_to_velocity(gain);
+        let id = self.next_note_id.get();
+        self.next_note_id.set(id + 1);
+        let note = MidiNote {
+            port: self.port.clone(),
+            key_owners: self.key_owners.clone(),
+            id,
+            channel,
+            key: key as u8,
+        };
+        // A note-on with no velocity is a note-off, so silent notes just don't get sent.
+        if velocity == 0 {
+            return Ok(note);
+        }
+
+        let bend = ((semitones - key) / PITCH_BEND_RANGE_SEMITONES).clamp(-1.0, 1.0);
+        let bend =
+            (PITCH_BEND_CENTER as f64 + bend * (PITCH_BEND_CENTER - 1) as f64).round() as u16;
+        send_at(
+            &self.port,
+            ctx,
+            &[PITCH_BEND | channel, (bend & 0x7F) as u8, (bend >> 7) as u8],
+            when,
+        )?;
+        send_at(
+            &self.port,
+            ctx,
+            &[NOTE_ON | channel, note.key, velocity],
+            when,
+        )?;
+        self.key_owners.borrow_mut().insert((channel, note.key), id);
+        Ok(note)
+    }
+
+    /// Silences everything on every channel we use and recenters the pitch bends, eg before
+    /// switching to another port.
+    pub fn all_notes_off(&self) -> Result<(), JsValue> {
+        for channel in (0..16).filter(|c| *c != PERCUSSION_CHANNEL) {
+            self.port.send(&message(&[
+                CONTROL_CHANGE | channel,
+                ALL_NOTES_OFF_CONTROLLER,
+                0,
+            ]))?;
+            self.port.send(&message(&[
+                PITCH_BEND | channel,
+                (PITCH_BEND_CENTER & 0x7F) as u8,
+                (PITCH_BEND_CENTER >> 7) as u8,
+            ]))?;
+        }
+        self.key_owners.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+/// A note started by a `MidiOutputTarget`, which `PlaybackGuard` sends the note-off for.
+#[derive(Debug)]
+pub struct MidiNote {
+    port: MidiOutput,
+    key_owners: KeyOwners,
+    id: u64,
+    channel: u8,
+    key: u8,
+}
+
+impl MidiNote {
+    /// Sends the note-off for `when` (in `ctx` time). MIDI only has one of each key per channel, so
+    /// if the same key has been struck again since, this leaves it alone rather than cutting off the
+    /// newer note. Messages can't be taken back once sent, so releasing again at a different time
+    /// only adds another note-off.
+    pub fn release_at(&self, ctx: &BaseAudioContext, when: f64) -> Result<(), JsValue> {
+        let mut key_owners = self.key_owners.borrow_mut();
+        match key_owners.get(&(self.channel, self.key)) {
+            Some(owner) if *owner != self.id => return Ok(()),
+            Some(_) => {
+                key_owners.remove(&(self.channel, self.key));
+            }
+            None => {}
+        }
+        send_at(
+            &self.port,
+            ctx,
+            &[NOTE_OFF | self.channel, self.key, 0],
+            when,
+        )
+    }
+}
+
+/// Voices go on consecutive channels, skipping percussion, and wrap around if there are more voices
+/// than channels.
+fn voice_channel(voice: usize) -> u8 {
+    let channels = (0..16u8).filter(|c| *c != PERCUSSION_CHANNEL).collect_vec();
+    channels[voice % channels.len()]
+}
+
+fn message(data: &[u8]) -> JsValue {
+    js_sys::Uint8Array::from(data).into()
+}
+
+/// Sends `data` at `when` (in `ctx` time). MIDI is timestamped against the page's clock rather than
+/// the audio one, so this converts between them as of now.
+fn send_at(
+    port: &MidiOutput,
+    ctx: &BaseAudioContext,
+    data: &[u8],
+    when: f64,
+) -> Result<(), JsValue> {
+    let delay_seconds = when - ctx.current_time();
+    let performance = web_sys::window().and_then(|window| window.performance());
+    match performance {
+        Some(performance) if delay_seconds > 0.0 => {
+            port.send_with_timestamp(&message(data), performance.now() + delay_seconds * 1000.0)
+        }
+        _ => port.send(&message(data)),
+    }
+}
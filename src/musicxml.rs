@@ -0,0 +1,924 @@
+//! A native (ie no OSMD/DOM needed) reader for MusicXML files, both the plain `.musicxml`/`.xml`
+//! flavor and the zipped `.mxl` flavor. It aims to produce the same `SongData` that
+//! `SongData::from_osmd` would for the same file, so that the song model can be built outside of
+//! a browser.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::io::{Cursor, Read};
+
+use fraction::Fraction;
+use itertools::Itertools;
+use roxmltree::{Document, Node};
+use zip::ZipArchive;
+
+use crate::song_data::{
+    Jump, Measure, NamedPart, SliceBuilder, SongData, TempoChange, TimeSignature, VoiceIndexMapping,
+};
+
+/// Why a MusicXML file couldn't be read.
+#[derive(Debug)]
+pub enum MusicXmlError {
+    Zip(zip::result::ZipError),
+    Io(std::io::Error),
+    Xml(roxmltree::Error),
+    /// The `.mxl` archive didn't contain a score we could find.
+    MissingRootFile,
+    /// The file parsed, but isn't shaped like a score we understand.
+    Malformed(String),
+}
+
+impl Display for MusicXmlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MusicXmlError::Zip(e) => write!(f, "Unable to read MXL archive: {e}"),
+            MusicXmlError::Io(e) => write!(f, "Unable to read MXL archive entry: {e}"),
+            MusicXmlError::Xml(e) => write!(f, "Unable to parse MusicXML: {e}"),
+            MusicXmlError::MissingRootFile => write!(f, "MXL archive has no score file"),
+            MusicXmlError::Malformed(message) => write!(f, "Malformed MusicXML: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for MusicXmlError {}
+
+impl From<zip::result::ZipError> for MusicXmlError {
+    fn from(e: zip::result::ZipError) -> Self {
+        MusicXmlError::Zip(e)
+    }
+}
+
+impl From<std::io::Error> for MusicXmlError {
+    fn from(e: std::io::Error) -> Self {
+        MusicXmlError::Io(e)
+    }
+}
+
+impl From<roxmltree::Error> for MusicXmlError {
+    fn from(e: roxmltree::Error) -> Self {
+        MusicXmlError::Xml(e)
+    }
+}
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+impl SongData {
+    /// Builds the song data straight from the bytes of a `.mxl`, `.musicxml` or `.xml` file.
+    pub fn from_musicxml(data: &[u8]) -> Result<Self, MusicXmlError> {
+        let xml = musicxml_text(data)?;
+        let document = parse_document(&xml)?;
+        let score = ParsedScore::from_document(&document)?;
+        Ok(score.into_song_data())
+    }
+}
+
+/// Who and what the score says it is, for listing it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScoreMetadata {
+    pub title: Option<String>,
+    pub composer: Option<String>,
+    pub arranger: Option<String>,
+}
+
+/// Reads the title, composer and arranger out of a `.mxl`, `.musicxml` or `.xml` file. These can
+/// be in the score's metadata or only in the text printed on the page, so we check both.
+pub fn score_metadata(data: &[u8]) -> Result<ScoreMetadata, MusicXmlError> {
+    let xml = musicxml_text(data)?;
+    let document = parse_document(&xml)?;
+    let root = document.root_element();
+    let non_empty = |text: Option<&str>| {
+        text.map(str::trim)
+            .filter(|text| !text.is_empty())
+            .map(str::to_string)
+    };
+    let creator = |creator_type: &str| {
+        let identified = root
+            .children()
+            .filter(|n| n.has_tag_name("identification"))
+            .flat_map(|n| n.children())
+            .find(|n| n.has_tag_name("creator") && n.attribute("type") == Some(creator_type))
+            .and_then(|n| non_empty(n.text()));
+        identified.or_else(|| credit_words(root, creator_type))
+    };
+
+    let title = root
+        .children()
+        .find(|n| n.has_tag_name("work"))
+        .and_then(|work| non_empty(child_text(work, "work-title")))
+        .or_else(|| non_empty(child_text(root, "movement-title")))
+        .or_else(|| credit_words(root, "title"));
+    Ok(ScoreMetadata {
+        title,
+        composer: creator("composer"),
+        arranger: creator("arranger"),
+    })
+}
+
+/// The text printed on the page for the given `credit-type`, eg "title".
+fn credit_words(root: Node, credit_type: &str) -> Option<String> {
+    root.children()
+        .filter(|n| n.has_tag_name("credit"))
+        .find(|credit| child_text(*credit, "credit-type") == Some(credit_type))
+        .map(|credit| {
+            credit
+                .children()
+                .filter(|n| n.has_tag_name("credit-words"))
+                .filter_map(|n| n.text())
+                .map(str::trim)
+                .join(" ")
+        })
+        .filter(|words| !words.is_empty())
+}
+
+/// Parses the score XML. Most exports start with a DOCTYPE, which roxmltree refuses by default.
+fn parse_document(xml: &str) -> Result<Document<'_>, roxmltree::Error> {
+    Document::parse_with_options(
+        xml,
+        roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        },
+    )
+}
+
+/// Gets the score XML out of the file, unzipping it first if needed.
+pub fn musicxml_text(data: &[u8]) -> Result<String, MusicXmlError> {
+    if !data.starts_with(ZIP_MAGIC) {
+        return Ok(String::from_utf8_lossy(data).into_owned());
+    }
+
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let root_file_path = match read_archive_entry(&mut archive, "META-INF/container.xml") {
+        Ok(container) => {
+            let container = Document::parse(&container)?;
+            container
+                .descendants()
+                .find(|n| n.has_tag_name("rootfile"))
+                .and_then(|n| n.attribute("full-path"))
+                .map(|path| path.to_string())
+        }
+        Err(_) => None,
+    };
+    // Fall back to the first thing that looks like a score if the container is missing/broken.
+    let root_file_path = root_file_path
+        .or_else(|| {
+            archive
+                .file_names()
+                .filter(|name| !name.starts_with("META-INF"))
+                .find(|name| name.ends_with(".xml") || name.ends_with(".musicxml"))
+                .map(|name| name.to_string())
+        })
+        .ok_or(MusicXmlError::MissingRootFile)?;
+
+    read_archive_entry(&mut archive, &root_file_path)
+}
+
+fn read_archive_entry(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<String, MusicXmlError> {
+    let mut entry = archive.by_name(name)?;
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// A note (or rest) as it appears in the score, positioned absolutely in whole notes.
+struct ScoreEntry {
+    timestamp: Fraction,
+    voice_key: (u32, u32),
+    /// `None` for rests and for notes that continue a tie, which still occupy a cursor position
+    /// but shouldn't start a new note.
+    sounding: Option<SoundingNote>,
+}
+
+struct SoundingNote {
+    pitch: u32,
+    duration: Fraction,
+}
+
+/// An entry positioned relative to the start of its measure.
+struct MeasureEntry {
+    measure_index: usize,
+    offset: Fraction,
+    entry: ScoreEntry,
+}
+
+/// Tempo or meter information, positioned relative to the start of its measure.
+enum MeasureMarking {
+    Tempo {
+        measure_index: usize,
+        offset: Fraction,
+        bpm: f64,
+    },
+    TimeSignature {
+        measure_index: usize,
+        numerator: u32,
+        denominator: u32,
+    },
+}
+
+struct ParsedScore {
+    entries: Vec<ScoreEntry>,
+    tempo_changes: Vec<TempoChange>,
+    time_signatures: Vec<TimeSignature>,
+    measures: Vec<Measure>,
+    parts: Vec<NamedPart>,
+}
+
+impl ParsedScore {
+    fn from_document(document: &Document) -> Result<Self, MusicXmlError> {
+        let root = document.root_element();
+        if !root.has_tag_name("score-partwise") {
+            return Err(MusicXmlError::Malformed(format!(
+                "Unsupported root element <{}>",
+                root.tag_name().name()
+            )));
+        }
+
+        let mut measure_entries = Vec::new();
+        let mut markings = Vec::new();
+        let mut measures: Vec<Measure> = Vec::new();
+        let mut parts = Vec::new();
+        let mut staff_offset = 0;
+        for part in root.children().filter(|n| n.has_tag_name("part")) {
+            let num_staves = parse_part(
+                part,
+                staff_offset,
+                &mut measure_entries,
+                &mut markings,
+                &mut measures,
+            )?;
+            let (name, alternate_names) = part_names(root, part.attribute("id"));
+            parts.push(NamedPart {
+                name,
+                alternate_names,
+                staff_ids: (staff_offset..staff_offset + num_staves).collect_vec(),
+            });
+            staff_offset += num_staves;
+        }
+
+        let mut measure_start = Fraction::from(0);
+        for measure in measures.iter_mut() {
+            measure.timestamp = measure_start;
+            measure_start += measure.duration;
+        }
+        let measure_starts = measures.iter().map(|m| m.timestamp).collect_vec();
+
+        let entries = measure_entries
+            .into_iter()
+            .map(|me| {
+                let mut entry = me.entry;
+                entry.timestamp = measure_starts[me.measure_index] + me.offset;
+                entry
+            })
+            .collect_vec();
+
+        // Every part tends to repeat the same markings, so dedupe them as we go.
+        let mut tempo_changes: Vec<TempoChange> = Vec::new();
+        let mut time_signatures: Vec<TimeSignature> = Vec::new();
+        for marking in markings {
+            match marking {
+                MeasureMarking::Tempo {
+                    measure_index,
+                    offset,
+                    bpm,
+                } => {
+                    let timestamp = measure_starts[measure_index] + offset;
+                    if !tempo_changes.iter().any(|tc| tc.timestamp == timestamp) {
+                        tempo_changes.push(TempoChange { timestamp, bpm });
+                    }
+                }
+                MeasureMarking::TimeSignature {
+                    measure_index,
+                    numerator,
+                    denominator,
+                } => {
+                    let timestamp = measure_starts[measure_index];
+                    if !time_signatures.iter().any(|ts| ts.timestamp == timestamp) {
+                        time_signatures.push(TimeSignature {
+                            timestamp,
+                            numerator,
+                            denominator,
+                        });
+                    }
+                }
+            }
+        }
+        tempo_changes.sort_by_key(|tc| tc.timestamp);
+        time_signatures.sort_by_key(|ts| ts.timestamp);
+
+        Ok(Self {
+            entries,
+            tempo_changes,
+            time_signatures,
+            measures,
+            parts,
+        })
+    }
+
+    fn into_song_data(self) -> SongData {
+        let voice_index_mapping = self
+            .entries
+            .iter()
+            .map(|e| e.voice_key)
+            .unique()
+            .sorted()
+            .collect::<VoiceIndexMapping>();
+
+        // Each distinct timestamp is a cursor position, same as OSMD's cursor.
+        let mut entries_by_timestamp: BTreeMap<Fraction, Vec<ScoreEntry>> = BTreeMap::new();
+        for entry in self.entries {
+            entries_by_timestamp
+                .entry(entry.timestamp)
+                .or_default()
+                .push(entry);
+        }
+
+        let mut slice_builder = SliceBuilder::new(voice_index_mapping.len());
+        for (cursor_index, (timestamp, entries)) in entries_by_timestamp.into_iter().enumerate() {
+            slice_builder.advance_to(timestamp);
+            for entry in entries {
+                let Some(sounding) = entry.sounding else {
+                    continue;
+                };
+                let voice = voice_index_mapping
+                    .index_for_key(entry.voice_key)
+                    .expect("Voice keys were built from these entries");
+                slice_builder.add_note(voice, sounding.pitch, sounding.duration);
+            }
+            slice_builder.finish_position(cursor_index);
+        }
+
+        let (slices, end_timestamp) = slice_builder.build();
+        SongData {
+            voice_names: voice_index_mapping.voice_names(&self.parts),
+            voice_index_mapping,
+            slices,
+            end_timestamp,
+            tempo_changes: self.tempo_changes,
+            time_signatures: self.time_signatures,
+            measures: self.measures,
+        }
+    }
+}
+
+/// Reads a single `<part>`, appending its entries and extending the measures as needed. Returns
+/// the number of staves the part used.
+fn parse_part(
+    part: Node,
+    staff_offset: u32,
+    measure_entries: &mut Vec<MeasureEntry>,
+    markings: &mut Vec<MeasureMarking>,
+    measures: &mut Vec<Measure>,
+) -> Result<u32, MusicXmlError> {
+    let mut num_staves = 1;
+    let mut divisions = 1u64;
+    // Index into `measure_entries` of tied notes that haven't been closed yet, keyed by
+    // (voice key, pitch).
+    let mut open_ties: HashMap<((u32, u32), u32), usize> = HashMap::new();
+    // Endings can span several measures, this is the one we're currently in (if any).
+    let mut ending_numbers = Vec::new();
+
+    for (measure_index, measure) in part
+        .children()
+        .filter(|n| n.has_tag_name("measure"))
+        .enumerate()
+    {
+        let mut position = Fraction::from(0);
+        let mut last_onset = Fraction::from(0);
+        let mut measure_length = Fraction::from(0);
+        let mut flow = MeasureFlow::default();
+
+        for element in measure.children().filter(|n| n.is_element()) {
+            match element.tag_name().name() {
+                "attributes" => {
+                    if let Some(d) = child_text(element, "divisions") {
+                        divisions = parse_number(d, "divisions")?;
+                        if divisions == 0 {
+                            return Err(MusicXmlError::Malformed(format!(
+                                "Invalid divisions: {d:?}"
+                            )));
+                        }
+                    }
+                    if let Some(s) = child_text(element, "staves") {
+                        num_staves = num_staves.max(parse_number(s, "staves")?);
+                    }
+                    if let Some(time) = element.children().find(|n| n.has_tag_name("time")) {
+                        // Compound signatures (eg "3+2") aren't worth the trouble here.
+                        let beats = child_text(time, "beats").and_then(|b| b.parse().ok());
+                        let beat_type = child_text(time, "beat-type").and_then(|b| b.parse().ok());
+                        if let (Some(numerator), Some(denominator)) = (beats, beat_type) {
+                            markings.push(MeasureMarking::TimeSignature {
+                                measure_index,
+                                numerator,
+                                denominator,
+                            });
+                        }
+                    }
+                }
+                "barline" => {
+                    if let Some(repeat) = element.children().find(|n| n.has_tag_name("repeat")) {
+                        match repeat.attribute("direction") {
+                            Some("forward") => flow.repeat_start = true,
+                            Some("backward") => {
+                                flow.repeat_end = Some(
+                                    repeat
+                                        .attribute("times")
+                                        .and_then(|t| t.parse().ok())
+                                        .unwrap_or(2),
+                                );
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let Some(ending) = element.children().find(|n| n.has_tag_name("ending")) {
+                        match ending.attribute("type") {
+                            Some("start") => {
+                                ending_numbers = ending
+                                    .attribute("number")
+                                    .unwrap_or_default()
+                                    .split(|c: char| c == ',' || c.is_whitespace())
+                                    .filter_map(|n| n.parse().ok())
+                                    .collect_vec();
+                            }
+                            Some("stop") | Some("discontinue") => flow.ending_stops = true,
+                            _ => {}
+                        }
+                    }
+                }
+                "direction" | "sound" => {
+                    flow.read_navigation(element);
+                    // Tempo lives on `<sound tempo="..">`, either directly in the measure or
+                    // inside a `<direction>` (`descendants` includes the node itself).
+                    let tempo = element
+                        .descendants()
+                        .filter(|n| n.has_tag_name("sound"))
+                        .find_map(|n| n.attribute("tempo"))
+                        .and_then(|t| t.parse::<f64>().ok())
+                        .filter(|bpm| *bpm > 0.0);
+                    if let Some(bpm) = tempo {
+                        markings.push(MeasureMarking::Tempo {
+                            measure_index,
+                            offset: position,
+                            bpm,
+                        });
+                    }
+                }
+                "backup" => {
+                    position -= duration_of(element, divisions)?;
+                }
+                "forward" => {
+                    position += duration_of(element, divisions)?;
+                }
+                "note" => {
+                    // Grace and cue notes don't take up any time in the performance.
+                    if has_child(element, "grace") || has_child(element, "cue") {
+                        continue;
+                    }
+                    let duration = duration_of(element, divisions)?;
+                    let onset = if has_child(element, "chord") {
+                        last_onset
+                    } else {
+                        let onset = position;
+                        position += duration;
+                        onset
+                    };
+                    last_onset = onset;
+
+                    let staff = child_text(element, "staff")
+                        .map(|s| parse_number::<u32>(s, "staff"))
+                        .transpose()?
+                        .unwrap_or(1);
+                    let voice = child_text(element, "voice")
+                        .map(|v| parse_number::<u32>(v, "voice"))
+                        .transpose()?
+                        .unwrap_or(1);
+                    // Staves are numbered from 1.
+                    let Some(staff_index) = staff.checked_sub(1) else {
+                        return Err(MusicXmlError::Malformed("Invalid staff: \"0\"".to_string()));
+                    };
+                    let voice_key = (staff_offset + staff_index, voice);
+
+                    let sounding = match note_pitch(element)? {
+                        Some(pitch) => {
+                            let tie_types = element
+                                .children()
+                                .filter(|n| n.has_tag_name("tie"))
+                                .filter_map(|n| n.attribute("type"))
+                                .collect_vec();
+                            let tie_key = (voice_key, pitch);
+                            let continued_tie = tie_types
+                                .contains(&"stop")
+                                .then(|| open_ties.remove(&tie_key))
+                                .flatten();
+                            if let Some(tie_start_index) = continued_tie {
+                                // Lengthen the original note rather than starting a new one.
+                                if let Some(sounding) =
+                                    &mut measure_entries[tie_start_index].entry.sounding
+                                {
+                                    sounding.duration += duration;
+                                }
+                                if tie_types.contains(&"start") {
+                                    open_ties.insert(tie_key, tie_start_index);
+                                }
+                                None
+                            } else {
+                                if tie_types.contains(&"start") {
+                                    open_ties.insert(tie_key, measure_entries.len());
+                                }
+                                Some(SoundingNote { pitch, duration })
+                            }
+                        }
+                        None => None,
+                    };
+
+                    measure_entries.push(MeasureEntry {
+                        measure_index,
+                        offset: onset,
+                        entry: ScoreEntry {
+                            // Filled in once we know where each measure starts.
+                            timestamp: Fraction::from(0),
+                            voice_key,
+                            sounding,
+                        },
+                    });
+                }
+                _ => {}
+            }
+            measure_length = measure_length.max(position);
+        }
+
+        if measures.len() <= measure_index {
+            measures.push(Measure::default());
+        }
+        let measure = &mut measures[measure_index];
+        // Parts should agree on measure lengths, but take the longest just in case.
+        measure.duration = measure.duration.max(measure_length);
+        // Navigation markings are often only written on the top part, so take them from anywhere.
+        measure.repeat_start |= flow.repeat_start;
+        measure.repeat_end = measure.repeat_end.or(flow.repeat_end);
+        if measure.ending_numbers.is_empty() {
+            measure.ending_numbers = ending_numbers.clone();
+        }
+        measure.segno |= flow.segno;
+        measure.to_coda |= flow.to_coda;
+        // A coda symbol on its own marks the coda, but it's also used alongside "To Coda".
+        measure.coda |= flow.coda && !flow.to_coda;
+        measure.fine |= flow.fine;
+        measure.jump = measure.jump.or(flow.jump);
+        if flow.ending_stops {
+            ending_numbers.clear();
+        }
+    }
+
+    Ok(num_staves)
+}
+
+/// Repeat and navigation markings found in a single measure of a single part.
+#[derive(Default)]
+struct MeasureFlow {
+    repeat_start: bool,
+    repeat_end: Option<u32>,
+    ending_stops: bool,
+    segno: bool,
+    coda: bool,
+    to_coda: bool,
+    fine: bool,
+    jump: Option<Jump>,
+}
+
+impl MeasureFlow {
+    /// Picks up navigation from a `<direction>` or `<sound>`. The `<sound>` attributes are what's
+    /// meant for playback, but plenty of files only have the symbols or words, so read those too.
+    fn read_navigation(&mut self, element: Node) {
+        for node in element.descendants() {
+            match node.tag_name().name() {
+                "sound" => {
+                    self.segno |= node.has_attribute("segno");
+                    self.coda |= node.has_attribute("coda");
+                    self.to_coda |= node.has_attribute("tocoda");
+                    self.fine |= node.has_attribute("fine");
+                    if node.has_attribute("dalsegno") {
+                        self.jump = Some(Jump::DalSegno);
+                    } else if node.attribute("dacapo") == Some("yes") {
+                        self.jump = Some(Jump::DaCapo);
+                    }
+                }
+                "segno" => self.segno = true,
+                "coda" => self.coda = true,
+                "words" => {
+                    let words = node.text().unwrap_or_default().trim().to_lowercase();
+                    if words.starts_with("d.s.") || words.starts_with("dal segno") {
+                        self.jump = Some(Jump::DalSegno);
+                    } else if words.starts_with("d.c.") || words.starts_with("da capo") {
+                        self.jump = Some(Jump::DaCapo);
+                    } else if words.starts_with("to coda") {
+                        self.to_coda = true;
+                    } else if words == "fine" {
+                        self.fine = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Looks up a part's name in the `<part-list>`, along with its abbreviation and instrument names.
+fn part_names(root: Node, part_id: Option<&str>) -> (String, Vec<String>) {
+    let Some(score_part) = part_id.and_then(|part_id| {
+        root.children()
+            .find(|n| n.has_tag_name("part-list"))?
+            .children()
+            .find(|n| n.has_tag_name("score-part") && n.attribute("id") == Some(part_id))
+    }) else {
+        return (String::new(), Vec::new());
+    };
+    let name = child_text(score_part, "part-name").unwrap_or_default();
+    let alternate_names = child_text(score_part, "part-abbreviation")
+        .into_iter()
+        .chain(
+            score_part
+                .children()
+                .filter(|n| n.has_tag_name("score-instrument"))
+                .filter_map(|n| child_text(n, "instrument-name")),
+        )
+        .map(|name| name.to_string())
+        .collect_vec();
+    (name.to_string(), alternate_names)
+}
+
+/// Gets the MIDI pitch of a `<note>`, or `None` if it's a rest (or otherwise unpitched).
+fn note_pitch(note: Node) -> Result<Option<u32>, MusicXmlError> {
+    let Some(pitch) = note.children().find(|n| n.has_tag_name("pitch")) else {
+        return Ok(None);
+    };
+    let step = child_text(pitch, "step")
+        .ok_or_else(|| MusicXmlError::Malformed("Pitch is missing a step".to_string()))?;
+    let step_index = match step {
+        "C" => 0,
+        "D" => 2,
+        "E" => 4,
+        "F" => 5,
+        "G" => 7,
+        "A" => 9,
+        "B" => 11,
+        _ => return Err(MusicXmlError::Malformed(format!("Unknown step {step}"))),
+    };
+    // Alters can technically be fractional (microtones), we just round those off.
+    let alter = child_text(pitch, "alter")
+        .map(|a| parse_number::<f32>(a, "alter"))
+        .transpose()?
+        .unwrap_or(0.0)
+        .round() as i32;
+    let octave = child_text(pitch, "octave")
+        .map(|o| parse_number::<i32>(o, "octave"))
+        .transpose()?
+        .ok_or_else(|| MusicXmlError::Malformed("Pitch is missing an octave".to_string()))?;
+
+    let midi_note = (octave + 1) * 12 + step_index + alter;
+    Ok(Some(midi_note.max(0) as u32))
+}
+
+/// Reads a `<duration>` child, converted from divisions into whole notes.
+fn duration_of(element: Node, divisions: u64) -> Result<Fraction, MusicXmlError> {
+    let duration = child_text(element, "duration")
+        .map(|d| parse_number::<u64>(d, "duration"))
+        .transpose()?
+        .unwrap_or(0);
+    Ok(Fraction::new(duration, divisions * 4))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, tag_name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|n| n.has_tag_name(tag_name))
+        .and_then(|n| n.text())
+        .map(|t| t.trim())
+}
+
+fn has_child(node: Node, tag_name: &str) -> bool {
+    node.children().any(|n| n.has_tag_name(tag_name))
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str, what: &str) -> Result<T, MusicXmlError> {
+    text.parse()
+        .map_err(|_| MusicXmlError::Malformed(format!("Invalid {what}: {text:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::song_data::SliceNote;
+
+    use super::*;
+
+    fn example_paths(extension: &str) -> Vec<PathBuf> {
+        let examples = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples");
+        std::fs::read_dir(examples)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().and_then(|e| e.to_str()) == Some(extension))
+            .sorted()
+            .collect_vec()
+    }
+
+    fn parse_example(name: &str) -> SongData {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("examples")
+            .join(format!("{name}.mxl"));
+        SongData::from_musicxml(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    fn note(pitch: u32, onset: (u64, u64), end: (u64, u64)) -> SliceNote {
+        SliceNote {
+            pitch,
+            onset: Fraction::new(onset.0, onset.1),
+            end_timestamp: Fraction::new(end.0, end.1),
+        }
+    }
+
+    #[test]
+    fn every_example_parses_into_a_playable_song() {
+        let paths = example_paths("mxl");
+        assert!(!paths.is_empty());
+        for path in paths {
+            let song_data = SongData::from_musicxml(&std::fs::read(&path).unwrap())
+                .unwrap_or_else(|e| panic!("{path:?}: {e}"));
+            let num_voices = song_data.voice_index_mapping.len();
+            assert!(num_voices >= 4, "{path:?}");
+            assert_eq!(song_data.voice_names.len(), num_voices, "{path:?}");
+            assert!(
+                song_data.voice_names.iter().all(|name| !name.is_empty()),
+                "{path:?}"
+            );
+
+            assert_eq!(song_data.slices[0].timestamp, Fraction::from(0), "{path:?}");
+            assert!(
+                song_data
+                    .slices
+                    .windows(2)
+                    .all(|pair| pair[0].timestamp < pair[1].timestamp),
+                "{path:?}"
+            );
+            let mut last_end = Fraction::from(0);
+            for slice in song_data.slices.iter() {
+                assert_eq!(slice.notes_by_voice.len(), num_voices, "{path:?}");
+                let notes = slice.notes_by_voice.iter().flatten().collect_vec();
+                // Slices only start where something is struck.
+                assert!(notes.iter().any(|n| slice.is_newly_struck(n)), "{path:?}");
+                for note in notes {
+                    // Everything's sung, so nothing should be wildly out of range.
+                    assert!((36..=84).contains(&note.pitch), "{path:?}: {note:?}");
+                    assert!(note.onset <= slice.timestamp, "{path:?}: {note:?}");
+                    assert!(note.end_timestamp > slice.timestamp, "{path:?}: {note:?}");
+                    last_end = last_end.max(note.end_timestamp);
+                }
+            }
+            assert_eq!(song_data.end_timestamp, last_end, "{path:?}");
+            let written_length = song_data
+                .measures
+                .iter()
+                .fold(Fraction::from(0), |length, measure| {
+                    length + measure.duration
+                });
+            assert!(song_data.end_timestamp <= written_length, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn example_songs_match_the_score() {
+        // (name, voices, slices, end_timestamp)
+        let expected = [
+            ("A Million Stars", 4, 18, Fraction::from(7)),
+            ("Cheer Up, Charlie", 4, 14, Fraction::from(6)),
+            ("Ebb Tide", 5, 19, Fraction::new(25u64, 4u64)),
+            // Ends on a rest, so the notes finish before the last measure does.
+            ("Lone Prairie", 4, 22, Fraction::from(8)),
+            ("Sing an Old Time Song Again", 5, 8, Fraction::from(3)),
+        ];
+        for (name, voices, slices, end_timestamp) in expected {
+            let song_data = parse_example(name);
+            assert_eq!(song_data.voice_index_mapping.len(), voices, "{name}");
+            assert_eq!(song_data.slices.len(), slices, "{name}");
+            assert_eq!(song_data.end_timestamp, end_timestamp, "{name}");
+        }
+
+        assert_eq!(
+            parse_example("Ebb Tide").voice_names,
+            vec!["Tenor", "Lead", "Bari", "Bass", "OPT 5th"]
+        );
+        assert_eq!(
+            parse_example("Sing an Old Time Song Again").voice_names,
+            vec![
+                "Tenor/Lead 1",
+                "Tenor/Lead 2",
+                "Tenor/Lead 3",
+                "Bari",
+                "Bass"
+            ]
+        );
+    }
+
+    #[test]
+    fn example_notes_keep_their_onsets_and_ties() {
+        let song_data = parse_example("A Million Stars");
+        assert_eq!(song_data.voice_names, vec!["Tenor", "Lead", "Bari", "Bass"]);
+        // The lead's pickup notes, then the first chord.
+        let slices = &song_data.slices;
+        assert_eq!(slices[0].timestamp, Fraction::from(0));
+        assert_eq!(
+            slices[0].notes_by_voice,
+            vec![vec![], vec![note(54, (0, 1), (1, 4))], vec![], vec![]]
+        );
+        assert_eq!(slices[1].timestamp, Fraction::new(1u64, 4u64));
+        assert_eq!(
+            slices[2].notes_by_voice,
+            vec![
+                vec![note(66, (1, 2), (1, 1))],
+                vec![note(61, (1, 2), (1, 1))],
+                vec![note(58, (1, 2), (1, 1))],
+                vec![note(42, (1, 2), (1, 1))],
+            ]
+        );
+
+        // The lead's tied note carries on through the following slices without being restruck.
+        let song_data = parse_example("Cheer Up, Charlie");
+        let tied = note(57, (2, 1), (6, 1));
+        let slice = &song_data.slices[4];
+        assert_eq!(slice.timestamp, Fraction::from(2));
+        assert_eq!(slice.notes_by_voice[1], vec![tied.clone()]);
+        assert!(slice.is_newly_struck(&tied));
+        let held = song_data
+            .slices
+            .iter()
+            .filter(|slice| slice.notes_by_voice[1].contains(&tied))
+            .collect_vec();
+        assert!(held.len() > 1);
+        assert!(held[1..].iter().all(|slice| !slice.is_newly_struck(&tied)));
+    }
+
+    #[test]
+    fn rejects_zero_divisions_and_staves() {
+        let score = |attributes: &str, staff: &str| {
+            format!(
+                r#"<score-partwise>
+                    <part-list><score-part id="P1"><part-name>Lead</part-name></score-part></part-list>
+                    <part id="P1"><measure number="1">
+                        <attributes>{attributes}</attributes>
+                        <note>
+                            <pitch><step>C</step><octave>4</octave></pitch>
+                            <duration>1</duration><voice>1</voice><staff>{staff}</staff>
+                        </note>
+                    </measure></part>
+                </score-partwise>"#
+            )
+        };
+        assert!(SongData::from_musicxml(score("<divisions>1</divisions>", "1").as_bytes()).is_ok());
+        assert!(matches!(
+            SongData::from_musicxml(score("<divisions>0</divisions>", "1").as_bytes()),
+            Err(MusicXmlError::Malformed(_))
+        ));
+        assert!(matches!(
+            SongData::from_musicxml(score("<divisions>1</divisions>", "0").as_bytes()),
+            Err(MusicXmlError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn metadata_comes_from_identification_then_credits() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <score-partwise version="3.1">
+                <work><work-title>Smile</work-title></work>
+                <movement-title>Ignored</movement-title>
+                <identification>
+                    <creator type="composer">Charlie Chaplin</creator>
+                </identification>
+                <credit page="1">
+                    <credit-type>arranger</credit-type>
+                    <credit-words>Arr. Someone</credit-words>
+                </credit>
+            </score-partwise>"#;
+        assert_eq!(
+            score_metadata(xml.as_bytes()).unwrap(),
+            ScoreMetadata {
+                title: Some("Smile".to_string()),
+                composer: Some("Charlie Chaplin".to_string()),
+                arranger: Some("Arr. Someone".to_string()),
+            }
+        );
+
+        let xml = r#"<score-partwise>
+                <movement-title> Dinah </movement-title>
+                <identification><creator type="composer"></creator></identification>
+            </score-partwise>"#;
+        assert_eq!(
+            score_metadata(xml.as_bytes()).unwrap(),
+            ScoreMetadata {
+                title: Some("Dinah".to_string()),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Keeps what the app needs on the device so it still works without a network connection. Our own
+//! files (the app shell and examples) are served by the service worker in `sw.js`, while samples
+//! from other sites are cached here since the service worker only handles our own origin.
+
+use std::fmt::{Display, Formatter};
+
+use gloo::net::http::Request;
+use js_sys::{Array, Uint8Array};
+use log::warn;
+use wasm_bindgen::JsCast;
+use web_sys::{Cache, Response};
+
+use crate::future_util::PromiseAsFuture;
+
+/// Bump this when the samples behind the same URLs change, so stale copies get thrown away.
+const SAMPLE_CACHE_VERSION: u32 = 1;
+const SAMPLE_CACHE_PREFIX: &str = "magic-piano-samples-v";
+/// Must match `SHELL_CACHE` in `sw.js`, which serves requests from it.
+const SHELL_CACHE: &str = "magic-piano-shell-v1";
+
+fn sample_cache_name() -> String {
+    format!("{SAMPLE_CACHE_PREFIX}{SAMPLE_CACHE_VERSION}")
+}
+
+/// `None` if Cache Storage isn't available, eg the page wasn't served over HTTPS.
+async fn open_cache(name: &str) -> Option<Cache> {
+    let caches = web_sys::window()?.caches().ok()?;
+    caches
+        .open(name)
+        .into_future()
+        .await
+        .ok()?
+        .dyn_into::<Cache>()
+        .ok()
+}
+
+/// Starts the service worker that lets the app load offline, and clears out old sample caches.
+pub async fn initialize() {
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    let has_service_worker =
+        js_sys::Reflect::has(&window.navigator(), &"serviceWorker".into()).unwrap_or(false);
+    if has_service_worker {
+        if let Err(e) = window
+            .navigator()
+            .service_worker()
+            .register("sw.js")
+            .into_future()
+            .await
+        {
+            warn!("Unable to register service worker: {e:?}");
+        }
+    }
+
+    let Ok(caches) = window.caches() else {
+        return;
+    };
+    let Ok(names) = caches.keys().into_future().await else {
+        return;
+    };
+    let current = sample_cache_name();
+    for name in Array::from(&names)
+        .iter()
+        .filter_map(|name| name.as_string())
+    {
+        if name.starts_with(SAMPLE_CACHE_PREFIX) && name != current {
+            let _ = caches.delete(&name).into_future().await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// We didn't get a response at all, eg we're offline.
+    Network(String),
+    /// The server responded, but not with the file.
+    Status(u16),
+}
+
+impl FetchError {
+    /// Whether trying again later might work.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            FetchError::Network(_) => true,
+            FetchError::Status(status) => matches!(status, 408 | 429 | 500..),
+        }
+    }
+}
+
+impl Display for FetchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Network(message) => write!(f, "network error: {message}"),
+            FetchError::Status(status) => write!(f, "server responded with HTTP {status}"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Reads the given URL out of the cache, treating any problem with the cache as a miss.
+async fn cached_bytes(cache: &Cache, url: &str) -> Option<Vec<u8>> {
+    let cached = cache.match_with_str(url).into_future().await.ok()?;
+    let response = cached.dyn_into::<Response>().ok()?;
+    let array_buffer = response.array_buffer().ok()?.into_future().await.ok()?;
+    Some(Uint8Array::new(&array_buffer).to_vec())
+}
+
+/// Fetches a sample, from the cache if we've fetched it before.
+pub async fn fetch_sample(url: &str) -> Result<Vec<u8>, FetchError> {
+    let cache = open_cache(&sample_cache_name()).await;
+    if let Some(cache) = &cache {
+        if let Some(data) = cached_bytes(cache, url).await {
+            return Ok(data);
+        }
+    }
+
+    let response = Request::get(url)
+        .send()
+        .await
+        .map_err(|e| FetchError::Network(e.to_string()))?;
+    if !response.ok() {
+        return Err(FetchError::Status(response.status()));
+    }
+    let mut data = response
+        .binary()
+        .await
+        .map_err(|e| FetchError::Network(e.to_string()))?;
+
+    if let Some(cache) = &cache {
+        let stored = Response::new_with_opt_u8_array(Some(&mut data));
+        let put = match &stored {
+            Ok(stored) => cache.put_with_str(url, stored).into_future().await,
+            Err(e) => Err(e.clone()),
+        };
+        if let Err(e) = put {
+            // Probably out of space, we can still play it this time.
+            warn!("Unable to cache sample {url}: {e:?}");
+        }
+    }
+    Ok(data)
+}
+
+/// Makes sure the given files from our own site are available offline, even if they haven't been
+/// opened yet.
+pub async fn precache(urls: &[String]) {
+    let Some(cache) = open_cache(SHELL_CACHE).await else {
+        return;
+    };
+    for url in urls {
+        let already_cached = cache
+            .match_with_str(url)
+            .into_future()
+            .await
+            .is_ok_and(|cached| !cached.is_undefined());
+        if already_cached {
+            continue;
+        }
+        if let Err(e) = cache.add_with_str(url).into_future().await {
+            warn!("Unable to cache {url} for offline use: {e:?}");
+        }
+    }
+}
@@ -31,6 +31,20 @@ extern "C" {
     #[wasm_bindgen(method)]
     pub fn render(this: &OpenSheetMusicDisplay);
 
+    /// Recalculates the graphical sheet from the music sheet, needed after eg transposing.
+    #[wasm_bindgen(method, js_name = "updateGraphic")]
+    pub fn update_graphic(this: &OpenSheetMusicDisplay);
+
+    /// Needed for `MusicSheet::set_transpose` to do anything.
+    #[wasm_bindgen(method, setter, js_name = "TransposeCalculator")]
+    pub fn set_transpose_calculator(
+        this: &OpenSheetMusicDisplay,
+        transpose_calculator: &TransposeCalculator,
+    );
+
+    #[wasm_bindgen(method)]
+    pub fn clear(this: &OpenSheetMusicDisplay);
+
     #[wasm_bindgen(method, getter)]
     pub fn cursor(this: &OpenSheetMusicDisplay) -> Option<Cursor>;
 
@@ -55,11 +69,38 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     pub fn rules(this: &OpenSheetMusicDisplay) -> EngravingRules;
 
+    pub type TransposeCalculator;
+
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> TransposeCalculator;
+
     pub type MusicSheet;
 
+    /// How many semitones to transpose the rendered notes and key signatures by.
+    #[wasm_bindgen(method, setter, js_name = "Transpose")]
+    pub fn set_transpose(this: &MusicSheet, semitones: i32);
+
     #[wasm_bindgen(method, getter)]
     pub fn staves(this: &MusicSheet) -> Vec<Staff>;
 
+    #[wasm_bindgen(method, getter, js_name = "SourceMeasures")]
+    pub fn source_measures(this: &MusicSheet) -> Vec<SourceMeasure>;
+
+    #[wasm_bindgen(method, getter, js_name = "Instruments")]
+    pub fn instruments(this: &MusicSheet) -> Vec<Instrument>;
+
+    /// What MusicXML calls a part.
+    pub type Instrument;
+
+    #[wasm_bindgen(method, getter, js_name = "Name")]
+    pub fn name(this: &Instrument) -> String;
+
+    #[wasm_bindgen(method, getter, js_name = "PartAbbreviation")]
+    pub fn part_abbreviation(this: &Instrument) -> Option<String>;
+
+    #[wasm_bindgen(method, getter, js_name = "Staves")]
+    pub fn staves(this: &Instrument) -> Vec<Staff>;
+
     pub type SourceStaffEntry;
 
     #[wasm_bindgen(method, getter, js_name = "parentStaff")]
@@ -78,6 +119,30 @@ extern "C" {
     #[wasm_bindgen(method, getter, js_name = "absoluteTimestamp")]
     pub fn absolute_timestamp(this: &SourceMeasure) -> Fraction;
 
+    #[wasm_bindgen(method, getter, js_name = "TempoInBPM")]
+    pub fn tempo_in_bpm(this: &SourceMeasure) -> f64;
+
+    #[wasm_bindgen(method, getter, js_name = "ActiveTimeSignature")]
+    pub fn active_time_signature(this: &SourceMeasure) -> Fraction;
+
+    #[wasm_bindgen(method, getter, js_name = "Duration")]
+    pub fn duration(this: &SourceMeasure) -> Fraction;
+
+    #[wasm_bindgen(method, getter, js_name = "FirstRepetitionInstructions")]
+    pub fn first_repetition_instructions(this: &SourceMeasure) -> Vec<RepetitionInstruction>;
+
+    #[wasm_bindgen(method, getter, js_name = "LastRepetitionInstructions")]
+    pub fn last_repetition_instructions(this: &SourceMeasure) -> Vec<RepetitionInstruction>;
+
+    pub type RepetitionInstruction;
+
+    #[wasm_bindgen(method, getter, js_name = "type")]
+    pub fn instruction_type(this: &RepetitionInstruction) -> RepetitionInstructionEnum;
+
+    /// Only set for endings. These are numbers, but may be undefined.
+    #[wasm_bindgen(method, getter, js_name = "endingIndices")]
+    pub fn ending_indices(this: &RepetitionInstruction) -> Option<Vec<JsValue>>;
+
     pub type GraphicalMusicSheet;
 
     #[wasm_bindgen(method, getter, js_name = "verticalGraphicalStaffEntryContainers")]
@@ -88,6 +153,22 @@ extern "C" {
     #[wasm_bindgen(method, getter, js_name = "musicPages")]
     pub fn music_pages(this: &GraphicalMusicSheet) -> Vec<GraphicalMusicPage>;
 
+    /// Converts a point in page (client) coordinates to the rendered SVG's coordinates.
+    #[wasm_bindgen(method, js_name = "domToSvg")]
+    pub fn dom_to_svg(this: &GraphicalMusicSheet, point: &PointF2D) -> PointF2D;
+
+    /// Converts a point in the rendered SVG's coordinates to OSMD's own units, which is what
+    /// positions are given in.
+    #[wasm_bindgen(method, js_name = "svgToOsmd")]
+    pub fn svg_to_osmd(this: &GraphicalMusicSheet, point: &PointF2D) -> PointF2D;
+
+    /// Only looks a short way around `point`, so is `None` for clicks away from any notes.
+    #[wasm_bindgen(method, js_name = "GetNearestStaffEntry")]
+    pub fn get_nearest_staff_entry(
+        this: &GraphicalMusicSheet,
+        point: &PointF2D,
+    ) -> Option<GraphicalStaffEntry>;
+
     pub type VerticalGraphicalStaffEntryContainer;
 
     // NOTE! This actually returns a Vec<Option<GraphicalStaffEntry>> but we can't nest the
@@ -326,6 +407,26 @@ pub enum StemDirectionType {
     Double = 3,
 }
 
+#[wasm_bindgen]
+#[derive(Debug)]
+pub enum RepetitionInstructionEnum {
+    StartLine = 0,
+    ForwardJump = 1,
+    BackJumpLine = 2,
+    Ending = 3,
+    DaCapo = 4,
+    DalSegno = 5,
+    Fine = 6,
+    ToCoda = 7,
+    DalSegnoAlFine = 8,
+    DaCapoAlFine = 9,
+    DalSegnoAlCoda = 10,
+    DaCapoAlCoda = 11,
+    Coda = 12,
+    Segno = 13,
+    None = 14,
+}
+
 impl Fraction {
     pub fn to_rust_fraction(&self) -> Option<fraction::Fraction> {
         let js_value: &JsValue = self;
@@ -339,6 +440,17 @@ impl Fraction {
     }
 }
 
+impl PointF2D {
+    /// A point to pass into OSMD. This is a plain object rather than an actual `PointF2D`, which
+    /// works since OSMD only ever reads `x` and `y`.
+    pub fn from_xy(x: f32, y: f32) -> Self {
+        let point = js_sys::Object::new();
+        js_sys::Reflect::set(&point, &"x".into(), &x.into()).unwrap();
+        js_sys::Reflect::set(&point, &"y".into(), &y.into()).unwrap();
+        point.unchecked_into()
+    }
+}
+
 #[derive(Serialize, Default)]
 pub struct IOSMDOptions {
     #[serde(rename = "pageFormat")]
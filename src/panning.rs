@@ -0,0 +1,119 @@
+//! Where each voice sits in the stereo field. Hearing your own part in a different place from the
+//! rest makes it much easier to pick out of the chord.
+
+/// How far out the outermost voices go when spreading them, so nobody ends up only in one ear.
+const SPREAD_WIDTH: f32 = 0.8;
+/// How close to the center the other voices get when one voice has the center to itself.
+const FOCUS_GAP: f32 = 0.3;
+
+/// A way of placing all the voices at once.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PanPreset {
+    Centered,
+    /// Evenly from left to right, in voice order.
+    Spread,
+    /// The chosen voice in the middle, with the rest spread either side of it.
+    FocusCentered,
+    /// The chosen voice only in the left ear, the rest only in the right.
+    FocusLeft,
+}
+
+/// The placements offered in the UI, by name.
+pub const PAN_PRESETS: &[(&str, PanPreset)] = &[
+    ("Everyone centered", PanPreset::Centered),
+    ("Spread out", PanPreset::Spread),
+    ("My part centered, others spread", PanPreset::FocusCentered),
+    ("My part left ear, rest right ear", PanPreset::FocusLeft),
+];
+
+impl PanPreset {
+    /// Whether the preset treats one voice differently from the rest.
+    pub fn uses_focus_voice(self) -> bool {
+        matches!(self, PanPreset::FocusCentered | PanPreset::FocusLeft)
+    }
+
+    /// The pan for each of `num_voices` voices, from -1 (left) to 1 (right). `focus_voice` is the
+    /// singer's own part, for the presets that use one.
+    pub fn positions(self, num_voices: usize, focus_voice: usize) -> Vec<f32> {
+        match self {
+            PanPreset::Centered => vec![0.0; num_voices],
+            PanPreset::Spread => evenly_spaced(num_voices, -SPREAD_WIDTH, SPREAD_WIDTH),
+            PanPreset::FocusCentered => {
+                let others = num_voices.saturating_sub(1);
+                // The left side gets the extra voice if there's an odd number, in voice order.
+                let num_left = others.div_ceil(2);
+                let mut others = evenly_spaced(num_left, -SPREAD_WIDTH, -FOCUS_GAP)
+                    .into_iter()
+                    .chain(evenly_spaced(others - num_left, FOCUS_GAP, SPREAD_WIDTH));
+                (0..num_voices)
+                    .map(|voice| {
+                        if voice == focus_voice {
+                            0.0
+                        } else {
+                            others.next().unwrap_or(0.0)
+                        }
+                    })
+                    .collect()
+            }
+            PanPreset::FocusLeft => (0..num_voices)
+                .map(|voice| if voice == focus_voice { -1.0 } else { 1.0 })
+                .collect(),
+        }
+    }
+}
+
+/// `count` positions from `from` to `to` inclusive, or halfway between them if there's only one.
+fn evenly_spaced(count: usize, from: f32, to: f32) -> Vec<f32> {
+    match count {
+        0 => Vec::new(),
+        1 => vec![(from + to) / 2.0],
+        _ => (0..count)
+            .map(|i| from + (to - from) * i as f32 / (count - 1) as f32)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spread_goes_left_to_right_in_voice_order() {
+        assert_eq!(PanPreset::Spread.positions(1, 0), vec![0.0]);
+        assert_eq!(
+            PanPreset::Spread.positions(3, 0),
+            vec![-SPREAD_WIDTH, 0.0, SPREAD_WIDTH]
+        );
+        let positions = PanPreset::Spread.positions(4, 0);
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn focus_voice_gets_the_center_to_itself() {
+        for num_voices in 1..=8 {
+            for focus_voice in 0..num_voices {
+                let positions = PanPreset::FocusCentered.positions(num_voices, focus_voice);
+                assert_eq!(positions.len(), num_voices);
+                for (voice, pan) in positions.into_iter().enumerate() {
+                    if voice == focus_voice {
+                        assert_eq!(pan, 0.0);
+                    } else {
+                        assert!(pan.abs() >= FOCUS_GAP, "{num_voices} {focus_voice} {voice}");
+                        assert!(
+                            pan.abs() <= SPREAD_WIDTH,
+                            "{num_voices} {focus_voice} {voice}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn focus_left_splits_the_ears() {
+        assert_eq!(
+            PanPreset::FocusLeft.positions(4, 2),
+            vec![1.0, 1.0, -1.0, 1.0]
+        );
+    }
+}
@@ -1,11 +1,40 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::rc::{Rc, Weak};
 
 use bit_set::BitSet;
+use fraction::Fraction;
+use itertools::Itertools;
+use log::error;
 use wasm_bindgen::JsValue;
-use web_sys::{AudioContext, AudioNode, GainNode};
+use web_sys::{
+    AudioContext, AudioNode, BaseAudioContext, GainNode, MidiOutput, OfflineAudioContext,
+    StereoPannerNode,
+};
 
-use crate::sampler::{Sampler, SamplerPlaybackGuard};
-use crate::song_data::SongData;
+use crate::instrument::{Instrument, InstrumentPreset, PlaybackGuard, FALLBACK_PIANO};
+use crate::midi_output::MidiOutputTarget;
+use crate::sampler::{SampleLoadReport, Sampler};
+use crate::song_data::{SongData, TimeSlice};
+use crate::synth::Synth;
+use crate::temperament::{equal_tempered_frequency, Temperament, STANDARD_A4_HZ};
+use crate::tuning::Tuning;
+
+/// What happens to notes that are still being held from an earlier position when stepping to a new
+/// one.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ArticulationMode {
+    /// Strike every note sounding at the new position, even ones that started earlier.
+    #[default]
+    RestrikeAll,
+    /// Only strike notes that start at the new position. Notes held over from earlier keep ringing
+    /// from their original attack, as long as they're still sounding.
+    NewOnsetsOnly,
+}
+
+/// Identifies a specific note in the song: (voice, pitch, onset).
+type SoundingNoteKey = (usize, u32, Fraction);
 
 /// This is the bridge between the UI and the playback layer. While it still uses some UI concepts,
 /// eg leptos' `Signal`s and `Resource`s, the APIs it exposes are all in the playback parlance (eg
@@ -14,78 +43,358 @@ use crate::song_data::SongData;
 /// Will panic if any JS operation fails for some reason.
 #[derive(Debug)]
 pub struct PlaybackManager {
-    ctx: AudioContext,
-    sampler: Sampler,
+    ctx: BaseAudioContext,
+    /// The built-in piano, which falls back to a synth if its samples couldn't be loaded.
+    piano: Rc<dyn Instrument>,
+    sample_load_report: SampleLoadReport,
+    using_fallback_piano: bool,
     overall_gain: GainNode,
     voice_gains: Vec<GainNode>,
+    /// Sits between each voice's gain and `overall_gain`.
+    voice_panners: Vec<StereoPannerNode>,
+    voice_instruments: Vec<Rc<dyn Instrument>>,
+    loaded_instruments: Vec<Rc<dyn Instrument>>,
+    /// When set, notes go out to this MIDI port instead of being played by the voices' instruments.
+    midi_output: Option<MidiOutputTarget>,
     song_data: Option<SongData>,
+    articulation_mode: ArticulationMode,
+    tuning: Tuning,
+    /// The frequency of A4, in Hz.
+    reference_pitch: f64,
+    /// `None` for equal temperament.
+    temperament: Option<Temperament>,
+    /// Shifts every note we play, without changing the song itself.
+    transpose_semitones: i32,
+    /// The notes we've started which something is still holding onto, so they can be handed over
+    /// rather than restruck.
+    sounding_notes: RefCell<HashMap<SoundingNoteKey, Weak<PlaybackGuard>>>,
 }
 
 impl PlaybackManager {
     pub async fn initialize() -> Self {
-        let ctx = AudioContext::new().unwrap();
-        let sampler = Sampler::initialize(ctx.clone(), &NOTES).await;
+        let ctx: BaseAudioContext = AudioContext::new().unwrap().into();
+        let (sampler, sample_load_report) = Sampler::initialize(ctx.clone(), &NOTES).await;
+        let using_fallback_piano = sampler.is_empty();
+        let piano: Rc<dyn Instrument> = if using_fallback_piano {
+            Rc::new(Synth::new(FALLBACK_PIANO))
+        } else {
+            Rc::new(sampler)
+        };
+
+        Self {
+            sample_load_report,
+            using_fallback_piano,
+            ..Self::new(ctx, piano)
+        }
+    }
 
+    /// Plays through `ctx`, using `piano` in place of the piano samples.
+    pub fn new(ctx: BaseAudioContext, piano: Rc<dyn Instrument>) -> Self {
         let overall_gain = Self::create_gain_node(&ctx, &ctx.destination())
             .expect("Unable to create playback gain nodes");
 
         Self {
             ctx,
-            sampler,
+            piano,
+            sample_load_report: SampleLoadReport::default(),
+            using_fallback_piano: false,
             overall_gain,
             voice_gains: Vec::new(),
+            voice_panners: Vec::new(),
+            voice_instruments: Vec::new(),
+            loaded_instruments: Vec::new(),
+            midi_output: None,
             song_data: None,
+            articulation_mode: ArticulationMode::default(),
+            tuning: Tuning::default(),
+            reference_pitch: STANDARD_A4_HZ,
+            temperament: None,
+            transpose_semitones: 0,
+            sounding_notes: RefCell::new(HashMap::new()),
         }
     }
 
-    fn create_gain_node(ctx: &AudioContext, destination: &AudioNode) -> Result<GainNode, JsValue> {
+    /// A gain node for a voice, going through a new panner into `destination`.
+    fn create_voice_nodes(
+        ctx: &BaseAudioContext,
+        destination: &AudioNode,
+    ) -> Result<(GainNode, StereoPannerNode), JsValue> {
+        let panner = ctx.create_stereo_panner()?;
+        panner.connect_with_audio_node(destination)?;
+        let gain = Self::create_gain_node(ctx, &panner)?;
+        Ok((gain, panner))
+    }
+
+    fn create_gain_node(
+        ctx: &BaseAudioContext,
+        destination: &AudioNode,
+    ) -> Result<GainNode, JsValue> {
         let gain = ctx.create_gain()?;
         gain.connect_with_audio_node(destination)?;
         Ok(gain)
     }
 
+    /// A copy of this playback manager which plays into `ctx` instead, with the same song,
+    /// instruments, gains and tuning. Used to render the song rather than play it live.
+    pub fn for_offline_context(&self, ctx: &OfflineAudioContext) -> Result<Self, JsValue> {
+        let ctx: BaseAudioContext = ctx.clone().into();
+        let overall_gain = Self::create_gain_node(&ctx, &ctx.destination())?;
+        overall_gain
+            .gain()
+            .set_value(self.overall_gain.gain().value());
+        let (voice_gains, voice_panners) = self
+            .voice_gains
+            .iter()
+            .zip(&self.voice_panners)
+            .map(|(live_gain, live_panner)| {
+                let (voice_gain, voice_panner) = Self::create_voice_nodes(&ctx, &overall_gain)?;
+                voice_gain.gain().set_value(live_gain.gain().value());
+                voice_panner.pan().set_value(live_panner.pan().value());
+                Ok((voice_gain, voice_panner))
+            })
+            .collect::<Result<Vec<_>, JsValue>>()?
+            .into_iter()
+            .unzip();
+
+        Ok(Self {
+            ctx,
+            piano: self.piano.clone(),
+            sample_load_report: SampleLoadReport::default(),
+            using_fallback_piano: self.using_fallback_piano,
+            overall_gain,
+            voice_gains,
+            voice_panners,
+            voice_instruments: self.voice_instruments.clone(),
+            loaded_instruments: self.loaded_instruments.clone(),
+            // Renders are always of the instruments, there's no recording what a MIDI device plays.
+            midi_output: None,
+            song_data: self.song_data.clone(),
+            articulation_mode: self.articulation_mode,
+            tuning: self.tuning,
+            reference_pitch: self.reference_pitch,
+            temperament: self.temperament.clone(),
+            transpose_semitones: self.transpose_semitones,
+            sounding_notes: RefCell::new(HashMap::new()),
+        })
+    }
+
     pub fn set_song_data(&mut self, song_data: SongData) {
         // Make sure we have enough gain nodes for the voices in the song.
         while self.voice_gains.len() < song_data.voice_index_mapping.len() {
-            let voice_gain = Self::create_gain_node(&self.ctx, &self.overall_gain)
-                .expect("Unable to create gain node for an individual voice");
+            let (voice_gain, voice_panner) =
+                Self::create_voice_nodes(&self.ctx, &self.overall_gain)
+                    .expect("Unable to create nodes for an individual voice");
             self.voice_gains.push(voice_gain);
+            self.voice_panners.push(voice_panner);
+            self.voice_instruments.push(self.piano.clone());
         }
 
         self.song_data = Some(song_data);
     }
 
+    pub fn voice_gain(&self, voice: usize) -> f32 {
+        self.voice_gains
+            .get(voice)
+            .map_or(1.0, |voice_gain| voice_gain.gain().value())
+    }
+
     pub fn set_voice_gain(&self, voice: usize, gain: f32) {
         if let Some(voice_gain) = self.voice_gains.get(voice) {
             voice_gain.gain().set_value(gain);
         }
     }
 
+    /// Places the voice from -1 (left) to 1 (right).
+    pub fn set_voice_pan(&self, voice: usize, pan: f32) {
+        if let Some(voice_panner) = self.voice_panners.get(voice) {
+            voice_panner.pan().set_value(pan.clamp(-1.0, 1.0));
+        }
+    }
+
+    pub fn set_voice_instrument(&mut self, voice: usize, preset: InstrumentPreset) {
+        if let Some(instrument) = self.voice_instruments.get_mut(voice) {
+            *instrument = match preset {
+                InstrumentPreset::Piano => self.piano.clone(),
+                InstrumentPreset::Synth(patch) => Rc::new(Synth::new(patch)),
+                InstrumentPreset::Loaded(index) => match self.loaded_instruments.get(index) {
+                    Some(loaded) => loaded.clone(),
+                    None => return,
+                },
+            };
+        }
+    }
+
+    /// Makes the instrument available as `InstrumentPreset::Loaded`, returning its index.
+    pub fn add_instrument(&mut self, instrument: Rc<dyn Instrument>) -> usize {
+        self.loaded_instruments.push(instrument);
+        self.loaded_instruments.len() - 1
+    }
+
+    /// Sends notes to `port` rather than playing them, or goes back to playing them if `None`.
+    /// Anything still sounding on the previous port is silenced.
+    pub fn set_midi_output(&mut self, port: Option<MidiOutput>) {
+        if let Some(previous) = self.midi_output.take() {
+            if let Err(e) = previous.all_notes_off() {
+                error!("Failed to silence the previous MIDI output: {e:?}");
+            }
+        }
+        self.midi_output = port.map(MidiOutputTarget::new);
+    }
+
+    /// Which of the piano's samples failed to load. If they all did, the piano is played by a
+    /// synth instead.
+    pub fn sample_load_report(&self) -> &SampleLoadReport {
+        &self.sample_load_report
+    }
+
+    pub fn using_fallback_piano(&self) -> bool {
+        self.using_fallback_piano
+    }
+
+    /// The context everything plays through, eg for loading samples into.
+    pub fn audio_context(&self) -> BaseAudioContext {
+        self.ctx.clone()
+    }
+
     pub fn set_overall_gain(&self, gain: f32) {
         self.overall_gain.gain().set_value(gain);
     }
 
+    pub fn set_articulation_mode(&mut self, articulation_mode: ArticulationMode) {
+        self.articulation_mode = articulation_mode;
+    }
+
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = tuning;
+    }
+
+    pub fn set_reference_pitch(&mut self, reference_pitch: f64) {
+        self.reference_pitch = reference_pitch;
+    }
+
+    pub fn set_temperament(&mut self, temperament: Option<Temperament>) {
+        self.temperament = temperament;
+    }
+
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.transpose_semitones = semitones;
+    }
+
+    /// The frequency to play the given MIDI pitch at, before any just intonation adjustments.
+    ///
+    /// Just intonation is worked out relative to equal temperament, so while it's on it replaces
+    /// the temperament rather than stacking on top of it. Keys the temperament leaves unmapped
+    /// fall back to equal temperament too.
+    fn base_frequency(&self, pitch: u32) -> f64 {
+        let tempered = match (&self.tuning, &self.temperament) {
+            (Tuning::Equal, Some(temperament)) => {
+                temperament.frequency(pitch, self.reference_pitch)
+            }
+            _ => None,
+        };
+        tempered.unwrap_or_else(|| equal_tempered_frequency(pitch as f64, self.reference_pitch))
+    }
+
+    /// How far each note in the slice should be detuned from equal temperament, in cents, laid
+    /// out the same as `slice.notes_by_voice`. All voices count towards the chord, even muted
+    /// ones, so muting a part doesn't change how the others are tuned.
+    pub fn cent_offsets_for_slice(&self, slice: &TimeSlice) -> Vec<Vec<f64>> {
+        let pitches = slice
+            .notes_by_voice
+            .iter()
+            .flat_map(|notes| notes.iter().map(|note| note.pitch))
+            .collect_vec();
+        let mut offsets = self.tuning.cent_offsets(&pitches).into_iter();
+        slice
+            .notes_by_voice
+            .iter()
+            .map(|notes| offsets.by_ref().take(notes.len()).collect_vec())
+            .collect_vec()
+    }
+
+    /// Starts the notes at the given position, scaled by `gain` (eg from a MIDI key's velocity).
+    /// Depending on the `ArticulationMode`, some of the returned guards may be shared with earlier
+    /// calls, for notes which are being held over.
     pub fn start_notes_at_relative_index(
         &self,
         song_index: usize,
         active_voices: &BitSet,
-    ) -> Option<(usize, Vec<SamplerPlaybackGuard>)> {
+        gain: f32,
+    ) -> Option<(usize, Vec<Rc<PlaybackGuard>>)> {
         let slice = self.song_data.as_ref()?.slices.get(song_index)?;
-        let mut sampler_playback_guards = Vec::new();
+        let when = self.current_time();
+        let cent_offsets = self.cent_offsets_for_slice(slice);
+        let mut playback_guards = Vec::new();
+        let mut sounding_notes = self.sounding_notes.borrow_mut();
+        sounding_notes.retain(|_, guard| guard.strong_count() > 0);
 
         for (voice, notes) in slice.notes_by_voice.iter().enumerate() {
             if !active_voices.contains(voice) {
                 continue;
             }
-            let voice_gain = &self.voice_gains[voice];
+            for (note, cents) in notes.iter().zip(&cent_offsets[voice]) {
+                let key = (voice, note.pitch, note.onset);
+                let held_over = match self.articulation_mode {
+                    ArticulationMode::NewOnsetsOnly if !slice.is_newly_struck(note) => {
+                        sounding_notes.get(&key).and_then(Weak::upgrade)
+                    }
+                    _ => None,
+                };
+                let guard = held_over.unwrap_or_else(|| {
+                    Rc::new(self.start_note_with_gain(voice, note.pitch, *cents, when, gain))
+                });
+                sounding_notes.insert(key, Rc::downgrade(&guard));
+                playback_guards.push(guard);
+            }
+        }
+
+        Some((slice.cursor_index, playback_guards))
+    }
+
+    /// Starts a single note in the given voice at `when` (in `AudioContext` time), transposed and
+    /// in the current temperament and reference pitch and then detuned by `cents`.
+    pub fn start_note(&self, voice: usize, pitch: u32, cents: f64, when: f64) -> PlaybackGuard {
+        self.start_note_with_gain(voice, pitch, cents, when, 1.0)
+    }
 
-            for key in notes.iter() {
-                sampler_playback_guards
-                    .push(self.sampler.start_note(key as i32, voice_gain).unwrap());
+    /// Like `start_note`, but struck with the given `gain` on top of the voice's gain.
+    fn start_note_with_gain(
+        &self,
+        voice: usize,
+        pitch: u32,
+        cents: f64,
+        when: f64,
+        gain: f32,
+    ) -> PlaybackGuard {
+        // Transposing happens before the temperament, so the temperament applies to the key we're
+        // actually playing in. Just intonation only depends on the intervals, which don't change.
+        let pitch = pitch.saturating_add_signed(self.transpose_semitones);
+        let frequency = self.base_frequency(pitch) * 2f64.powf(cents / 1200.0);
+        if let Some(midi_output) = &self.midi_output {
+            let gain =
+                self.overall_gain.gain().value() * self.voice_gains[voice].gain().value() * gain;
+            // The device may have been unplugged, in which case it's better to keep playing here
+            // than to go silent.
+            match midi_output.start_note(&self.ctx, voice, frequency, gain, when) {
+                Ok(note) => {
+                    let start_time = when.max(self.current_time());
+                    return PlaybackGuard::for_midi(self.ctx.clone(), note, start_time);
+                }
+                Err(e) => error!("Failed to send a note to the MIDI output: {e:?}"),
             }
         }
+        self.voice_instruments[voice]
+            .start_note(frequency, gain, &self.voice_gains[voice], when)
+            .unwrap()
+    }
+
+    /// The current time of the audio clock, which is what everything gets scheduled against.
+    pub fn current_time(&self) -> f64 {
+        self.ctx.current_time()
+    }
 
-        Some((slice.cursor_index, sampler_playback_guards))
+    pub fn song_data(&self) -> Option<&SongData> {
+        self.song_data.as_ref()
     }
 
     pub fn max_song_index(&self) -> Option<usize> {
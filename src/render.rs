@@ -0,0 +1,487 @@
+//! Renders part of the song to audio files, eg to send a singer a practice track with their part
+//! brought up or left out.
+
+use std::io::{Cursor, Write};
+use std::ops::{Range, RangeInclusive};
+
+use bit_set::BitSet;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{AudioBuffer, OfflineAudioContext};
+use zip::result::ZipResult;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::future_util::PromiseAsFuture;
+use crate::playback_manager::PlaybackManager;
+use crate::song_data::SongData;
+use crate::transport::{Player, Transport};
+
+/// Rendered in stereo, so each voice keeps the pan it has in the mixer.
+const NUM_CHANNELS: u32 = 2;
+
+/// A part of the song to render.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Excerpt {
+    /// Indexes into `SongData::slices`.
+    pub song_range: Range<usize>,
+    /// Replaces the song's tempo, the same as when playing it live.
+    pub tempo_bpm: Option<f64>,
+}
+
+/// Audio rendered from an `OfflineAudioContext`.
+pub struct RenderedAudio {
+    pub sample_rate: f32,
+    /// One `Vec` of samples for each channel, all the same length.
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl RenderedAudio {
+    /// The number of samples in each channel.
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    /// The loudest sample across all channels.
+    pub fn peak(&self) -> f32 {
+        self.channels
+            .iter()
+            .flatten()
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    /// Encodes the audio as a 16-bit PCM WAV file.
+    pub fn to_wav(&self) -> Vec<u8> {
+        const BYTES_PER_SAMPLE: u16 = 2;
+        let num_channels = self.channels.len() as u16;
+        let sample_rate = self.sample_rate as u32;
+        let block_align = num_channels * BYTES_PER_SAMPLE;
+        let data_len = (self.len() * block_align as usize) as u32;
+
+        let mut wav = Vec::with_capacity(44 + data_len as usize);
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        // Uncompressed PCM
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&num_channels.to_le_bytes());
+        wav.extend_from_slice(&sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        wav.extend_from_slice(&block_align.to_le_bytes());
+        wav.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        for frame in 0..self.len() {
+            for channel in &self.channels {
+                let sample = (channel[frame].clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                wav.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        wav
+    }
+}
+
+/// The slices which fall in the given (1-based) measures, as numbered by
+/// `SongData::measure_and_beat`.
+pub fn song_range_for_measures(
+    song_data: &SongData,
+    measures: RangeInclusive<usize>,
+) -> Range<usize> {
+    let measure_of = |song_index: usize| {
+        song_data
+            .measure_and_beat(song_data.slices[song_index].timestamp)
+            .0
+    };
+    let num_slices = song_data.slices.len();
+    let start = (0..num_slices)
+        .find(|song_index| measure_of(*song_index) >= *measures.start())
+        .unwrap_or(num_slices);
+    let end = (start..num_slices)
+        .find(|song_index| measure_of(*song_index) > *measures.end())
+        .unwrap_or(num_slices);
+    start..end
+}
+
+/// How many frames it takes to render the excerpt at `sample_rate`, through to the end of the last
+/// note's release. `None` if the excerpt doesn't start on a slice.
+pub fn render_length<P: Player>(player: &P, excerpt: &Excerpt, sample_rate: f32) -> Option<u32> {
+    let transport = Transport::start(player, excerpt.song_range.start, excerpt.tempo_bpm)?
+        .with_end(excerpt.song_range.end);
+    Some((transport.duration(player) * sample_rate as f64).ceil() as u32)
+}
+
+/// Adds the stems together, each scaled by its gain, into `len` frames of `NUM_CHANNELS` channels.
+pub fn mix_down(sample_rate: f32, len: usize, stems: &[(RenderedAudio, f32)]) -> RenderedAudio {
+    let mut channels = vec![vec![0.0; len]; NUM_CHANNELS as usize];
+    for (stem, gain) in stems {
+        for (mixed, channel) in channels.iter_mut().zip(&stem.channels) {
+            for (mixed, sample) in mixed.iter_mut().zip(channel) {
+                *mixed += sample * gain;
+            }
+        }
+    }
+    RenderedAudio {
+        sample_rate,
+        channels,
+    }
+}
+
+/// A render that's been set up and is ready to go. Each voice is rendered on its own at full
+/// volume and then mixed down at its gain, so the stems and the mix come from the same audio. All
+/// the scheduling happens up front, so the `PlaybackManager` doesn't need to be held onto while it
+/// renders.
+pub struct OfflineRender {
+    sample_rate: f32,
+    len: usize,
+    stems: Vec<StemRender>,
+}
+
+struct StemRender {
+    voice: usize,
+    /// The voice's gain in the mixer, applied when mixing down.
+    gain: f32,
+    ctx: OfflineAudioContext,
+    /// Has to stay alive until rendering finishes, since dropping it stops the notes.
+    _transport: Transport,
+}
+
+impl OfflineRender {
+    /// Sets up the excerpt with the given voices, using the playback manager's current
+    /// instruments, gains and tuning.
+    pub fn new(
+        playback_manager: &PlaybackManager,
+        excerpt: &Excerpt,
+        voices: &BitSet,
+    ) -> Result<Self, JsValue> {
+        let nothing_to_render = || JsValue::from_str("Nothing to render");
+        let sample_rate = playback_manager.audio_context().sample_rate();
+        let len =
+            render_length(playback_manager, excerpt, sample_rate).ok_or_else(nothing_to_render)?;
+        let stems = voices
+            .iter()
+            .map(|voice| {
+                let ctx =
+                    OfflineAudioContext::new_with_number_of_channels_and_length_and_sample_rate(
+                        NUM_CHANNELS,
+                        len,
+                        sample_rate,
+                    )?;
+                let offline_playback_manager = playback_manager.for_offline_context(&ctx)?;
+                offline_playback_manager.set_voice_gain(voice, 1.0);
+                let mut transport = Transport::start(
+                    &offline_playback_manager,
+                    excerpt.song_range.start,
+                    excerpt.tempo_bpm,
+                )
+                .ok_or_else(nothing_to_render)?
+                .with_end(excerpt.song_range.end);
+                transport.schedule_all(&offline_playback_manager, &BitSet::from_iter([voice]));
+                Ok(StemRender {
+                    voice,
+                    gain: playback_manager.voice_gain(voice),
+                    ctx,
+                    _transport: transport,
+                })
+            })
+            .collect::<Result<Vec<_>, JsValue>>()?;
+
+        Ok(Self {
+            sample_rate,
+            len: len as usize,
+            stems,
+        })
+    }
+
+    /// Renders each voice on its own, by voice index.
+    pub async fn render_stems(self) -> Result<Vec<(usize, RenderedAudio)>, JsValue> {
+        let mut stems = Vec::new();
+        for stem in &self.stems {
+            let audio = stem.render().await?;
+            stems.push((
+                stem.voice,
+                mix_down(self.sample_rate, self.len, &[(audio, stem.gain)]),
+            ));
+        }
+        Ok(stems)
+    }
+
+    /// Renders all the voices mixed together.
+    pub async fn render_mix(self) -> Result<RenderedAudio, JsValue> {
+        let mut stems = Vec::new();
+        for stem in &self.stems {
+            stems.push((stem.render().await?, stem.gain));
+        }
+        Ok(mix_down(self.sample_rate, self.len, &stems))
+    }
+}
+
+impl StemRender {
+    async fn render(&self) -> Result<RenderedAudio, JsValue> {
+        let buffer = self
+            .ctx
+            .start_rendering()?
+            .into_future()
+            .await?
+            .dyn_into::<AudioBuffer>()?;
+        let channels = (0..buffer.number_of_channels())
+            .map(|channel| buffer.get_channel_data(channel))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(RenderedAudio {
+            sample_rate: buffer.sample_rate(),
+            channels,
+        })
+    }
+}
+
+/// Bundles the given (file name, contents) pairs into a zip file.
+pub fn zip_files(files: &[(String, Vec<u8>)]) -> ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        zip.start_file(name.as_str(), SimpleFileOptions::default())?;
+        zip.write_all(contents)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song_data::TimeSlice;
+
+    #[test]
+    fn wav_has_a_header_and_interleaved_samples() {
+        let audio = RenderedAudio {
+            sample_rate: 44100.0,
+            channels: vec![vec![0.0, 1.0, -1.0], vec![0.5, 0.0, 2.0]],
+        };
+        let wav = audio.to_wav();
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([wav[22], wav[23]]), 2);
+        assert_eq!(
+            u32::from_le_bytes([wav[24], wav[25], wav[26], wav[27]]),
+            44100
+        );
+        assert_eq!(&wav[36..40], b"data");
+        let data_len = u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]);
+        assert_eq!(data_len, 3 * 2 * 2);
+        assert_eq!(wav.len(), 44 + data_len as usize);
+
+        let samples = wav[44..]
+            .chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<_>>();
+        // Out of range samples are clipped.
+        assert_eq!(samples, vec![0, 16384, i16::MAX, 0, -i16::MAX, i16::MAX]);
+    }
+
+    #[test]
+    fn measures_map_to_the_slices_within_them() {
+        let song_data = SongData::from_midi(include_bytes!("../examples/A Million Stars.mid"))
+            .expect("Example should parse");
+        let all = song_range_for_measures(&song_data, 1..=usize::MAX);
+        assert_eq!(all, 0..song_data.slices.len());
+
+        let second = song_range_for_measures(&song_data, 2..=2);
+        assert!(!second.is_empty());
+        for song_index in second.clone() {
+            let timestamp = song_data.slices[song_index].timestamp;
+            assert_eq!(song_data.measure_and_beat(timestamp).0, 2);
+        }
+        assert_eq!(song_range_for_measures(&song_data, 1..=2), 0..second.end);
+    }
+
+    /// Just enough of a player to work out how long renders take.
+    struct SongPlayer(SongData);
+
+    impl Player for SongPlayer {
+        type Guard = ();
+
+        fn song_data(&self) -> Option<&SongData> {
+            Some(&self.0)
+        }
+
+        fn current_time(&self) -> f64 {
+            0.0
+        }
+
+        fn cent_offsets_for_slice(&self, slice: &TimeSlice) -> Vec<Vec<f64>> {
+            slice
+                .notes_by_voice
+                .iter()
+                .map(|notes| vec![0.0; notes.len()])
+                .collect()
+        }
+
+        fn play_note(&self, _voice: usize, _pitch: u32, _cents: f64, _start: f64, _release: f64) {}
+    }
+
+    /// A stereo stem with a square wave in it.
+    fn tone(len: usize) -> RenderedAudio {
+        let samples = (0..len)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect::<Vec<_>>();
+        RenderedAudio {
+            sample_rate: 1000.0,
+            channels: vec![samples.clone(), samples],
+        }
+    }
+
+    #[test]
+    fn renders_last_as_long_as_the_excerpt() {
+        let song_data = SongData::from_midi(include_bytes!("../examples/A Million Stars.mid"))
+            .expect("Example should parse");
+        let song_range = song_range_for_measures(&song_data, 1..=2);
+        let from = song_data.slices[song_range.start].timestamp;
+        let to = song_data.slices[song_range.end].timestamp;
+        let tempo_scale = 120.0 / song_data.tempo_at(from).unwrap();
+        let player = SongPlayer(song_data.clone());
+        let excerpt = Excerpt {
+            song_range: song_range.clone(),
+            tempo_bpm: Some(120.0),
+        };
+
+        let len = render_length(&player, &excerpt, 1000.0).unwrap();
+        // A moment at the start for the first note to be scheduled, and a second at the end for
+        // the last release.
+        let expected_seconds = 0.05 + song_data.seconds_between(from, to) / tempo_scale + 1.0;
+        assert_eq!(len, (expected_seconds * 1000.0).ceil() as u32);
+
+        let past_the_end = Excerpt {
+            song_range: song_data.slices.len()..song_data.slices.len(),
+            tempo_bpm: None,
+        };
+        assert_eq!(render_length(&player, &past_the_end, 1000.0), None);
+    }
+
+    #[test]
+    fn stems_are_mixed_down_at_their_gains() {
+        let len = 100;
+        for gain in [1.0, 0.25] {
+            let stem = mix_down(1000.0, len, &[(tone(len), gain)]);
+            assert_eq!(stem.len(), len);
+            assert_eq!(stem.channels.len(), NUM_CHANNELS as usize);
+            assert_eq!(stem.peak(), 0.5 * gain);
+        }
+
+        let muted = mix_down(1000.0, len, &[(tone(len), 0.0)]);
+        assert_eq!(muted.len(), len);
+        assert_eq!(muted.peak(), 0.0);
+
+        let mix = mix_down(1000.0, len, &[(tone(len), 1.0), (tone(len), 0.5)]);
+        assert_eq!(mix.len(), len);
+        assert_eq!(mix.peak(), 0.75);
+
+        let nothing = mix_down(1000.0, len, &[]);
+        assert_eq!(nothing.len(), len);
+        assert_eq!(nothing.peak(), 0.0);
+    }
+
+    /// These need a browser for WebAudio, run them with `wasm-pack test --headless --chrome`.
+    #[cfg(target_arch = "wasm32")]
+    mod offline {
+        use std::rc::Rc;
+
+        use wasm_bindgen_test::*;
+        use web_sys::{AudioContext, BaseAudioContext};
+
+        use super::*;
+        use crate::instrument::FALLBACK_PIANO;
+        use crate::synth::Synth;
+
+        wasm_bindgen_test_configure!(run_in_browser);
+
+        /// Takes a moment at the start for the first note to be scheduled, and a second at the end
+        /// for the last release.
+        const LEAD_IN_SECONDS: f64 = 0.05;
+        const RELEASE_SECONDS: f64 = 1.0;
+
+        fn playback_manager() -> PlaybackManager {
+            let ctx: BaseAudioContext = AudioContext::new().unwrap().into();
+            let mut playback_manager =
+                PlaybackManager::new(ctx, Rc::new(Synth::new(FALLBACK_PIANO)));
+            let song_data = SongData::from_midi(include_bytes!("../examples/A Million Stars.mid"))
+                .expect("Example should parse");
+            playback_manager.set_song_data(song_data);
+            playback_manager
+        }
+
+        #[wasm_bindgen_test]
+        async fn render_lasts_as_long_as_the_excerpt() {
+            let playback_manager = playback_manager();
+            let song_data = playback_manager.song_data().unwrap();
+            let song_range = song_range_for_measures(song_data, 1..=2);
+            let from = song_data.slices[song_range.start].timestamp;
+            let to = song_data.slices[song_range.end].timestamp;
+            let excerpt = Excerpt {
+                song_range: song_range.clone(),
+                tempo_bpm: Some(120.0),
+            };
+
+            let rendered = OfflineRender::new(&playback_manager, &excerpt, &BitSet::from_iter([0]))
+                .unwrap()
+                .render_mix()
+                .await
+                .unwrap();
+
+            let tempo_scale = 120.0 / song_data.tempo_at(from).unwrap();
+            let expected_seconds = LEAD_IN_SECONDS
+                + song_data.seconds_between(from, to) / tempo_scale
+                + RELEASE_SECONDS;
+            let expected_len = (expected_seconds * rendered.sample_rate as f64).ceil() as usize;
+            assert!(rendered.len().abs_diff(expected_len) <= 1);
+            assert_eq!(rendered.channels.len(), NUM_CHANNELS as usize);
+        }
+
+        #[wasm_bindgen_test]
+        async fn every_stem_has_sound() {
+            let playback_manager = playback_manager();
+            let song_data = playback_manager.song_data().unwrap();
+            let num_voices = song_data.voice_index_mapping.len();
+            let excerpt = Excerpt {
+                song_range: song_range_for_measures(song_data, 1..=4),
+                tempo_bpm: None,
+            };
+            let voices = BitSet::from_iter(0..num_voices);
+
+            let stems = OfflineRender::new(&playback_manager, &excerpt, &voices)
+                .unwrap()
+                .render_stems()
+                .await
+                .unwrap();
+
+            assert_eq!(stems.len(), num_voices);
+            let stem_len = stems[0].1.len();
+            for (voice, stem) in &stems {
+                assert_eq!(stem.len(), stem_len);
+                assert!(stem.peak() > 0.01, "Voice {voice} is silent");
+            }
+        }
+
+        #[wasm_bindgen_test]
+        async fn silenced_voices_stay_silent() {
+            let playback_manager = playback_manager();
+            playback_manager.set_voice_gain(0, 0.0);
+            let excerpt = Excerpt {
+                song_range: song_range_for_measures(playback_manager.song_data().unwrap(), 1..=2),
+                tempo_bpm: None,
+            };
+
+            let rendered = OfflineRender::new(&playback_manager, &excerpt, &BitSet::from_iter([0]))
+                .unwrap()
+                .render_mix()
+                .await
+                .unwrap();
+            assert_eq!(rendered.peak(), 0.0);
+
+            let rendered = OfflineRender::new(&playback_manager, &excerpt, &BitSet::new())
+                .unwrap()
+                .render_mix()
+                .await
+                .unwrap();
+            assert_eq!(rendered.peak(), 0.0);
+        }
+    }
+}
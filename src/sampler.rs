@@ -1,58 +1,106 @@
-use std::collections::BTreeMap;
-use std::fmt::{Debug, Formatter};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::RangeInclusive;
 
 use futures::future::join_all;
-use gloo::net::http::Request;
+use gloo::timers::future::TimeoutFuture;
 use itertools::Itertools;
 use js_sys::Uint8Array;
-use log::error;
+use log::{error, warn};
 use once_cell::sync::Lazy;
 use regex::{Regex, RegexBuilder};
 use wasm_bindgen::{JsCast, JsValue};
-use web_sys::{
-    AudioBuffer, AudioBufferSourceNode, AudioContext, AudioNode, AudioScheduledSourceNode, GainNode,
-};
+use web_sys::{AudioBuffer, AudioNode, AudioScheduledSourceNode, BaseAudioContext};
 
 use crate::future_util::PromiseAsFuture;
+use crate::instrument::{Instrument, PlaybackGuard};
+use crate::midi_input::gain_to_velocity;
+use crate::offline_cache::{self, FetchError};
+use crate::soundfont::{parse_sfz, SoundFont, SoundFontError, ZoneInfo};
+use crate::synth::Envelope;
+use crate::temperament::{equal_tempered_frequency, midi_note_for_frequency, STANDARD_A4_HZ};
 
-/// Holds onto the playback nodes that were started by the `Sampler` allowing you to stop them
-/// before they reach the end of the sample.
-///
-/// Dropping the object will stop the playback.
-// TODO - If we need to get around this behavior we can add a `.forget()`.
-pub struct SamplerPlaybackGuard {
-    ctx: AudioContext,
-    buffer_source: AudioBufferSourceNode,
-    gain: GainNode,
-}
-
-impl SamplerPlaybackGuard {
-    fn stop_playback(&self) -> Result<(), JsValue> {
-        let current_time = self.ctx.current_time();
-        let end_time = current_time + 1.0;
-        self.gain.gain().set_value_at_time(1.0, current_time)?;
-        self.gain
-            .gain()
-            .linear_ramp_to_value_at_time(0.0, end_time)?;
-        // Need to disambiguate between `AudioScheduledSourceNode` and `AudioBufferSourceNode` copies of the method.
-        AudioScheduledSourceNode::stop_with_when(&self.buffer_source, end_time)?;
+/// How long the sampler takes to fade out once a note is released, in seconds.
+const RELEASE_SECONDS: f64 = 1.0;
+
+/// How many times to try fetching a sample before giving up on it.
+const MAX_FETCH_ATTEMPTS: u32 = 3;
+/// How long to wait before retrying a failed fetch, multiplied by how many tries we've had.
+const RETRY_DELAY_MILLIS: u32 = 500;
+
+#[derive(Debug)]
+pub enum SampleLoadError {
+    MalformedNoteName(String),
+    Fetch {
+        url: String,
+        error: FetchError,
+    },
+    /// We got the file, but the browser couldn't make sense of it as audio.
+    Decode {
+        url: String,
+        message: String,
+    },
+}
 
-        Ok(())
+impl Display for SampleLoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleLoadError::MalformedNoteName(name) => write!(f, "Malformed note name {name}"),
+            SampleLoadError::Fetch { url, error } => {
+                write!(f, "Unable to fetch sample at {url}: {error}")
+            }
+            SampleLoadError::Decode { url, message } => {
+                write!(f, "Unable to decode sample at {url}: {message}")
+            }
+        }
     }
 }
 
-impl Drop for SamplerPlaybackGuard {
-    fn drop(&mut self) {
-        if let Err(e) = self.stop_playback() {
-            error!("Failed to stop playback: {e:?}");
+impl std::error::Error for SampleLoadError {}
+
+/// What went wrong while loading the samples for `Sampler::initialize`.
+#[derive(Debug, Default)]
+pub struct SampleLoadReport {
+    pub failures: Vec<SampleLoadError>,
+    /// The keys that would have been played by the samples that failed, which now get stretched
+    /// further from another sample and so won't sound as good.
+    pub degraded_ranges: Vec<RangeInclusive<u8>>,
+}
+
+/// A sample and the notes it's used for.
+struct Zone {
+    info: ZoneInfo,
+    buffer: AudioBuffer,
+    /// `info.loop_frames`, converted to seconds into `buffer`.
+    loop_seconds: Option<(f64, f64)>,
+}
+
+impl Zone {
+    /// `sample_rate` is the rate of the sample the loop points were given against, which isn't
+    /// necessarily the buffer's if it was resampled while decoding.
+    fn new(info: ZoneInfo, buffer: AudioBuffer, sample_rate: f32) -> Self {
+        let loop_seconds = info.loop_frames.map(|(start, end)| {
+            (
+                start as f64 / sample_rate as f64,
+                end as f64 / sample_rate as f64,
+            )
+        });
+        Self {
+            info,
+            buffer,
+            loop_seconds,
         }
     }
+
+    /// How far (in semitones) the zone's sample has to be stretched to play `key`.
+    fn distance(&self, key: u8) -> f64 {
+        (self.info.root_key - key as f64).abs()
+    }
 }
 
 /// Heavily inspired by https://tonejs.github.io/docs/latest/classes/Sampler
 pub struct Sampler {
-    ctx: AudioContext,
-    buffers: BTreeMap<i32, AudioBuffer>,
+    zones: Vec<Zone>,
 }
 
 impl Debug for Sampler {
@@ -64,69 +112,266 @@ impl Debug for Sampler {
 }
 
 impl Sampler {
-    /// * `urls`: A series of (note_name, url) pairs
+    /// * `urls`: A series of (note_name, url) pairs, each sample is used for the notes closer to it
+    ///   than any other.
+    ///
+    /// Samples which fail to load are left out, their notes get played by stretching the
+    /// neighbouring samples further. The report says which samples those were.
     // fn new(urls: impl IntoIterator<Item = (impl AsRef<str>, impl AsRef<str>)>) -> Self {
-    pub async fn initialize(ctx: AudioContext, urls: &[(&str, &str)]) -> Self {
+    pub async fn initialize(
+        ctx: BaseAudioContext,
+        urls: &[(&str, &str)],
+    ) -> (Self, SampleLoadReport) {
         let ctx_ref = &ctx;
+        let mut report = SampleLoadReport::default();
 
-        let buffer_futures = urls.iter().map(|(note_name, url)| async move {
-            (
-                note_name_to_midi_note(note_name)
-                    .unwrap_or_else(|| panic!("Malformed note name {note_name}")),
-                url_to_audio_buffer(ctx_ref, url)
-                    .await
-                    .unwrap_or_else(|e| panic!("Unable to fetch sample at {url}: {e:?}")),
-            )
-        });
-        let buffers = join_all(buffer_futures)
-            .await
-            .into_iter()
-            .collect::<BTreeMap<_, _>>();
+        let mut notes = Vec::new();
+        for (note_name, url) in urls {
+            match note_name_to_midi_note(note_name) {
+                Some(note) => notes.push((note, *url)),
+                None => report
+                    .failures
+                    .push(SampleLoadError::MalformedNoteName(note_name.to_string())),
+            }
+        }
+        notes.sort_by_key(|(note, _)| *note);
+
+        let buffer_futures = notes
+            .iter()
+            .map(|(_, url)| async move { load_sample(ctx_ref, url).await });
+        let buffers = join_all(buffer_futures).await;
+
+        // Split the keyboard halfway between each sample, with ties going to the higher one.
+        let mut zones = Vec::new();
+        for (index, ((note, _), buffer)) in notes.iter().zip(buffers).enumerate() {
+            let low_key = match index.checked_sub(1) {
+                Some(previous) => (notes[previous].0 + note + 1) / 2,
+                None => 0,
+            };
+            let high_key = match notes.get(index + 1) {
+                Some((next, _)) => (note + next + 1) / 2 - 1,
+                None => 127,
+            };
+            let key_range = low_key.clamp(0, 127) as u8..=high_key.clamp(0, 127) as u8;
+
+            let buffer = match buffer {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    error!("{e}");
+                    report.failures.push(e);
+                    match report.degraded_ranges.last_mut() {
+                        Some(last) if *last.end() as usize + 1 == *key_range.start() as usize => {
+                            *last = *last.start()..=*key_range.end()
+                        }
+                        _ => report.degraded_ranges.push(key_range),
+                    }
+                    continue;
+                }
+            };
+            let sample_rate = buffer.sample_rate();
+            zones.push(Zone::new(
+                ZoneInfo {
+                    key_range,
+                    root_key: *note as f64,
+                    ..Default::default()
+                },
+                buffer,
+                sample_rate,
+            ));
+        }
+
+        (Self { zones }, report)
+    }
+
+    /// True if there aren't any samples to play, in which case `start_note` will panic.
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// One sampler for each preset in the SoundFont.
+    pub fn from_sound_font(
+        ctx: &BaseAudioContext,
+        sound_font: &SoundFont,
+    ) -> Result<Vec<(String, Self)>, JsValue> {
+        let buffers = sound_font
+            .samples
+            .iter()
+            .map(|sample| {
+                let buffer =
+                    ctx.create_buffer(1, sample.frames.len() as u32, sample.sample_rate as f32)?;
+                buffer.copy_to_channel(&sample.frames, 0)?;
+                Ok(buffer)
+            })
+            .collect::<Result<Vec<_>, JsValue>>()?;
 
-        Self { ctx, buffers }
+        Ok(sound_font
+            .presets
+            .iter()
+            .map(|preset| {
+                let zones = preset
+                    .zones
+                    .iter()
+                    .map(|(info, sample)| {
+                        let sample_rate = sound_font.samples[*sample].sample_rate as f32;
+                        Zone::new(info.clone(), buffers[*sample].clone(), sample_rate)
+                    })
+                    .collect_vec();
+                (preset.name.clone(), Self { zones })
+            })
+            .collect_vec())
     }
 
-    pub fn start_note(
+    /// Loads an SFZ instrument. `files` are the sample files that came with it, by file name.
+    /// Samples are matched up by file name alone since that's all the browser gives us.
+    pub async fn from_sfz(
+        ctx: &BaseAudioContext,
+        sfz: &str,
+        files: &HashMap<String, Vec<u8>>,
+    ) -> Result<Self, SoundFontError> {
+        let files_by_name = files
+            .iter()
+            .map(|(name, data)| (file_name(name).to_lowercase(), data))
+            .collect::<HashMap<_, _>>();
+
+        let mut buffers = HashMap::new();
+        let mut zones = Vec::new();
+        for region in parse_sfz(sfz)? {
+            if !buffers.contains_key(&region.sample) {
+                let data = files_by_name
+                    .get(&file_name(&region.sample).to_lowercase())
+                    .ok_or_else(|| SoundFontError::MissingSample(region.sample.clone()))?;
+                let buffer = bytes_to_audio_buffer(ctx, data).await.map_err(|e| {
+                    SoundFontError::Malformed(format!("Unable to decode {}: {e:?}", region.sample))
+                })?;
+                let sample_rate = wav_sample_rate(data)
+                    .map(|rate| rate as f32)
+                    .unwrap_or_else(|| buffer.sample_rate());
+                buffers.insert(region.sample.clone(), (buffer, sample_rate));
+            }
+            let (buffer, sample_rate) = &buffers[&region.sample];
+            zones.push(Zone::new(region.zone, buffer.clone(), *sample_rate));
+        }
+
+        Ok(Self { zones })
+    }
+
+    /// The zone to play `key` with. If no zone covers it, the one that needs the least stretching.
+    fn zone_for(&self, key: u8, velocity: u8) -> Option<&Zone> {
+        fn closest(zones: Vec<&Zone>, key: u8) -> Option<&Zone> {
+            zones
+                .into_iter()
+                .min_by(|a, b| a.distance(key).total_cmp(&b.distance(key)))
+        }
+        let covering_key = self
+            .zones
+            .iter()
+            .filter(|zone| zone.info.key_range.contains(&key))
+            .collect_vec();
+        closest(
+            covering_key
+                .iter()
+                .copied()
+                .filter(|zone| zone.info.velocity_range.contains(&velocity))
+                .collect_vec(),
+            key,
+        )
+        .or_else(|| closest(covering_key, key))
+        .or_else(|| closest(self.zones.iter().collect_vec(), key))
+    }
+}
+
+impl Instrument for Sampler {
+    fn start_note(
         &self,
-        midi_note: i32,
+        frequency: f64,
+        gain: f32,
         output_node: &AudioNode,
-    ) -> Result<SamplerPlaybackGuard, JsValue> {
-        // Find closest note
-        let above = self.buffers.range(midi_note..).next();
-        let below = self.buffers.range(..=midi_note).last();
-        let (diff, buffer) = [above, below]
-            .into_iter()
-            .flatten()
-            .map(|(key, buffer)| (midi_note - key, buffer))
-            .min_by_key(|(diff, _)| diff.abs())
-            .unwrap_or_else(|| {
-                panic!("Unable to find a corresponding buffer for midi note {midi_note}")
-            });
-
-        // Pitch shift accordingly
-        let buffer_source = self.ctx.create_buffer_source()?;
-        buffer_source.set_buffer(Some(buffer));
-
-        let frequency_ratio = 2f32.powf(diff as f32 / 12.0);
-        buffer_source.playback_rate().set_value(frequency_ratio);
-
-        // Set up our gain (for fadeout at the end) and play
-        let gain = self.ctx.create_gain()?;
-        gain.gain().set_value(1.0);
+        when: f64,
+    ) -> Result<PlaybackGuard, JsValue> {
+        let key = midi_note_for_frequency(frequency).round().clamp(0.0, 127.0) as u8;
+        let zone = self
+            .zone_for(key, gain_to_velocity(gain))
+            .unwrap_or_else(|| panic!("Unable to find a corresponding buffer for midi note {key}"));
+
+        // Pitch shift accordingly. The samples are all tuned to concert pitch.
+        let ctx = output_node.context();
+        let buffer_source = ctx.create_buffer_source()?;
+        buffer_source.set_buffer(Some(&zone.buffer));
+        if let Some((loop_start, loop_end)) = zone.loop_seconds {
+            buffer_source.set_loop(true);
+            buffer_source.set_loop_start(loop_start);
+            buffer_source.set_loop_end(loop_end);
+        }
+
+        let sample_frequency = equal_tempered_frequency(zone.info.root_key, STANDARD_A4_HZ);
+        buffer_source
+            .playback_rate()
+            .set_value((frequency / sample_frequency) as f32);
+
+        // Set up our gain (for the velocity, and fadeout at the end) and play
+        let level = gain;
+        let gain = ctx.create_gain()?;
+        gain.gain().set_value(level);
 
         buffer_source.connect_with_audio_node(&gain)?;
         gain.connect_with_audio_node(output_node)?;
 
-        buffer_source.start()?;
+        // Need to disambiguate between `AudioScheduledSourceNode` and `AudioBufferSourceNode` copies of the method.
+        AudioScheduledSourceNode::start_with_when(&buffer_source, when)?;
 
-        Ok(SamplerPlaybackGuard {
-            ctx: self.ctx.clone(),
-            buffer_source,
+        Ok(PlaybackGuard::new(
+            ctx,
+            vec![buffer_source.into()],
             gain,
-        })
+            when,
+            level,
+            // The samples have their own attack and decay, so this just holds them steady.
+            Envelope {
+                attack: 0.0,
+                decay: 0.0,
+                sustain: 1.0,
+                release: RELEASE_SECONDS,
+            },
+        ))
     }
 }
 
+/// The last part of a path, which may use either kind of slash.
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// The sample rate from a WAV file's header, which is what loop points are given in.
+/// `decodeAudioData` resamples to the context's rate so we can't get it from the decoded buffer.
+fn wav_sample_rate(data: &[u8]) -> Option<u32> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut position = 12;
+    while position + 8 <= data.len() {
+        let size = u32::from_le_bytes(data[position + 4..position + 8].try_into().ok()?) as usize;
+        if &data[position..position + 4] == b"fmt " {
+            return Some(u32::from_le_bytes(
+                data.get(position + 12..position + 16)?.try_into().ok()?,
+            ));
+        }
+        position += 8 + size + size % 2;
+    }
+    None
+}
+
+/// The name of a MIDI note, eg "C4" for 60, using sharps for the black keys.
+pub fn midi_note_name(midi_note: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!(
+        "{}{}",
+        NAMES[midi_note as usize % 12],
+        midi_note as i32 / 12 - 1
+    )
+}
+
 fn note_name_to_midi_note(note_name: &str) -> Option<i32> {
     static RE: Lazy<Regex> = Lazy::new(|| {
         RegexBuilder::new(r"^([a-g](?:b|#|##|x|bb|###|#x|x#|bbb)?)(-?[0-9]+)")
@@ -225,15 +470,38 @@ fn pitch_name_to_scale_index(pitch_name: &str) -> Option<i32> {
     })
 }
 
-async fn url_to_audio_buffer(ctx: &AudioContext, url: &str) -> Result<AudioBuffer, JsValue> {
-    let audio_data = Request::get(url)
-        .send()
-        .await
-        .unwrap()
-        .binary()
+/// Fetches and decodes a sample, retrying if the network lets us down.
+async fn load_sample(ctx: &BaseAudioContext, url: &str) -> Result<AudioBuffer, SampleLoadError> {
+    let mut attempt = 1;
+    let audio_data = loop {
+        match offline_cache::fetch_sample(url).await {
+            Ok(audio_data) => break audio_data,
+            Err(e) if e.is_transient() && attempt < MAX_FETCH_ATTEMPTS => {
+                warn!("Retrying sample {url} after {e}");
+                TimeoutFuture::new(RETRY_DELAY_MILLIS * attempt).await;
+                attempt += 1;
+            }
+            Err(error) => {
+                return Err(SampleLoadError::Fetch {
+                    url: url.to_string(),
+                    error,
+                })
+            }
+        }
+    };
+    bytes_to_audio_buffer(ctx, &audio_data)
         .await
-        .unwrap();
-    let audio_data = Uint8Array::from(audio_data.as_slice()).buffer();
+        .map_err(|e| SampleLoadError::Decode {
+            url: url.to_string(),
+            message: format!("{e:?}"),
+        })
+}
+
+async fn bytes_to_audio_buffer(
+    ctx: &BaseAudioContext,
+    data: &[u8],
+) -> Result<AudioBuffer, JsValue> {
+    let audio_data = Uint8Array::from(data).buffer();
 
     ctx.decode_audio_data(&audio_data)?
         .into_future()
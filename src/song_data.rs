@@ -1,13 +1,17 @@
 use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
 
-use bit_set::BitSet;
-use fraction::Fraction;
+use fraction::{Fraction, ToPrimitive};
 use itertools::Itertools;
 
-use crate::opensheetmusicdisplay_bindings::{OpenSheetMusicDisplay, Tie, VoiceEntry};
+use crate::opensheetmusicdisplay_bindings::{
+    OpenSheetMusicDisplay, RepetitionInstructionEnum, SourceMeasure, Tie, VoiceEntry,
+};
 
-#[derive(Clone)]
+/// What we assume when a song doesn't specify a tempo.
+pub const DEFAULT_BPM: f64 = 100.0;
+
+#[derive(Clone, PartialEq)]
 pub struct VoiceIndexMapping(Vec<(u32, u32)>);
 
 impl FromIterator<(u32, u32)> for VoiceIndexMapping {
@@ -28,17 +32,149 @@ impl VoiceIndexMapping {
                 .id_in_music_sheet(),
             voice_entry.parent_voice().voice_id(),
         );
-        self.0
-            .iter()
-            .position(|k| *k == voice_key)
+        self.index_for_key(voice_key)
             .unwrap_or_else(|| panic!("Unable to find voice index for key {voice_key:?}"))
     }
+
+    /// Looks up the voice index for a `(staff_id, voice_id)` pair.
+    pub fn index_for_key(&self, voice_key: (u32, u32)) -> Option<usize> {
+        self.0.iter().position(|k| *k == voice_key)
+    }
+
+    /// Names each voice after the part it's in. Barbershop and choral scores tend to put two
+    /// voices on a staff and name the part after both (eg "Tenor/Lead"), so if the part name (or
+    /// failing that, one of its alternate names) splits into one name per voice we use those, top
+    /// voice first. Voices that aren't in a named part get "Voice N".
+    pub fn voice_names(&self, parts: &[NamedPart]) -> Vec<String> {
+        let mut names = (1..=self.len())
+            .map(|num| format!("Voice {num}"))
+            .collect_vec();
+        for part in parts {
+            let voices = self
+                .0
+                .iter()
+                .positions(|(staff_id, _)| part.staff_ids.contains(staff_id))
+                .collect_vec();
+            let candidates = std::iter::once(&part.name)
+                .chain(&part.alternate_names)
+                .map(|name| split_part_name(name))
+                .filter(|part_names| !part_names.is_empty())
+                .collect_vec();
+            if let Some(part_names) = candidates
+                .iter()
+                .find(|part_names| part_names.len() == voices.len())
+            {
+                for (voice, name) in voices.into_iter().zip(part_names) {
+                    names[voice] = name.clone();
+                }
+                continue;
+            }
+            let Some(part_names) = candidates.first() else {
+                continue;
+            };
+            if let [voice] = voices[..] {
+                names[voice] = part_names.join("/");
+            } else {
+                for (num, voice) in voices.into_iter().enumerate() {
+                    names[voice] = format!("{} {}", part_names.join("/"), num + 1);
+                }
+            }
+        }
+        names
+    }
+}
+
+/// A named group of staves from the source, eg a MusicXML part or a MIDI track.
+pub struct NamedPart {
+    pub name: String,
+    /// Other names for the part (eg its abbreviation), for when `name` doesn't have one per voice.
+    pub alternate_names: Vec<String>,
+    /// The first half of the `(staff_id, voice_id)` voice keys in this part.
+    pub staff_ids: Vec<u32>,
+}
+
+/// Splits a part name like "TENOR\nLEAD" or "Soprano/Alto" into its voices, tidying up all-caps
+/// (or all-lowercase) names while we're at it.
+fn split_part_name(name: &str) -> Vec<String> {
+    name.split(['\n', '/', ',', '&'])
+        .map(|n| n.trim())
+        .filter(|n| !n.is_empty())
+        .map(|n| {
+            if n.chars().any(|c| c.is_lowercase()) && n.chars().any(|c| c.is_uppercase()) {
+                return n.to_string();
+            }
+            n.split_whitespace()
+                .map(|word| {
+                    let mut chars = word.chars();
+                    chars
+                        .next()
+                        .into_iter()
+                        .chain(chars.flat_map(|c| c.to_lowercase()))
+                        .collect::<String>()
+                })
+                .join(" ")
+        })
+        .collect_vec()
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct SongData {
     pub voice_index_mapping: VoiceIndexMapping,
     pub slices: Vec<TimeSlice>,
+    /// When the last note of the song finishes.
+    pub end_timestamp: Fraction,
+    /// Sorted by timestamp. May be empty if the source doesn't say anything about tempo.
+    pub tempo_changes: Vec<TempoChange>,
+    /// Sorted by timestamp. May be empty, in which case it's assumed to be 4/4 throughout.
+    pub time_signatures: Vec<TimeSignature>,
+    /// One per voice, in voice index order.
+    pub voice_names: Vec<String>,
+    /// In written order. May be empty if the source doesn't have measures (eg MIDI), in which case
+    /// the song can only be played as written.
+    pub measures: Vec<Measure>,
+}
+
+/// All timestamps are in whole notes from the start of the song, same as OSMD's timestamps.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TempoChange {
+    pub timestamp: Fraction,
+    /// Quarter notes per minute.
+    pub bpm: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeSignature {
+    pub timestamp: Fraction,
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+/// A measure of the score, along with any markings which change the order it's performed in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Measure {
+    pub timestamp: Fraction,
+    pub duration: Fraction,
+    /// Whether a repeated section starts here.
+    pub repeat_start: bool,
+    /// If a repeated section ends here, how many times the section is played in total.
+    pub repeat_end: Option<u32>,
+    /// Which passes through a repeat this measure is played on, if it's part of a volta (first
+    /// ending, second ending, etc). Empty if it's played every time.
+    pub ending_numbers: Vec<u32>,
+    pub segno: bool,
+    pub coda: bool,
+    /// Jump to the coda after this measure, once we've already taken a D.C. or D.S.
+    pub to_coda: bool,
+    /// Stop after this measure, once we've already taken a D.C. or D.S.
+    pub fine: bool,
+    /// Jump back after this measure.
+    pub jump: Option<Jump>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Jump {
+    DaCapo,
+    DalSegno,
 }
 
 impl Debug for SongData {
@@ -49,6 +185,11 @@ impl Debug for SongData {
     }
 }
 
+fn whole_notes_to_seconds(whole_notes: Fraction, bpm: f64) -> f64 {
+    // Tempo is in quarter notes per minute.
+    whole_notes.to_f64().unwrap_or(0.0) * 4.0 * 60.0 / bpm
+}
+
 #[derive(Hash, Eq, PartialEq)]
 struct TieKey {
     start_time: Fraction,
@@ -80,6 +221,54 @@ impl TieKey {
     }
 }
 
+/// Converts OSMD's repetition instructions to our `Measure`. OSMD only marks where an ending
+/// starts and stops, so `ending_numbers` carries the current ending between measures.
+fn measure_from_osmd(source_measure: &SourceMeasure, ending_numbers: &mut Vec<u32>) -> Measure {
+    let mut measure = Measure {
+        timestamp: source_measure
+            .absolute_timestamp()
+            .to_rust_fraction()
+            .unwrap(),
+        duration: source_measure.duration().to_rust_fraction().unwrap(),
+        ..Default::default()
+    };
+    for instruction in source_measure.first_repetition_instructions() {
+        match instruction.instruction_type() {
+            RepetitionInstructionEnum::StartLine => measure.repeat_start = true,
+            RepetitionInstructionEnum::Ending => {
+                *ending_numbers = instruction
+                    .ending_indices()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|index| index.as_f64())
+                    .map(|index| index as u32)
+                    .collect_vec();
+            }
+            RepetitionInstructionEnum::Segno => measure.segno = true,
+            RepetitionInstructionEnum::Coda => measure.coda = true,
+            _ => {}
+        }
+    }
+    measure.ending_numbers = ending_numbers.clone();
+    for instruction in source_measure.last_repetition_instructions() {
+        match instruction.instruction_type() {
+            // OSMD doesn't keep track of how many times to repeat.
+            RepetitionInstructionEnum::BackJumpLine => measure.repeat_end = Some(2),
+            RepetitionInstructionEnum::Ending => ending_numbers.clear(),
+            RepetitionInstructionEnum::DaCapo
+            | RepetitionInstructionEnum::DaCapoAlFine
+            | RepetitionInstructionEnum::DaCapoAlCoda => measure.jump = Some(Jump::DaCapo),
+            RepetitionInstructionEnum::DalSegno
+            | RepetitionInstructionEnum::DalSegnoAlFine
+            | RepetitionInstructionEnum::DalSegnoAlCoda => measure.jump = Some(Jump::DalSegno),
+            RepetitionInstructionEnum::Fine => measure.fine = true,
+            RepetitionInstructionEnum::ToCoda => measure.to_coda = true,
+            _ => {}
+        }
+    }
+    measure
+}
+
 impl SongData {
     pub fn from_osmd(osmd: &OpenSheetMusicDisplay) -> Self {
         // Build the (staff_id, voice_id) pairs and sort them. Use position as final voice index.
@@ -98,14 +287,10 @@ impl SongData {
         cursor.reset();
 
         // Iterate through to build the slices.
-        // Store (voice, active pitch, end timestamp)
         // Ignore subsequent items in ties (use start time/voice/pitch/duration as key)
         //  where time is voice entry timestamp + measure absolute timestamp?
-        let mut active_notes_by_voice: Vec<Vec<(u32, Fraction)>> = (0..voice_index_mapping.len())
-            .map(|_| Vec::new())
-            .collect_vec();
+        let mut slice_builder = SliceBuilder::new(voice_index_mapping.len());
         let mut seen_ties = HashSet::new();
-        let mut slices = Vec::new();
         let mut cursor_index = 0;
         while !cursor.iterator().end_reached() {
             let current_timestamp = cursor
@@ -113,19 +298,8 @@ impl SongData {
                 .current_timestamp()
                 .to_rust_fraction()
                 .unwrap();
-            // First expire any old notes
-            active_notes_by_voice = active_notes_by_voice
-                .into_iter()
-                .map(|voice_notes| {
-                    voice_notes
-                        .into_iter()
-                        .filter(|(_, end_timestamp)| end_timestamp > &current_timestamp)
-                        .collect_vec()
-                })
-                .collect_vec();
+            slice_builder.advance_to(current_timestamp);
 
-            // Then add any new notes
-            let mut added_new_note = false;
             let Some(current_voice_entries) = cursor.iterator().current_voice_entries() else {
                 continue;
             };
@@ -145,45 +319,294 @@ impl SongData {
                     } else {
                         note.length().to_rust_fraction().unwrap()
                     };
-                    active_notes_by_voice[voice].push((pitch, current_timestamp + duration));
-                    added_new_note = true;
+                    slice_builder.add_note(voice, pitch, duration);
                 }
             }
-
-            // If anything was added, copy all the notes over to the slice. This avoids treating
-            // note-stops as a new slice, including skipping rests.
-            if added_new_note {
-                slices.push(TimeSlice::new(
-                    active_notes_by_voice
-                        .iter()
-                        .map(|voice| voice.iter().map(|(pitch, _)| *pitch as usize).collect())
-                        .collect_vec(),
-                    cursor_index,
-                ));
-            }
+            slice_builder.finish_position(cursor_index);
 
             cursor.next();
             cursor_index += 1;
         }
 
+        // OSMD tracks tempo and meter per measure, so only keep the changes.
+        let mut tempo_changes: Vec<TempoChange> = Vec::new();
+        let mut time_signatures: Vec<TimeSignature> = Vec::new();
+        for source_measure in osmd.sheet().source_measures() {
+            let timestamp = source_measure
+                .absolute_timestamp()
+                .to_rust_fraction()
+                .unwrap();
+            let bpm = source_measure.tempo_in_bpm();
+            if bpm > 0.0 && tempo_changes.last().map(|tc| tc.bpm) != Some(bpm) {
+                tempo_changes.push(TempoChange { timestamp, bpm });
+            }
+            // Intentionally not using `to_rust_fraction` since we don't want 4/4 reduced to 1/1.
+            let active_time_signature = source_measure.active_time_signature();
+            let (numerator, denominator) = (
+                active_time_signature.numerator(),
+                active_time_signature.denominator(),
+            );
+            if time_signatures
+                .last()
+                .map(|ts| (ts.numerator, ts.denominator))
+                != Some((numerator, denominator))
+            {
+                time_signatures.push(TimeSignature {
+                    timestamp,
+                    numerator,
+                    denominator,
+                });
+            }
+        }
+
+        let measures = osmd
+            .sheet()
+            .source_measures()
+            .iter()
+            .scan(Vec::new(), |ending_numbers, source_measure| {
+                Some(measure_from_osmd(source_measure, ending_numbers))
+            })
+            .collect_vec();
+
+        let parts = osmd
+            .sheet()
+            .instruments()
+            .into_iter()
+            .map(|instrument| NamedPart {
+                name: instrument.name(),
+                alternate_names: instrument.part_abbreviation().into_iter().collect_vec(),
+                staff_ids: instrument
+                    .staves()
+                    .into_iter()
+                    .map(|staff| staff.id_in_music_sheet())
+                    .collect_vec(),
+            })
+            .collect_vec();
+
+        let (slices, end_timestamp) = slice_builder.build();
         Self {
+            voice_names: voice_index_mapping.voice_names(&parts),
             voice_index_mapping,
             slices,
+            end_timestamp,
+            tempo_changes,
+            time_signatures,
+            measures,
         }
     }
+
+    /// How long it takes to play from one timestamp to another at the song's tempo(s).
+    pub fn seconds_between(&self, from: Fraction, to: Fraction) -> f64 {
+        let mut seconds = 0.0;
+        let mut position = from;
+        let mut bpm = self.tempo_at(from).unwrap_or(DEFAULT_BPM);
+        for tempo_change in self
+            .tempo_changes
+            .iter()
+            .filter(|tc| tc.timestamp > from && tc.timestamp < to)
+        {
+            seconds += whole_notes_to_seconds(tempo_change.timestamp - position, bpm);
+            position = tempo_change.timestamp;
+            bpm = tempo_change.bpm;
+        }
+        seconds + whole_notes_to_seconds(to - position, bpm)
+    }
+
+    /// The tempo in effect at the given timestamp, if the song has any tempo markings.
+    pub fn tempo_at(&self, timestamp: Fraction) -> Option<f64> {
+        self.tempo_changes
+            .iter()
+            .take_while(|tc| tc.timestamp <= timestamp)
+            .last()
+            .or(self.tempo_changes.first())
+            .map(|tc| tc.bpm)
+    }
+
+    /// Converts a timestamp to a 1-based (measure, beat) pair, where the beat may be fractional.
+    /// This is only an approximation for scores (it doesn't know about pickup measures), so is
+    /// mostly useful when there isn't a score to look at.
+    pub fn measure_and_beat(&self, timestamp: Fraction) -> (usize, Fraction) {
+        let mut measure = 0;
+        let mut signature_start = Fraction::from(0);
+        let mut measure_length = Fraction::from(1);
+        let mut beat_length = Fraction::new(1u64, 4u64);
+        for time_signature in self.time_signatures.iter() {
+            if time_signature.timestamp > timestamp {
+                break;
+            }
+            // Count the (possibly partial) measures under the previous signature.
+            let elapsed = (time_signature.timestamp - signature_start) / measure_length;
+            measure += elapsed.ceil().to_usize().unwrap_or(0);
+            signature_start = time_signature.timestamp;
+            measure_length = Fraction::new(
+                time_signature.numerator as u64,
+                time_signature.denominator as u64,
+            );
+            beat_length = Fraction::new(1u64, time_signature.denominator as u64);
+        }
+        let elapsed = (timestamp - signature_start) / measure_length;
+        let measures_in = elapsed.floor();
+        let within_measure = timestamp - signature_start - measures_in * measure_length;
+        (
+            measure + measures_in.to_usize().unwrap_or(0) + 1,
+            within_measure / beat_length + Fraction::from(1),
+        )
+    }
 }
 
-#[derive(Clone)]
+/// Accumulates `TimeSlice`s while walking through a score one cursor position at a time. This is
+/// shared between the different score sources (OSMD, MusicXML, etc) so they all agree on what
+/// counts as a slice.
+pub struct SliceBuilder {
+    active_notes_by_voice: Vec<Vec<SliceNote>>,
+    current_timestamp: Fraction,
+    end_timestamp: Fraction,
+    slices: Vec<TimeSlice>,
+}
+
+impl SliceBuilder {
+    pub fn new(num_voices: usize) -> Self {
+        Self {
+            active_notes_by_voice: (0..num_voices).map(|_| Vec::new()).collect_vec(),
+            current_timestamp: Fraction::from(0),
+            end_timestamp: Fraction::from(0),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Moves to the given timestamp, expiring any notes which have ended by then.
+    pub fn advance_to(&mut self, timestamp: Fraction) {
+        self.current_timestamp = timestamp;
+        for voice_notes in self.active_notes_by_voice.iter_mut() {
+            voice_notes.retain(|note| note.end_timestamp > timestamp);
+        }
+    }
+
+    /// Starts a note at the current timestamp.
+    pub fn add_note(&mut self, voice: usize, pitch: u32, duration: Fraction) {
+        let end_timestamp = self.current_timestamp + duration;
+        self.active_notes_by_voice[voice].push(SliceNote {
+            pitch,
+            onset: self.current_timestamp,
+            end_timestamp,
+        });
+        self.end_timestamp = self.end_timestamp.max(end_timestamp);
+    }
+
+    /// Finishes off the current position, creating a slice if any notes were started.
+    pub fn finish_position(&mut self, cursor_index: usize) {
+        let slice = TimeSlice::new(
+            self.current_timestamp,
+            self.active_notes_by_voice.clone(),
+            cursor_index,
+        );
+        // Only keep it if something was struck. This avoids treating note-stops as a new slice,
+        // including skipping rests.
+        if slice
+            .notes_by_voice
+            .iter()
+            .flatten()
+            .any(|note| slice.is_newly_struck(note))
+        {
+            self.slices.push(slice);
+        }
+    }
+
+    /// Returns the slices along with the timestamp at which the last note ends.
+    pub fn build(self) -> (Vec<TimeSlice>, Fraction) {
+        (self.slices, self.end_timestamp)
+    }
+}
+
+/// A single note that's sounding during a `TimeSlice`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SliceNote {
+    pub pitch: u32,
+    /// When the note was struck. This is before the slice's timestamp if the note is being held
+    /// over from an earlier slice.
+    pub onset: Fraction,
+    /// When the note stops, which may be after the slice ends.
+    pub end_timestamp: Fraction,
+}
+
+#[derive(Clone, PartialEq)]
 pub struct TimeSlice {
-    pub notes_by_voice: Vec<BitSet>,
+    /// When this slice starts, in whole notes from the start of the song.
+    pub timestamp: Fraction,
+    /// Every note sounding in each voice during this slice, whether newly struck or held over.
+    pub notes_by_voice: Vec<Vec<SliceNote>>,
     pub cursor_index: usize,
 }
 
 impl TimeSlice {
-    fn new(notes_by_voice: Vec<BitSet>, cursor_index: usize) -> Self {
+    fn new(timestamp: Fraction, notes_by_voice: Vec<Vec<SliceNote>>, cursor_index: usize) -> Self {
         Self {
+            timestamp,
             notes_by_voice,
             cursor_index,
         }
     }
+
+    /// Whether the note starts at this slice, as opposed to being held over from an earlier one.
+    pub fn is_newly_struck(&self, note: &SliceNote) -> bool {
+        note.onset == self.timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(name: &str, alternate_names: &[&str], staff_ids: Vec<u32>) -> NamedPart {
+        NamedPart {
+            name: name.to_string(),
+            alternate_names: alternate_names.iter().map(|n| n.to_string()).collect_vec(),
+            staff_ids,
+        }
+    }
+
+    #[test]
+    fn splits_part_names_into_voices() {
+        assert_eq!(split_part_name("TENOR\nLEAD"), vec!["Tenor", "Lead"]);
+        assert_eq!(split_part_name("BARI\nBASS"), vec!["Bari", "Bass"]);
+        assert_eq!(split_part_name("Soprano / Alto"), vec!["Soprano", "Alto"]);
+        assert_eq!(split_part_name("OPT 5th"), vec!["OPT 5th"]);
+        assert_eq!(split_part_name(" \n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn names_voices_after_their_parts() {
+        let mapping = VoiceIndexMapping::from_iter([(0, 1), (0, 2), (1, 1), (1, 2)]);
+        let parts = [
+            part("TENOR\nLEAD", &[], vec![0]),
+            part("BARI\nBASS", &[], vec![1]),
+        ];
+        assert_eq!(
+            mapping.voice_names(&parts),
+            vec!["Tenor", "Lead", "Bari", "Bass"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_alternate_names_then_numbers() {
+        let mapping = VoiceIndexMapping::from_iter([(0, 1), (0, 2), (0, 3), (1, 1), (1, 2)]);
+        let parts = [
+            part("TENOR\nLEAD", &["Tenor/Lead"], vec![0]),
+            part("Low voices", &["BARI\nBASS"], vec![1]),
+        ];
+        assert_eq!(
+            mapping.voice_names(&parts),
+            vec![
+                "Tenor/Lead 1",
+                "Tenor/Lead 2",
+                "Tenor/Lead 3",
+                "Bari",
+                "Bass"
+            ]
+        );
+        assert_eq!(
+            mapping.voice_names(&[]),
+            vec!["Voice 1", "Voice 2", "Voice 3", "Voice 4", "Voice 5"]
+        );
+    }
 }
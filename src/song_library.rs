@@ -0,0 +1,217 @@
+//! Songs the user has uploaded, kept in IndexedDB so they don't need uploading again every visit.
+//! What we list songs by is kept apart from the files themselves, so listing doesn't have to read
+//! every score.
+
+use std::fmt::{Display, Formatter};
+
+use js_sys::{Array, Promise, Uint8Array};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{
+    IdbDatabase, IdbObjectStore, IdbObjectStoreParameters, IdbRequest, IdbTransactionMode,
+};
+
+use crate::future_util::PromiseAsFuture;
+use crate::musicxml::score_metadata;
+use crate::song_settings::content_hash;
+
+const DATABASE_NAME: &str = "magic-piano-library";
+const DATABASE_VERSION: u32 = 1;
+/// `LibraryEntry`s, by id.
+const ENTRY_STORE: &str = "entries";
+/// The files' bytes, by the id of their entry.
+const FILE_STORE: &str = "files";
+
+/// A song in the library.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    /// A hash of the file, so uploading the same file again replaces it rather than adding another.
+    pub id: String,
+    /// From the score if it has one, otherwise the file name. The user can change it.
+    pub title: String,
+    pub composer: Option<String>,
+    pub arranger: Option<String>,
+    /// What the file was called when it was uploaded, for exporting it again.
+    pub file_name: String,
+}
+
+impl LibraryEntry {
+    /// The composer and arranger, for showing under the title.
+    pub fn credits(&self) -> Option<String> {
+        match (&self.composer, &self.arranger) {
+            (Some(composer), Some(arranger)) => Some(format!("{composer}, arr. {arranger}")),
+            (Some(composer), None) => Some(composer.clone()),
+            (None, Some(arranger)) => Some(format!("arr. {arranger}")),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LibraryError {
+    /// The browser doesn't have IndexedDB, or won't let us use it (eg in some private modes).
+    Unavailable,
+    /// We can only list MusicXML files.
+    NotAScore(String),
+    Missing(String),
+    Js(String),
+}
+
+impl Display for LibraryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LibraryError::Unavailable => write!(f, "this browser can't store songs"),
+            LibraryError::NotAScore(message) => write!(f, "not a MusicXML score: {message}"),
+            LibraryError::Missing(id) => write!(f, "song {id} isn't in the library"),
+            LibraryError::Js(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for LibraryError {}
+
+impl From<JsValue> for LibraryError {
+    fn from(e: JsValue) -> Self {
+        LibraryError::Js(format!("{e:?}"))
+    }
+}
+
+impl From<serde_wasm_bindgen::Error> for LibraryError {
+    fn from(e: serde_wasm_bindgen::Error) -> Self {
+        LibraryError::Js(e.to_string())
+    }
+}
+
+/// Waits for an IndexedDB request to finish, giving its result.
+async fn request_result(request: &IdbRequest) -> Result<JsValue, LibraryError> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let succeeded = request.clone();
+        let on_success = Closure::once_into_js(move || {
+            let result = succeeded.result().unwrap_or(JsValue::UNDEFINED);
+            let _ = resolve.call1(&JsValue::UNDEFINED, &result);
+        });
+        let failed = request.clone();
+        let on_error = Closure::once_into_js(move || {
+            let error = failed
+                .error()
+                .ok()
+                .flatten()
+                .map_or(JsValue::UNDEFINED, JsValue::from);
+            let _ = reject.call1(&JsValue::UNDEFINED, &error);
+        });
+        request.set_onsuccess(Some(on_success.unchecked_ref()));
+        request.set_onerror(Some(on_error.unchecked_ref()));
+    });
+    Ok(promise.into_future().await?)
+}
+
+async fn open_database() -> Result<IdbDatabase, LibraryError> {
+    let factory = web_sys::window()
+        .and_then(|window| window.indexed_db().ok().flatten())
+        .ok_or(LibraryError::Unavailable)?;
+    let request = factory.open_with_u32(DATABASE_NAME, DATABASE_VERSION)?;
+    // Only called the first time, or when `DATABASE_VERSION` is bumped.
+    let upgrading = request.clone();
+    let on_upgrade_needed = Closure::once_into_js(move || {
+        let Ok(database) = upgrading
+            .result()
+            .and_then(|db| db.dyn_into::<IdbDatabase>())
+        else {
+            return;
+        };
+        let entry_parameters = IdbObjectStoreParameters::new();
+        entry_parameters.set_key_path(&"id".into());
+        let _ =
+            database.create_object_store_with_optional_parameters(ENTRY_STORE, &entry_parameters);
+        let _ = database.create_object_store(FILE_STORE);
+    });
+    request.set_onupgradeneeded(Some(on_upgrade_needed.unchecked_ref()));
+    Ok(request_result(&request).await?.dyn_into::<IdbDatabase>()?)
+}
+
+/// The entry and file stores, in one transaction so they can't get out of step.
+async fn open_stores(
+    mode: IdbTransactionMode,
+) -> Result<(IdbObjectStore, IdbObjectStore), LibraryError> {
+    let database = open_database().await?;
+    let transaction = database.transaction_with_str_sequence_and_mode(
+        &Array::of2(&ENTRY_STORE.into(), &FILE_STORE.into()),
+        mode,
+    )?;
+    Ok((
+        transaction.object_store(ENTRY_STORE)?,
+        transaction.object_store(FILE_STORE)?,
+    ))
+}
+
+/// Everything in the library, by title.
+pub async fn list() -> Result<Vec<LibraryEntry>, LibraryError> {
+    let (entries, _) = open_stores(IdbTransactionMode::Readonly).await?;
+    let all = request_result(&entries.get_all()?).await?;
+    let mut entries = Array::from(&all)
+        .iter()
+        .map(serde_wasm_bindgen::from_value::<LibraryEntry>)
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.title.to_lowercase());
+    Ok(entries)
+}
+
+/// Stores an uploaded score, listed under what it says it is.
+pub async fn add(file_name: &str, data: &[u8]) -> Result<LibraryEntry, LibraryError> {
+    let metadata = score_metadata(data).map_err(|e| LibraryError::NotAScore(e.to_string()))?;
+    let entry = LibraryEntry {
+        id: format!("{:016x}", content_hash(data)),
+        title: metadata.title.unwrap_or_else(|| {
+            match file_name.rsplit_once('.') {
+                Some((stem, _)) if !stem.is_empty() => stem,
+                _ => file_name,
+            }
+            .to_string()
+        }),
+        composer: metadata.composer,
+        arranger: metadata.arranger,
+        file_name: file_name.to_string(),
+    };
+
+    let (entries, files) = open_stores(IdbTransactionMode::Readwrite).await?;
+    let file_request = files.put_with_key(&Uint8Array::from(data), &entry.id.as_str().into())?;
+    let entry_request = entries.put(&serde_wasm_bindgen::to_value(&entry)?)?;
+    request_result(&file_request).await?;
+    request_result(&entry_request).await?;
+    Ok(entry)
+}
+
+pub async fn rename(id: &str, title: &str) -> Result<LibraryEntry, LibraryError> {
+    let (entries, _) = open_stores(IdbTransactionMode::Readonly).await?;
+    let stored = request_result(&entries.get(&id.into())?).await?;
+    if stored.is_undefined() {
+        return Err(LibraryError::Missing(id.to_string()));
+    }
+    let mut entry = serde_wasm_bindgen::from_value::<LibraryEntry>(stored)?;
+    entry.title = title.to_string();
+    // Transactions close once they've nothing left to do, which they may have by the time we get
+    // here, so the write gets a new one.
+    let (entries, _) = open_stores(IdbTransactionMode::Readwrite).await?;
+    request_result(&entries.put(&serde_wasm_bindgen::to_value(&entry)?)?).await?;
+    Ok(entry)
+}
+
+pub async fn delete(id: &str) -> Result<(), LibraryError> {
+    let (entries, files) = open_stores(IdbTransactionMode::Readwrite).await?;
+    let entry_request = entries.delete(&id.into())?;
+    let file_request = files.delete(&id.into())?;
+    request_result(&entry_request).await?;
+    request_result(&file_request).await?;
+    Ok(())
+}
+
+/// The file as it was uploaded.
+pub async fn file_bytes(id: &str) -> Result<Vec<u8>, LibraryError> {
+    let (_, files) = open_stores(IdbTransactionMode::Readonly).await?;
+    let stored = request_result(&files.get(&id.into())?).await?;
+    if stored.is_undefined() {
+        return Err(LibraryError::Missing(id.to_string()));
+    }
+    Ok(Uint8Array::new(&stored).to_vec())
+}
@@ -0,0 +1,88 @@
+use gloo::storage::{LocalStorage, Storage};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+const STORAGE_KEY_PREFIX: &str = "song_settings:";
+
+/// For each voice and overall, out of 100.
+pub const DEFAULT_VOLUME: u32 = 70;
+/// The sheet music's zoom, as a percentage.
+pub const DEFAULT_ZOOM: f64 = 75.0;
+
+/// Things the user has changed for a particular song, kept in local storage so they're still
+/// there the next time the song is opened.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SongSettings {
+    /// Empty if the voices haven't been renamed.
+    pub voice_names: Vec<String>,
+    /// Empty if the mixer hasn't been saved for this song, in which case it's left as it was.
+    pub voice_mixes: Vec<VoiceMix>,
+    pub overall_volume: u32,
+    /// As a cursor index rather than a song index, so it doesn't depend on the playback order.
+    pub start_cursor_index: usize,
+    /// As a percentage.
+    pub zoom: f64,
+    pub original_line_breaks: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VoiceMix {
+    pub volume: u32,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+impl Default for VoiceMix {
+    fn default() -> Self {
+        Self {
+            volume: DEFAULT_VOLUME,
+            mute: false,
+            solo: false,
+        }
+    }
+}
+
+impl Default for SongSettings {
+    fn default() -> Self {
+        Self {
+            voice_names: Vec::new(),
+            voice_mixes: Vec::new(),
+            overall_volume: DEFAULT_VOLUME,
+            start_cursor_index: 0,
+            zoom: DEFAULT_ZOOM,
+            original_line_breaks: false,
+        }
+    }
+}
+
+impl SongSettings {
+    /// Loads the settings for the song with the given key, or the defaults if there aren't any.
+    pub fn load(song_key: &str) -> Self {
+        LocalStorage::get(format!("{STORAGE_KEY_PREFIX}{song_key}")).unwrap_or_default()
+    }
+
+    pub fn save(&self, song_key: &str) {
+        if let Err(e) = LocalStorage::set(format!("{STORAGE_KEY_PREFIX}{song_key}"), self) {
+            warn!("Unable to save song settings: {e}");
+        }
+    }
+
+    /// Everything back to how it is for a song that's never been opened, except the voice names,
+    /// which are more about the singers than about practicing.
+    pub fn reset_to_defaults(&mut self) {
+        *self = Self {
+            voice_names: std::mem::take(&mut self.voice_names),
+            ..Self::default()
+        };
+    }
+}
+
+/// A hash of the song file, for identifying songs which don't otherwise have a stable name (eg
+/// uploads). This is FNV-1a, which is plenty for this and, unlike `DefaultHasher`, won't change
+/// between Rust versions.
+pub fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,57 @@
+//! Readers for sampled instrument formats, so voices can be played with something other than the
+//! built-in piano (eg a choir "ooh" or an organ). These only work out which samples cover which
+//! notes, turning them into something playable is up to the `Sampler`.
+
+use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+
+mod sf2;
+mod sfz;
+
+pub use sf2::SoundFont;
+pub use sfz::parse_sfz;
+
+/// Which notes a sample is used for, and how to play it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneInfo {
+    pub key_range: RangeInclusive<u8>,
+    pub velocity_range: RangeInclusive<u8>,
+    /// The MIDI note the sample sounds at when played back as recorded. Fractional if the sample
+    /// is tuned off of a semitone.
+    pub root_key: f64,
+    /// Where the sustain loop starts and ends, in frames into the sample.
+    pub loop_frames: Option<(u32, u32)>,
+}
+
+impl Default for ZoneInfo {
+    fn default() -> Self {
+        Self {
+            key_range: 0..=127,
+            velocity_range: 0..=127,
+            root_key: 60.0,
+            loop_frames: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SoundFontError {
+    /// The file isn't shaped like the format it claims to be.
+    Malformed(String),
+    /// An SFZ region refers to a sample file we weren't given.
+    MissingSample(String),
+    /// There's nothing to play, eg the file has no regions.
+    Empty,
+}
+
+impl Display for SoundFontError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SoundFontError::Malformed(message) => write!(f, "Malformed instrument: {message}"),
+            SoundFontError::MissingSample(path) => write!(f, "Missing sample file {path}"),
+            SoundFontError::Empty => write!(f, "Instrument has no samples"),
+        }
+    }
+}
+
+impl std::error::Error for SoundFontError {}
@@ -0,0 +1,583 @@
+use std::collections::HashMap;
+use std::ops::{Range, RangeInclusive};
+
+use itertools::Itertools;
+
+use super::{SoundFontError, ZoneInfo};
+
+// Generators we look at, from section 8.1.2 of the SoundFont 2.04 spec. Anything else (envelopes,
+// filters, effects) is ignored.
+const START_ADDRS_OFFSET: u16 = 0;
+const END_ADDRS_OFFSET: u16 = 1;
+const STARTLOOP_ADDRS_OFFSET: u16 = 2;
+const ENDLOOP_ADDRS_OFFSET: u16 = 3;
+const START_ADDRS_COARSE_OFFSET: u16 = 4;
+const END_ADDRS_COARSE_OFFSET: u16 = 12;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const STARTLOOP_ADDRS_COARSE_OFFSET: u16 = 45;
+const ENDLOOP_ADDRS_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+
+/// Sample types with this bit set live in the synth's ROM rather than the file.
+const ROM_SAMPLE: u16 = 0x8000;
+
+/// A SoundFont 2 (`.sf2`) bank, flattened down to which samples each preset plays for which
+/// notes.
+#[derive(Debug)]
+pub struct SoundFont {
+    pub presets: Vec<Sf2Preset>,
+    /// Shared between the presets, which refer to them by index.
+    pub samples: Vec<Sf2Sample>,
+}
+
+#[derive(Debug)]
+pub struct Sf2Preset {
+    pub name: String,
+    /// Each zone and the index of the sample in `SoundFont::samples` that it plays.
+    pub zones: Vec<(ZoneInfo, usize)>,
+}
+
+#[derive(Debug)]
+pub struct Sf2Sample {
+    /// Mono, in the range -1 to 1.
+    pub frames: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+/// A generator's raw amount, which is interpreted differently depending on the generator.
+type Generators = HashMap<u16, [u8; 2]>;
+
+fn amount(generators: &Generators, generator: u16) -> Option<i32> {
+    generators
+        .get(&generator)
+        .map(|amount| i16::from_le_bytes(*amount) as i32)
+}
+
+fn range(generators: &Generators, generator: u16) -> Option<RangeInclusive<u8>> {
+    generators.get(&generator).map(|[low, high]| *low..=*high)
+}
+
+fn intersect(a: RangeInclusive<u8>, b: RangeInclusive<u8>) -> Option<RangeInclusive<u8>> {
+    let range = *a.start().max(b.start())..=*a.end().min(b.end());
+    (!range.is_empty()).then_some(range)
+}
+
+fn malformed(message: &str) -> SoundFontError {
+    SoundFontError::Malformed(message.to_string())
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// A RIFF chunk's id and contents.
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+/// The RIFF chunks directly inside `data`.
+fn chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, SoundFontError> {
+    let mut chunks = Vec::new();
+    let mut position = 0;
+    while position + 8 <= data.len() {
+        let id = &data[position..position + 4];
+        let size = u32_at(data, position + 4) as usize;
+        let contents = data
+            .get(position + 8..position + 8 + size)
+            .ok_or_else(|| malformed("chunk runs past the end of the file"))?;
+        chunks.push((id, contents));
+        // Chunks are padded to an even length.
+        position += 8 + size + size % 2;
+    }
+    Ok(chunks)
+}
+
+/// Splits a `pdta` sub-chunk into its fixed size records.
+fn records<'a>(
+    pdta: &HashMap<&[u8], &'a [u8]>,
+    id: &str,
+    size: usize,
+) -> Result<Vec<&'a [u8]>, SoundFontError> {
+    let data = pdta
+        .get(id.as_bytes())
+        .ok_or_else(|| SoundFontError::Malformed(format!("missing {id} chunk")))?;
+    Ok(data.chunks_exact(size).collect_vec())
+}
+
+fn record_name(record: &[u8]) -> String {
+    let name = &record[..20];
+    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..end]).trim().to_string()
+}
+
+/// The generators for each zone in `bags`, where `bags` indexes into the (bag, generator) lists.
+fn zone_generators(
+    bags: &[&[u8]],
+    generators: &[&[u8]],
+    bag_range: Range<usize>,
+) -> Result<Vec<Generators>, SoundFontError> {
+    bag_range
+        .map(|bag| {
+            let start = u16_at(
+                bags.get(bag).ok_or_else(|| malformed("bag out of range"))?,
+                0,
+            );
+            let end = u16_at(
+                bags.get(bag + 1)
+                    .ok_or_else(|| malformed("bag out of range"))?,
+                0,
+            );
+            Ok(generators
+                .get(start as usize..end as usize)
+                .ok_or_else(|| malformed("generator out of range"))?
+                .iter()
+                .map(|record| (u16_at(record, 0), [record[2], record[3]]))
+                .collect())
+        })
+        .collect()
+}
+
+/// Splits off the global zone, which is the first zone when it doesn't end in `terminal`. Its
+/// generators are the defaults for the rest.
+fn split_global(mut zones: Vec<Generators>, terminal: u16) -> (Generators, Vec<Generators>) {
+    if zones
+        .first()
+        .is_some_and(|zone| !zone.contains_key(&terminal))
+    {
+        let global = zones.remove(0);
+        (global, zones)
+    } else {
+        (Generators::new(), zones)
+    }
+}
+
+impl SoundFont {
+    /// Reads a `.sf2` file. See http://www.synthfont.com/sfspec24.pdf
+    pub fn parse(data: &[u8]) -> Result<Self, SoundFontError> {
+        let (_, riff) = chunks(data)?
+            .into_iter()
+            .find(|(id, _)| *id == b"RIFF")
+            .ok_or_else(|| malformed("not a RIFF file"))?;
+        if riff.get(..4) != Some(b"sfbk") {
+            return Err(malformed("not a SoundFont"));
+        }
+
+        let mut smpl = None;
+        let mut pdta = HashMap::new();
+        for (id, contents) in chunks(&riff[4..])? {
+            if id != b"LIST" || contents.len() < 4 {
+                continue;
+            }
+            let sub_chunks = chunks(&contents[4..])?;
+            match &contents[..4] {
+                b"sdta" => {
+                    smpl = sub_chunks
+                        .into_iter()
+                        .find(|(id, _)| *id == b"smpl")
+                        .map(|(_, data)| data)
+                }
+                b"pdta" => pdta.extend(sub_chunks),
+                _ => {}
+            }
+        }
+        let smpl = smpl.ok_or_else(|| malformed("no sample data"))?;
+        let sample_data = smpl
+            .chunks_exact(2)
+            .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+            .collect_vec();
+
+        let preset_headers = records(&pdta, "phdr", 38)?;
+        let preset_bags = records(&pdta, "pbag", 4)?;
+        let preset_generators = records(&pdta, "pgen", 4)?;
+        let instruments = records(&pdta, "inst", 22)?;
+        let instrument_bags = records(&pdta, "ibag", 4)?;
+        let instrument_generators = records(&pdta, "igen", 4)?;
+        let sample_headers = records(&pdta, "shdr", 46)?;
+
+        let mut samples = Vec::new();
+        // (start, end, sample rate) to index in `samples`, since presets often share samples.
+        let mut sample_indices = HashMap::new();
+        let mut presets = Vec::new();
+        // The last header of each list is a terminator, which only marks where the bags end.
+        for (header, next_header) in preset_headers.iter().tuple_windows() {
+            let bag_range = u16_at(header, 24) as usize..u16_at(next_header, 24) as usize;
+            let (preset_global, preset_zones) = split_global(
+                zone_generators(&preset_bags, &preset_generators, bag_range)?,
+                INSTRUMENT,
+            );
+
+            let mut zones = Vec::new();
+            for preset_zone in preset_zones {
+                let preset_zone = preset_global
+                    .iter()
+                    .chain(&preset_zone)
+                    .map(|(generator, amount)| (*generator, *amount))
+                    .collect::<Generators>();
+                let Some(instrument) = amount(&preset_zone, INSTRUMENT) else {
+                    continue;
+                };
+                let instrument = instrument as u16 as usize;
+                let (Some(instrument_header), Some(next_instrument_header)) =
+                    (instruments.get(instrument), instruments.get(instrument + 1))
+                else {
+                    return Err(malformed("instrument out of range"));
+                };
+                let bag_range = u16_at(instrument_header, 20) as usize
+                    ..u16_at(next_instrument_header, 20) as usize;
+                let (instrument_global, instrument_zones) = split_global(
+                    zone_generators(&instrument_bags, &instrument_generators, bag_range)?,
+                    SAMPLE_ID,
+                );
+
+                for instrument_zone in instrument_zones {
+                    let zone = instrument_global
+                        .iter()
+                        .chain(&instrument_zone)
+                        .map(|(generator, amount)| (*generator, *amount))
+                        .collect::<Generators>();
+                    let Some(sample_id) = amount(&zone, SAMPLE_ID) else {
+                        continue;
+                    };
+                    let header = sample_headers
+                        .get(sample_id as u16 as usize)
+                        .ok_or_else(|| malformed("sample out of range"))?;
+                    if u16_at(header, 44) & ROM_SAMPLE != 0 {
+                        continue;
+                    }
+
+                    let (Some(key_range), Some(velocity_range)) = (
+                        intersect(
+                            range(&zone, KEY_RANGE).unwrap_or(0..=127),
+                            range(&preset_zone, KEY_RANGE).unwrap_or(0..=127),
+                        ),
+                        intersect(
+                            range(&zone, VEL_RANGE).unwrap_or(0..=127),
+                            range(&preset_zone, VEL_RANGE).unwrap_or(0..=127),
+                        ),
+                    ) else {
+                        continue;
+                    };
+
+                    let offset = |fine, coarse| {
+                        amount(&zone, fine).unwrap_or(0) as i64
+                            + 32768 * amount(&zone, coarse).unwrap_or(0) as i64
+                    };
+                    let frame = |position: u32, fine, coarse| {
+                        (position as i64 + offset(fine, coarse)).clamp(0, sample_data.len() as i64)
+                            as usize
+                    };
+                    let start = frame(
+                        u32_at(header, 20),
+                        START_ADDRS_OFFSET,
+                        START_ADDRS_COARSE_OFFSET,
+                    );
+                    let end = frame(
+                        u32_at(header, 24),
+                        END_ADDRS_OFFSET,
+                        END_ADDRS_COARSE_OFFSET,
+                    )
+                    .max(start);
+                    if start == end {
+                        continue;
+                    }
+                    let sample_rate = u32_at(header, 36);
+
+                    // Modes 1 and 3 loop (3 plays on past the loop on release, but we just fade).
+                    let loop_frames = matches!(amount(&zone, SAMPLE_MODES), Some(1 | 3))
+                        .then(|| {
+                            let loop_start = frame(
+                                u32_at(header, 28),
+                                STARTLOOP_ADDRS_OFFSET,
+                                STARTLOOP_ADDRS_COARSE_OFFSET,
+                            );
+                            let loop_end = frame(
+                                u32_at(header, 32),
+                                ENDLOOP_ADDRS_OFFSET,
+                                ENDLOOP_ADDRS_COARSE_OFFSET,
+                            );
+                            (
+                                loop_start.saturating_sub(start),
+                                loop_end.saturating_sub(start),
+                            )
+                        })
+                        .filter(|(loop_start, loop_end)| {
+                            loop_start < loop_end && *loop_end <= end - start
+                        })
+                        .map(|(loop_start, loop_end)| (loop_start as u32, loop_end as u32));
+
+                    let original_pitch = match header[40] {
+                        // 255 means the sample is unpitched.
+                        pitch @ 0..=127 => pitch as i32,
+                        _ => 60,
+                    };
+                    let root_key = match amount(&zone, OVERRIDING_ROOT_KEY) {
+                        Some(root_key @ 0..=127) => root_key,
+                        _ => original_pitch,
+                    };
+                    // Tuning is additive between the preset and instrument levels.
+                    let tune_cents = 100
+                        * (amount(&zone, COARSE_TUNE).unwrap_or(0)
+                            + amount(&preset_zone, COARSE_TUNE).unwrap_or(0))
+                        + amount(&zone, FINE_TUNE).unwrap_or(0)
+                        + amount(&preset_zone, FINE_TUNE).unwrap_or(0)
+                        + header[41] as i8 as i32;
+
+                    let sample = *sample_indices
+                        .entry((start, end, sample_rate))
+                        .or_insert_with(|| {
+                            samples.push(Sf2Sample {
+                                frames: sample_data[start..end]
+                                    .iter()
+                                    .map(|frame| *frame as f32 / 32768.0)
+                                    .collect_vec(),
+                                sample_rate,
+                            });
+                            samples.len() - 1
+                        });
+                    zones.push((
+                        ZoneInfo {
+                            key_range,
+                            velocity_range,
+                            // Tuning a zone up plays the sample faster, which is the same as it
+                            // having been recorded that much lower.
+                            root_key: root_key as f64 - tune_cents as f64 / 100.0,
+                            loop_frames,
+                        },
+                        sample,
+                    ));
+                }
+            }
+
+            if !zones.is_empty() {
+                presets.push(Sf2Preset {
+                    name: record_name(header),
+                    zones,
+                });
+            }
+        }
+
+        if presets.is_empty() {
+            return Err(SoundFontError::Empty);
+        }
+        Ok(Self { presets, samples })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((contents.len() as u32).to_le_bytes());
+        chunk.extend(contents);
+        if contents.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(b"LIST", &[kind.to_vec(), chunks.concat()].concat())
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut record = name.as_bytes().to_vec();
+        record.resize(20, 0);
+        record
+    }
+
+    fn generator(generator: u16, amount: [u8; 2]) -> Vec<u8> {
+        [generator.to_le_bytes(), amount].concat()
+    }
+
+    fn signed(amount: i16) -> [u8; 2] {
+        amount.to_le_bytes()
+    }
+
+    fn preset_header(preset: &str, bag: u16) -> Vec<u8> {
+        [
+            name(preset),
+            vec![0; 4],
+            bag.to_le_bytes().to_vec(),
+            vec![0; 12],
+        ]
+        .concat()
+    }
+
+    fn instrument_header(instrument: &str, bag: u16) -> Vec<u8> {
+        [name(instrument), bag.to_le_bytes().to_vec()].concat()
+    }
+
+    fn sample_header(sample: &str, start: u32, end: u32, original_pitch: u8) -> Vec<u8> {
+        [
+            name(sample),
+            start.to_le_bytes().to_vec(),
+            end.to_le_bytes().to_vec(),
+            start.to_le_bytes().to_vec(),
+            end.to_le_bytes().to_vec(),
+            22050u32.to_le_bytes().to_vec(),
+            vec![original_pitch, 0],
+            vec![0; 4],
+        ]
+        .concat()
+    }
+
+    /// Two presets, "Choir" (which plays three zones of the "Ooh" instrument) and "Nothing" (which
+    /// has no zones), sharing two samples.
+    fn tiny_sound_font() -> Vec<u8> {
+        let smpl = (0..100i16)
+            .flat_map(|frame| frame.to_le_bytes())
+            .collect_vec();
+        let bags = |starts: &[u16]| {
+            starts
+                .iter()
+                .flat_map(|start| [start.to_le_bytes(), [0, 0]].concat())
+                .collect_vec()
+        };
+        let pdta = [
+            chunk(
+                b"phdr",
+                &[
+                    preset_header("Choir", 0),
+                    preset_header("Nothing", 1),
+                    preset_header("EOP", 1),
+                ]
+                .concat(),
+            ),
+            chunk(b"pbag", &bags(&[0, 2])),
+            chunk(b"pmod", &[0; 10]),
+            chunk(
+                b"pgen",
+                &[
+                    generator(KEY_RANGE, [40, 80]),
+                    generator(INSTRUMENT, signed(0)),
+                    generator(0, [0, 0]),
+                ]
+                .concat(),
+            ),
+            chunk(
+                b"inst",
+                &[instrument_header("Ooh", 0), instrument_header("EOI", 4)].concat(),
+            ),
+            chunk(b"ibag", &bags(&[0, 1, 4, 7, 11])),
+            chunk(b"imod", &[0; 10]),
+            chunk(
+                b"igen",
+                &[
+                    // The global zone.
+                    generator(FINE_TUNE, signed(-50)),
+                    generator(KEY_RANGE, [0, 64]),
+                    generator(VEL_RANGE, [0, 63]),
+                    generator(SAMPLE_ID, signed(0)),
+                    generator(KEY_RANGE, [65, 127]),
+                    generator(OVERRIDING_ROOT_KEY, signed(70)),
+                    generator(SAMPLE_ID, signed(1)),
+                    generator(VEL_RANGE, [64, 127]),
+                    generator(COARSE_TUNE, signed(1)),
+                    generator(FINE_TUNE, signed(0)),
+                    generator(SAMPLE_ID, signed(0)),
+                    generator(0, [0, 0]),
+                ]
+                .concat(),
+            ),
+            chunk(
+                b"shdr",
+                &[
+                    sample_header("Ooh C4", 0, 40, 60),
+                    sample_header("Ooh C5", 50, 90, 72),
+                    sample_header("EOS", 0, 0, 0),
+                ]
+                .concat(),
+            ),
+        ];
+        chunk(
+            b"RIFF",
+            &[
+                b"sfbk".to_vec(),
+                list(b"INFO", &[chunk(b"INAM", b"Tiny\0")]),
+                list(b"sdta", &[chunk(b"smpl", &smpl)]),
+                list(b"pdta", &pdta),
+            ]
+            .concat(),
+        )
+    }
+
+    #[test]
+    fn flattens_presets_into_zones() {
+        let sound_font = SoundFont::parse(&tiny_sound_font()).unwrap();
+
+        assert_eq!(
+            sound_font
+                .presets
+                .iter()
+                .map(|preset| &preset.name)
+                .collect_vec(),
+            vec!["Choir"]
+        );
+        assert_eq!(
+            sound_font.presets[0].zones,
+            vec![
+                (
+                    ZoneInfo {
+                        key_range: 40..=64,
+                        velocity_range: 0..=63,
+                        root_key: 60.5,
+                        loop_frames: None,
+                    },
+                    0
+                ),
+                (
+                    ZoneInfo {
+                        key_range: 65..=80,
+                        velocity_range: 0..=127,
+                        root_key: 70.5,
+                        loop_frames: None,
+                    },
+                    1
+                ),
+                (
+                    ZoneInfo {
+                        key_range: 40..=80,
+                        velocity_range: 64..=127,
+                        root_key: 59.0,
+                        loop_frames: None,
+                    },
+                    0
+                ),
+            ]
+        );
+
+        assert_eq!(sound_font.samples.len(), 2);
+        assert_eq!(sound_font.samples[1].sample_rate, 22050);
+        assert_eq!(sound_font.samples[1].frames.len(), 40);
+        assert_eq!(sound_font.samples[1].frames[0], 50.0 / 32768.0);
+    }
+
+    #[test]
+    fn rejects_files_that_arent_sound_fonts() {
+        assert!(matches!(
+            SoundFont::parse(&chunk(b"RIFF", b"WAVEfmt ")),
+            Err(SoundFontError::Malformed(_))
+        ));
+        let mut truncated = tiny_sound_font();
+        truncated.truncate(truncated.len() - 10);
+        assert!(matches!(
+            SoundFont::parse(&truncated),
+            Err(SoundFontError::Malformed(_))
+        ));
+    }
+}
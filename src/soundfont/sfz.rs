@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::{SoundFontError, ZoneInfo};
+
+/// One `<region>` of an SFZ instrument, with everything it inherits from its group filled in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SfzRegion {
+    /// Relative to the `.sfz` file, always with `/` separators.
+    pub sample: String,
+    pub zone: ZoneInfo,
+}
+
+/// The headers that opcodes can be set under, from the outermost in. Each level inherits the
+/// opcodes of the ones before it.
+const LEVELS: [&str; 4] = ["global", "master", "group", "region"];
+
+/// Reads the regions out of an `.sfz` file. See https://sfzformat.com/
+///
+/// Only the opcodes that decide which sample plays for which note are read, anything else (eg
+/// envelopes, filters and `#include`s) is ignored.
+pub fn parse_sfz(sfz: &str) -> Result<Vec<SfzRegion>, SoundFontError> {
+    static TOKEN_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"<(\w+)>|(\w+)=").unwrap());
+
+    let mut default_path = String::new();
+    // The opcodes in effect at each of `LEVELS`.
+    let mut levels: [HashMap<String, String>; 4] = Default::default();
+    // Which header we're under, or `None` for `<control>` and anything we don't know.
+    let mut current_level = None;
+    let mut regions = Vec::new();
+
+    for line in sfz.lines() {
+        let line = line.split("//").next().unwrap_or("");
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let tokens = TOKEN_RE.captures_iter(line).collect_vec();
+        for (index, token) in tokens.iter().enumerate() {
+            if let Some(header) = token.get(1) {
+                if current_level == Some(3) {
+                    regions.push(region(&levels, &default_path)?);
+                }
+                current_level = LEVELS.iter().position(|level| *level == header.as_str());
+                if let Some(level) = current_level {
+                    // Starting a new header clears it and everything nested inside it.
+                    for opcodes in &mut levels[level..] {
+                        opcodes.clear();
+                    }
+                }
+                continue;
+            }
+
+            let opcode = &token[2];
+            // Values run up to the next token, since sample paths can contain spaces.
+            let value_end = tokens
+                .get(index + 1)
+                .map(|next| next.get(0).unwrap().start())
+                .unwrap_or(line.len());
+            let value = line[token.get(0).unwrap().end()..value_end].trim();
+            match current_level {
+                Some(level) => {
+                    levels[level].insert(opcode.to_string(), value.to_string());
+                }
+                None if opcode == "default_path" => default_path = value.replace('\\', "/"),
+                None => {}
+            }
+        }
+    }
+    if current_level == Some(3) {
+        regions.push(region(&levels, &default_path)?);
+    }
+
+    if regions.is_empty() {
+        return Err(SoundFontError::Empty);
+    }
+    Ok(regions)
+}
+
+fn region(
+    levels: &[HashMap<String, String>; 4],
+    default_path: &str,
+) -> Result<SfzRegion, SoundFontError> {
+    let opcode = |name: &str| {
+        levels
+            .iter()
+            .rev()
+            .find_map(|opcodes| opcodes.get(name))
+            .map(String::as_str)
+    };
+    let number = |name: &str| -> Result<Option<f64>, SoundFontError> {
+        opcode(name)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    SoundFontError::Malformed(format!("expected a number for {name}, got {value}"))
+                })
+            })
+            .transpose()
+    };
+    let key = |name: &str| -> Result<Option<u8>, SoundFontError> {
+        opcode(name)
+            .map(|value| {
+                parse_key(value).ok_or_else(|| {
+                    SoundFontError::Malformed(format!("expected a key for {name}, got {value}"))
+                })
+            })
+            .transpose()
+    };
+
+    let sample = opcode("sample")
+        .ok_or_else(|| SoundFontError::Malformed("region has no sample".to_string()))?
+        .replace('\\', "/");
+    let sample = format!("{default_path}{sample}");
+
+    // `key` is shorthand for setting all three.
+    let single_key = key("key")?;
+    let low_key = key("lokey")?.or(single_key).unwrap_or(0);
+    let high_key = key("hikey")?.or(single_key).unwrap_or(127);
+    let center = key("pitch_keycenter")?.or(single_key).unwrap_or(60);
+    let low_velocity = number("lovel")?.unwrap_or(0.0) as u8;
+    let high_velocity = number("hivel")?.unwrap_or(127.0) as u8;
+    let tune_cents = number("tune")?.unwrap_or(0.0) + 100.0 * number("transpose")?.unwrap_or(0.0);
+
+    let loops = matches!(
+        opcode("loop_mode").or(opcode("loopmode")),
+        Some("loop_continuous" | "loop_sustain")
+    );
+    let loop_start = number("loop_start")?.or(number("loopstart")?);
+    let loop_end = number("loop_end")?.or(number("loopend")?);
+    let loop_frames = match (loops, loop_start, loop_end) {
+        (true, Some(start), Some(end)) if start < end => Some((start as u32, end as u32)),
+        _ => None,
+    };
+
+    Ok(SfzRegion {
+        sample,
+        zone: ZoneInfo {
+            key_range: low_key..=high_key,
+            velocity_range: low_velocity..=high_velocity,
+            // Tuning a region up plays the sample faster, which is the same as it having been
+            // recorded that much lower.
+            root_key: center as f64 - tune_cents / 100.0,
+            loop_frames,
+        },
+    })
+}
+
+/// SFZ keys can be MIDI numbers or note names, where `c4` is middle C (60).
+fn parse_key(value: &str) -> Option<u8> {
+    if let Ok(number) = value.parse::<u8>() {
+        return Some(number);
+    }
+    let value = value.to_lowercase();
+    let mut chars = value.chars();
+    let pitch_class = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next()? {
+        '#' | '♯' => (1, &rest[rest.chars().next()?.len_utf8()..]),
+        'b' | '♭' => (-1, &rest[rest.chars().next()?.len_utf8()..]),
+        _ => (0, rest),
+    };
+    let octave = octave.parse::<i32>().ok()?;
+    u8::try_from((octave + 1) * 12 + pitch_class + accidental).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_inherit_from_their_group_and_global() {
+        let sfz = r"
+            <control> default_path=samples\
+            // Everything is a little flat.
+            <global> lovel=10 hivel=120 loop_mode=loop_continuous tune=-50
+            <group> key=c4 loop_start=10 loop_end=100
+            <region> sample=ooh c4.wav
+            <region> sample=ooh d4.wav lokey=61 hikey=64 pitch_keycenter=d4 hivel=64
+            <group> lokey=65 hikey=127
+            <region> sample=aah.wav transpose=1 tune=0
+        ";
+        assert_eq!(
+            parse_sfz(sfz).unwrap(),
+            vec![
+                SfzRegion {
+                    sample: "samples/ooh c4.wav".to_string(),
+                    zone: ZoneInfo {
+                        key_range: 60..=60,
+                        velocity_range: 10..=120,
+                        root_key: 60.5,
+                        loop_frames: Some((10, 100)),
+                    },
+                },
+                SfzRegion {
+                    sample: "samples/ooh d4.wav".to_string(),
+                    zone: ZoneInfo {
+                        key_range: 61..=64,
+                        velocity_range: 10..=64,
+                        root_key: 62.5,
+                        loop_frames: Some((10, 100)),
+                    },
+                },
+                // A new group doesn't keep anything from the last one.
+                SfzRegion {
+                    sample: "samples/aah.wav".to_string(),
+                    zone: ZoneInfo {
+                        key_range: 65..=127,
+                        velocity_range: 10..=120,
+                        root_key: 59.0,
+                        loop_frames: None,
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_instruments_with_nothing_to_play() {
+        assert!(matches!(
+            parse_sfz("<group> key=60"),
+            Err(SoundFontError::Empty)
+        ));
+        assert!(matches!(
+            parse_sfz("<region> key=60"),
+            Err(SoundFontError::Malformed(_))
+        ));
+        assert!(matches!(
+            parse_sfz("<region> sample=a.wav lokey=h2"),
+            Err(SoundFontError::Malformed(_))
+        ));
+    }
+}
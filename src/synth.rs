@@ -0,0 +1,152 @@
+use wasm_bindgen::JsValue;
+use web_sys::AudioNode;
+
+use crate::instrument::{Instrument, PlaybackGuard};
+
+/// How fast the vibrato wavers, in Hz. About what a singer does.
+const VIBRATO_HZ: f32 = 5.5;
+
+/// Synth notes hold at their sustain level rather than decaying like the piano, so they're pulled
+/// down to sit at a similar loudness.
+const PEAK_GAIN: f32 = 0.3;
+
+/// An ADSR envelope. Times are in seconds and `sustain` is a fraction of the peak level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f32,
+    pub release: f64,
+}
+
+impl Envelope {
+    /// How loud a note peaking at `peak` is `elapsed` seconds after it starts, while it's held.
+    pub fn level_at(&self, peak: f32, elapsed: f64) -> f32 {
+        let sustain_level = peak * self.sustain;
+        if elapsed < 0.0 {
+            0.0
+        } else if elapsed < self.attack {
+            peak * (elapsed / self.attack) as f32
+        } else if elapsed < self.attack + self.decay {
+            peak + (sustain_level - peak) * ((elapsed - self.attack) / self.decay) as f32
+        } else {
+            sustain_level
+        }
+    }
+}
+
+/// Everything that decides what a `Synth` sounds like.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SynthPatch {
+    /// The relative amplitude of each harmonic, starting with the fundamental.
+    pub harmonics: &'static [f32],
+    pub envelope: Envelope,
+    /// How far the pitch wavers either way, in cents. 0 for a steady tone.
+    pub vibrato_cents: f32,
+}
+
+/// An additive synth, which sustains for as long as the note is held. Good for hearing where the
+/// voices sit in long chords, which the piano doesn't do well.
+#[derive(Debug)]
+pub struct Synth {
+    patch: SynthPatch,
+}
+
+impl Synth {
+    pub fn new(patch: SynthPatch) -> Self {
+        Self { patch }
+    }
+}
+
+impl Instrument for Synth {
+    fn start_note(
+        &self,
+        frequency: f64,
+        gain: f32,
+        output_node: &AudioNode,
+        when: f64,
+    ) -> Result<PlaybackGuard, JsValue> {
+        let ctx = output_node.context();
+        // The envelope has to be scheduled from when the note actually starts.
+        let when = when.max(ctx.current_time());
+        let SynthPatch {
+            harmonics,
+            envelope,
+            vibrato_cents,
+        } = self.patch;
+
+        // Index 0 of a periodic wave is the DC offset, the harmonics start from 1.
+        let mut real = vec![0.0; harmonics.len() + 1];
+        let mut imag = [0.0]
+            .into_iter()
+            .chain(harmonics.iter().copied())
+            .collect::<Vec<_>>();
+        let wave = ctx.create_periodic_wave(&mut real, &mut imag)?;
+        let oscillator = ctx.create_oscillator()?;
+        oscillator.set_periodic_wave(&wave);
+        oscillator.frequency().set_value(frequency as f32);
+
+        let peak_gain = PEAK_GAIN * gain;
+        let sustain_level = peak_gain * envelope.sustain;
+        let gain = ctx.create_gain()?;
+        gain.gain().set_value_at_time(0.0, when)?;
+        gain.gain()
+            .linear_ramp_to_value_at_time(peak_gain, when + envelope.attack)?;
+        gain.gain()
+            .linear_ramp_to_value_at_time(sustain_level, when + envelope.attack + envelope.decay)?;
+
+        oscillator.connect_with_audio_node(&gain)?;
+        gain.connect_with_audio_node(output_node)?;
+
+        let mut sources = vec![oscillator.clone().into()];
+        if vibrato_cents > 0.0 {
+            let vibrato = ctx.create_oscillator()?;
+            vibrato.frequency().set_value(VIBRATO_HZ);
+            let depth = ctx.create_gain()?;
+            depth.gain().set_value(vibrato_cents);
+            vibrato.connect_with_audio_node(&depth)?;
+            depth.connect_with_audio_param(&oscillator.detune())?;
+            vibrato.start_with_when(when)?;
+            sources.push(vibrato.into());
+        }
+        oscillator.start_with_when(when)?;
+
+        Ok(PlaybackGuard::new(
+            ctx, sources, gain, when, peak_gain, envelope,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_ramps_up_then_down_to_sustain() {
+        let envelope = Envelope {
+            attack: 0.1,
+            decay: 0.2,
+            sustain: 0.5,
+            release: 0.3,
+        };
+        let level_at = |elapsed: f64| (envelope.level_at(0.8, elapsed) * 1000.0).round();
+        assert_eq!(level_at(-0.1), 0.0);
+        assert_eq!(level_at(0.05), 400.0);
+        assert_eq!(level_at(0.1), 800.0);
+        assert_eq!(level_at(0.2), 600.0);
+        assert_eq!(level_at(0.3), 400.0);
+        assert_eq!(level_at(10.0), 400.0);
+    }
+
+    #[test]
+    fn envelope_without_attack_or_decay_holds_steady() {
+        let envelope = Envelope {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.3,
+        };
+        assert_eq!(envelope.level_at(0.7, 0.0), 0.7);
+        assert_eq!(envelope.level_at(0.7, 5.0), 0.7);
+    }
+}
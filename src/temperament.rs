@@ -0,0 +1,483 @@
+use std::fmt::{Display, Formatter};
+
+use itertools::Itertools;
+
+/// The pitch of A4 that everything (including our samples) is normally tuned to.
+pub const STANDARD_A4_HZ: f64 = 440.0;
+
+const A4_MIDI_NOTE: u32 = 69;
+
+/// Historical 12-note temperaments, as cents above C for each pitch class.
+pub const HISTORICAL_TEMPERAMENTS: &[(&str, [f64; 12])] = &[
+    (
+        "Pythagorean",
+        [
+            0.0, 113.685, 203.910, 294.135, 407.820, 498.045, 611.730, 701.955, 815.640, 905.865,
+            996.090, 1109.775,
+        ],
+    ),
+    (
+        "Quarter-comma meantone",
+        [
+            0.0, 76.049, 193.157, 310.265, 386.314, 503.422, 579.471, 696.578, 772.627, 889.735,
+            1006.843, 1082.892,
+        ],
+    ),
+    (
+        "Werckmeister III",
+        [
+            0.0, 90.225, 192.180, 294.135, 390.225, 498.045, 588.270, 696.090, 792.180, 888.270,
+            996.090, 1092.180,
+        ],
+    ),
+    (
+        "Kirnberger III",
+        [
+            0.0, 90.225, 193.157, 294.135, 386.314, 498.045, 590.224, 696.578, 792.180, 889.735,
+            996.090, 1088.269,
+        ],
+    ),
+    (
+        "Vallotti",
+        [
+            0.0, 94.135, 196.090, 298.045, 392.180, 501.955, 592.180, 698.045, 796.090, 894.135,
+            1000.000, 1090.225,
+        ],
+    ),
+];
+
+/// The frequency of a (possibly fractional) MIDI note in 12-tone equal temperament.
+pub fn equal_tempered_frequency(midi_note: f64, a4_hz: f64) -> f64 {
+    a4_hz * 2f64.powf((midi_note - A4_MIDI_NOTE as f64) / 12.0)
+}
+
+/// The (possibly fractional) MIDI note that sounds at the given frequency when A4 is 440Hz.
+pub fn midi_note_for_frequency(frequency: f64) -> f64 {
+    A4_MIDI_NOTE as f64 + 12.0 * (frequency / STANDARD_A4_HZ).log2()
+}
+
+#[derive(Debug)]
+pub enum ScalaError {
+    /// The file ended before everything it promised was there.
+    Truncated,
+    /// A line we couldn't make sense of, with a description of what we expected.
+    Malformed(String),
+}
+
+impl Display for ScalaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScalaError::Truncated => write!(f, "Scala file ended early"),
+            ScalaError::Malformed(message) => write!(f, "Malformed Scala file: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ScalaError {}
+
+/// How MIDI notes are laid onto the scale, as described by a Scala `.kbm` file.
+#[derive(Clone, Debug, PartialEq)]
+struct KeyboardMapping {
+    first_note: u32,
+    last_note: u32,
+    /// The MIDI note which plays the scale's first degree.
+    middle_note: u32,
+    /// The MIDI note whose frequency is given directly, all others are tuned relative to it.
+    reference_note: u32,
+    /// The frequency of `reference_note`, assuming A4 is at `STANDARD_A4_HZ`.
+    reference_frequency: f64,
+    /// Which scale degree each key in the repeating pattern plays, `None` for keys that are left
+    /// silent. Empty means every key plays the next degree up.
+    degrees: Vec<Option<u32>>,
+    /// The scale degree that the pattern repeats at, or 0 to repeat at the scale's own period.
+    octave_degree: u32,
+}
+
+impl Default for KeyboardMapping {
+    /// Degree 0 on middle C, with A4 at concert pitch. This is what Scala itself assumes when
+    /// there isn't a `.kbm` file.
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: A4_MIDI_NOTE,
+            reference_frequency: STANDARD_A4_HZ,
+            degrees: Vec::new(),
+            octave_degree: 0,
+        }
+    }
+}
+
+/// A tuning for every key, either one of the `HISTORICAL_TEMPERAMENTS` or loaded from a Scala
+/// `.scl` file (and optionally `.kbm` file).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Temperament {
+    pub name: String,
+    /// Cents above the first degree for each degree after it, with the interval the scale repeats
+    /// at (usually 1200) last. This is the same layout as a `.scl` file.
+    pitches: Vec<f64>,
+    mapping: KeyboardMapping,
+}
+
+impl Temperament {
+    /// A 12-note temperament given as cents above C, with A4 at the reference pitch.
+    pub fn from_cents_above_c(name: &str, cents: &[f64; 12]) -> Self {
+        Self {
+            name: name.to_string(),
+            pitches: cents[1..].iter().copied().chain([1200.0]).collect_vec(),
+            mapping: KeyboardMapping::default(),
+        }
+    }
+
+    /// Reads a Scala `.scl` scale. See https://www.huygens-fokker.org/scala/scl_format.html
+    pub fn from_scala(scl: &str) -> Result<Self, ScalaError> {
+        let mut lines = scala_lines(scl);
+        let description = lines.next().ok_or(ScalaError::Truncated)?;
+        let count = parse_count(lines.next().ok_or(ScalaError::Truncated)?)?;
+        if count == 0 {
+            return Err(ScalaError::Malformed(
+                "scale needs at least one pitch".to_string(),
+            ));
+        }
+        let pitches = lines
+            .take(count)
+            .map(parse_pitch)
+            .collect::<Result<Vec<_>, _>>()?;
+        if pitches.len() < count {
+            return Err(ScalaError::Truncated);
+        }
+
+        Ok(Self {
+            name: description.trim().to_string(),
+            pitches,
+            mapping: KeyboardMapping::default(),
+        })
+    }
+
+    /// Lays the scale onto the keyboard according to a Scala `.kbm` file, rather than starting
+    /// from middle C. See https://www.huygens-fokker.org/scala/help.htm#mappings
+    pub fn with_keyboard_mapping(mut self, kbm: &str) -> Result<Self, ScalaError> {
+        let mut lines = scala_lines(kbm);
+        let mut next = || lines.next().ok_or(ScalaError::Truncated);
+        let size = parse_count(next()?)?;
+        let first_note = parse_count(next()?)? as u32;
+        let last_note = parse_count(next()?)? as u32;
+        let middle_note = parse_count(next()?)? as u32;
+        let reference_note = parse_count(next()?)? as u32;
+        let reference_frequency = first_token(next()?)
+            .parse::<f64>()
+            .ok()
+            .filter(|frequency| *frequency > 0.0)
+            .ok_or_else(|| ScalaError::Malformed("expected a reference frequency".to_string()))?;
+        let octave_degree = parse_count(next()?)? as u32;
+        // Scala lets the mapping stop early, the remaining keys are unmapped.
+        let degrees = (0..size)
+            .map(|_| match lines.next().map(first_token) {
+                None | Some("x") => Ok(None),
+                Some(degree) => degree.parse().map(Some).map_err(|_| {
+                    ScalaError::Malformed(format!("expected a scale degree, got {degree:?}"))
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.mapping = KeyboardMapping {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            degrees,
+            octave_degree,
+        };
+        if self.cents_above_middle_note(reference_note).is_none() {
+            return Err(ScalaError::Malformed(
+                "reference note isn't mapped to a scale degree".to_string(),
+            ));
+        }
+        Ok(self)
+    }
+
+    /// The frequency that the given MIDI note should sound at, with A4 moved to `a4_hz`. `None`
+    /// for keys that the temperament leaves unmapped.
+    pub fn frequency(&self, midi_note: u32, a4_hz: f64) -> Option<f64> {
+        let mapping = &self.mapping;
+        if midi_note < mapping.first_note || midi_note > mapping.last_note {
+            return None;
+        }
+        let cents = self.cents_above_middle_note(midi_note)?
+            - self.cents_above_middle_note(mapping.reference_note)?;
+        let reference_frequency = mapping.reference_frequency * a4_hz / STANDARD_A4_HZ;
+        Some(reference_frequency * 2f64.powf(cents / 1200.0))
+    }
+
+    fn cents_above_middle_note(&self, midi_note: u32) -> Option<f64> {
+        let keys = midi_note as i64 - self.mapping.middle_note as i64;
+        let degrees = &self.mapping.degrees;
+        if degrees.is_empty() {
+            return Some(self.cents_for_degree(keys));
+        }
+        let octave = keys.div_euclid(degrees.len() as i64);
+        let degree = degrees[keys.rem_euclid(degrees.len() as i64) as usize]?;
+        let octave_degree = match self.mapping.octave_degree {
+            0 => self.pitches.len() as i64,
+            octave_degree => octave_degree as i64,
+        };
+        Some(
+            octave as f64 * self.cents_for_degree(octave_degree)
+                + self.cents_for_degree(degree as i64),
+        )
+    }
+
+    /// Cents above the first degree for any degree, including ones past the end of the scale or
+    /// below it.
+    fn cents_for_degree(&self, degree: i64) -> f64 {
+        let count = self.pitches.len() as i64;
+        let period = self.pitches[self.pitches.len() - 1];
+        let within_period = match degree.rem_euclid(count) {
+            0 => 0.0,
+            index => self.pitches[index as usize - 1],
+        };
+        degree.div_euclid(count) as f64 * period + within_period
+    }
+}
+
+/// The meaningful lines of a Scala file, ie everything but comments.
+fn scala_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents.lines().filter(|line| !line.starts_with('!'))
+}
+
+/// Scala ignores anything after the value on a line, so it can be used for labels.
+fn first_token(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_count(line: &str) -> Result<usize, ScalaError> {
+    let token = first_token(line);
+    token
+        .parse()
+        .map_err(|_| ScalaError::Malformed(format!("expected a whole number, got {token:?}")))
+}
+
+/// Pitches with a `.` are in cents, anything else is a ratio (or a whole number).
+fn parse_pitch(line: &str) -> Result<f64, ScalaError> {
+    let token = first_token(line);
+    let malformed = || ScalaError::Malformed(format!("expected cents or a ratio, got {token:?}"));
+    if token.contains('.') {
+        return token.parse().map_err(|_| malformed());
+    }
+    let (numerator, denominator) = token.split_once('/').unwrap_or((token, "1"));
+    let numerator = numerator.parse::<f64>().map_err(|_| malformed())?;
+    let denominator = denominator.parse::<f64>().map_err(|_| malformed())?;
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return Err(malformed());
+    }
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Equal tempered frequencies at A4 = 440Hz for every MIDI note, from the standard tables.
+    const EQUAL_TEMPERED_HZ: [f64; 128] = [
+        8.1758, 8.6620, 9.1770, 9.7227, 10.3009, 10.9134, 11.5623, 12.2499, 12.9783, 13.7500,
+        14.5676, 15.4339, 16.3516, 17.3239, 18.3540, 19.4454, 20.6017, 21.8268, 23.1247, 24.4997,
+        25.9565, 27.5000, 29.1352, 30.8677, 32.7032, 34.6478, 36.7081, 38.8909, 41.2034, 43.6535,
+        46.2493, 48.9994, 51.9131, 55.0000, 58.2705, 61.7354, 65.4064, 69.2957, 73.4162, 77.7817,
+        82.4069, 87.3071, 92.4986, 97.9989, 103.8262, 110.0000, 116.5409, 123.4708, 130.8128,
+        138.5913, 146.8324, 155.5635, 164.8138, 174.6141, 184.9972, 195.9977, 207.6523, 220.0000,
+        233.0819, 246.9417, 261.6256, 277.1826, 293.6648, 311.1270, 329.6276, 349.2282, 369.9944,
+        391.9954, 415.3047, 440.0000, 466.1638, 493.8833, 523.2511, 554.3653, 587.3295, 622.2540,
+        659.2551, 698.4565, 739.9888, 783.9909, 830.6094, 880.0000, 932.3275, 987.7666, 1046.5023,
+        1108.7305, 1174.6591, 1244.5079, 1318.5102, 1396.9129, 1479.9777, 1567.9817, 1661.2188,
+        1760.0000, 1864.6550, 1975.5332, 2093.0045, 2217.4610, 2349.3181, 2489.0159, 2637.0205,
+        2793.8259, 2959.9554, 3135.9635, 3322.4376, 3520.0000, 3729.3101, 3951.0664, 4186.0090,
+        4434.9221, 4698.6363, 4978.0317, 5274.0409, 5587.6517, 5919.9108, 6271.9270, 6644.8752,
+        7040.0000, 7458.6202, 7902.1328, 8372.0181, 8869.8442, 9397.2726, 9956.0635, 10548.0818,
+        11175.3034, 11839.8215, 12543.8540,
+    ];
+
+    const TWELVE_TONE_EQUAL_SCL: &str = "! 12-tet.scl
+!
+12 tone equal temperament
+ 12
+!
+ 100.0
+ 200.
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    fn assert_close(actual: f64, expected: f64, note: u32) {
+        // The table is rounded to 4 decimal places.
+        assert!(
+            (actual - expected).abs() < 0.0001,
+            "MIDI note {note}: expected {expected}Hz, got {actual}Hz"
+        );
+    }
+
+    #[test]
+    fn equal_temperament_matches_reference_table() {
+        for (note, &expected) in EQUAL_TEMPERED_HZ.iter().enumerate() {
+            let note = note as u32;
+            assert_close(
+                equal_tempered_frequency(note as f64, STANDARD_A4_HZ),
+                expected,
+                note,
+            );
+        }
+    }
+
+    #[test]
+    fn reference_pitch_scales_every_note() {
+        for a4_hz in [415.0, 442.0] {
+            for (note, &expected) in EQUAL_TEMPERED_HZ.iter().enumerate() {
+                let note = note as u32;
+                let expected = expected * a4_hz / STANDARD_A4_HZ;
+                let actual = equal_tempered_frequency(note as f64, a4_hz);
+                assert!(
+                    (actual - expected).abs() < 0.0001 * a4_hz / STANDARD_A4_HZ + 1e-9,
+                    "MIDI note {note} at A4 = {a4_hz}: expected {expected}Hz, got {actual}Hz"
+                );
+            }
+        }
+        assert_eq!(equal_tempered_frequency(69.0, 442.0), 442.0);
+        assert_eq!(equal_tempered_frequency(57.0, 415.0), 207.5);
+    }
+
+    #[test]
+    fn midi_note_for_frequency_inverts_equal_temperament() {
+        for (note, &frequency) in EQUAL_TEMPERED_HZ.iter().enumerate() {
+            assert!((midi_note_for_frequency(frequency) - note as f64).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn equal_scala_file_matches_reference_table() {
+        let temperament = Temperament::from_scala(TWELVE_TONE_EQUAL_SCL).unwrap();
+        assert_eq!(temperament.name, "12 tone equal temperament");
+        for (note, &expected) in EQUAL_TEMPERED_HZ.iter().enumerate() {
+            let note = note as u32;
+            assert_close(
+                temperament.frequency(note, STANDARD_A4_HZ).unwrap(),
+                expected,
+                note,
+            );
+            assert_close(
+                temperament.frequency(note, 415.0).unwrap(),
+                expected * 415.0 / STANDARD_A4_HZ,
+                note,
+            );
+        }
+    }
+
+    #[test]
+    fn historical_temperaments_keep_a_at_reference_pitch() {
+        for (name, cents) in HISTORICAL_TEMPERAMENTS {
+            let temperament = Temperament::from_cents_above_c(name, cents);
+            for note in (0..128).filter(|note| note % 12 == 9) {
+                assert_close(
+                    temperament.frequency(note, 442.0).unwrap(),
+                    equal_tempered_frequency(note as f64, 442.0),
+                    note,
+                );
+            }
+            // Octaves are always pure.
+            for note in 0..116 {
+                let low = temperament.frequency(note, STANDARD_A4_HZ).unwrap();
+                let high = temperament.frequency(note + 12, STANDARD_A4_HZ).unwrap();
+                assert!((high / low - 2.0).abs() < 1e-9, "{name}, MIDI note {note}");
+            }
+        }
+    }
+
+    #[test]
+    fn quarter_comma_meantone_has_pure_thirds() {
+        let (name, cents) = HISTORICAL_TEMPERAMENTS[1];
+        let temperament = Temperament::from_cents_above_c(name, &cents);
+        // C4 -> E4
+        let c = temperament.frequency(60, STANDARD_A4_HZ).unwrap();
+        let e = temperament.frequency(64, STANDARD_A4_HZ).unwrap();
+        assert!((e / c - 5.0 / 4.0).abs() < 1e-5);
+        // A4 stays at 440, so C4 is a meantone major sixth below it.
+        assert_close(c, 440.0 / 2f64.powf(889.735 / 1200.0), 60);
+    }
+
+    #[test]
+    fn scala_ratios_and_cents() {
+        let temperament =
+            Temperament::from_scala("Just major triad\n3\n5/4 major third\n701.955\n2\n").unwrap();
+        assert_eq!(temperament.pitches.len(), 3);
+        assert!((temperament.pitches[0] - 386.3137).abs() < 0.0001);
+        assert!((temperament.pitches[2] - 1200.0).abs() < 1e-9);
+        assert!(Temperament::from_scala("Broken\n2\n100.0\n").is_err());
+        assert!(Temperament::from_scala("Broken\n1\n-3/2\n").is_err());
+    }
+
+    #[test]
+    fn keyboard_mapping() {
+        // Only the white keys play, each one step of the 7 note scale, with C4 = 256Hz.
+        let scale = Temperament::from_scala(
+            "7 note equal\n7\n171.429\n342.857\n514.286\n685.714\n857.143\n1028.571\n2/1\n",
+        )
+        .unwrap();
+        let kbm = "! white keys
+12
+0
+127
+60
+60
+256.0
+7
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+        let temperament = scale.with_keyboard_mapping(kbm).unwrap();
+        assert_close(
+            temperament.frequency(60, STANDARD_A4_HZ).unwrap(),
+            256.0,
+            60,
+        );
+        assert_close(
+            temperament.frequency(72, STANDARD_A4_HZ).unwrap(),
+            512.0,
+            72,
+        );
+        assert_close(
+            temperament.frequency(48, STANDARD_A4_HZ).unwrap(),
+            128.0,
+            48,
+        );
+        assert_close(
+            temperament.frequency(62, STANDARD_A4_HZ).unwrap(),
+            256.0 * 2f64.powf(171.429 / 1200.0),
+            62,
+        );
+        assert_eq!(temperament.frequency(61, STANDARD_A4_HZ), None);
+        // The reference pitch setting still moves everything.
+        assert_close(
+            temperament.frequency(60, 442.0).unwrap(),
+            256.0 * 442.0 / 440.0,
+            60,
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use fraction::Fraction;
+use itertools::Itertools;
+
+use crate::song_data::{Jump, Measure, SliceNote, SongData, TempoChange, TimeSignature, TimeSlice};
+
+/// Which order to step through the song's measures in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PlaybackOrder {
+    /// Straight through, top to bottom, the way it's printed.
+    #[default]
+    Written,
+    /// Following repeats, endings, D.C./D.S. and codas, the way it would be sung.
+    Performed,
+}
+
+/// Guards against malformed markings sending us round in circles.
+const MAX_PASSES_THROUGH_SCORE: usize = 16;
+
+/// Works out which measures are played, in order, when following the score's repeats and jumps.
+///
+/// Follows the usual conventions: D.C./D.S. are only taken once, and once taken, repeats are
+/// skipped (playing the last ending) and "To Coda"/"Fine" are obeyed.
+pub fn performance_order(measures: &[Measure]) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut index = 0;
+    let mut repeat_start = 0;
+    // Which time through the current repeated section we're on, starting at 1.
+    let mut pass = 1;
+    let mut repeating = false;
+    let mut jumped = false;
+
+    while let Some(measure) = measures.get(index) {
+        if order.len() > measures.len() * MAX_PASSES_THROUGH_SCORE {
+            break;
+        }
+        if measure.repeat_start && !repeating {
+            repeat_start = index;
+            pass = 1;
+        }
+        repeating = false;
+
+        if !measure.ending_numbers.is_empty() {
+            // After a jump we go straight to the last ending.
+            let pass = if jumped {
+                last_ending_number(measures, index)
+            } else {
+                pass
+            };
+            if !measure.ending_numbers.contains(&pass) {
+                index += 1;
+                continue;
+            }
+        }
+
+        order.push(index);
+
+        if jumped && measure.fine {
+            break;
+        }
+        if jumped && measure.to_coda {
+            if let Some(coda) = (index + 1..measures.len()).find(|&i| measures[i].coda) {
+                index = coda;
+                continue;
+            }
+        }
+        if let (Some(times), false) = (measure.repeat_end, jumped) {
+            if pass < times {
+                pass += 1;
+                repeating = true;
+                index = repeat_start;
+                continue;
+            }
+        }
+        let leaving_ending = !measure.ending_numbers.is_empty()
+            && measures
+                .get(index + 1)
+                .is_none_or(|next| next.ending_numbers.is_empty());
+        if measure.repeat_end.is_some() || leaving_ending {
+            // Done with this repeated section, anything after it starts fresh.
+            repeat_start = index + 1;
+            pass = 1;
+        }
+        if let (Some(jump), false) = (measure.jump, jumped) {
+            jumped = true;
+            index = match jump {
+                Jump::DaCapo => 0,
+                Jump::DalSegno => measures.iter().position(|m| m.segno).unwrap_or(0),
+            };
+            continue;
+        }
+        index += 1;
+    }
+
+    order
+}
+
+/// The highest ending number in the run of ending measures that `index` is part of.
+fn last_ending_number(measures: &[Measure], index: usize) -> u32 {
+    let has_ending = |m: &&Measure| !m.ending_numbers.is_empty();
+    measures[..index]
+        .iter()
+        .rev()
+        .take_while(has_ending)
+        .chain(measures[index..].iter().take_while(has_ending))
+        .flat_map(|m| m.ending_numbers.iter().copied())
+        .max()
+        .unwrap_or(1)
+}
+
+impl SongData {
+    /// The song laid out in the given order. Slices keep their `cursor_index`, so in performed
+    /// order the same cursor position can show up more than once.
+    pub fn in_order(&self, order: PlaybackOrder) -> SongData {
+        match order {
+            PlaybackOrder::Written => self.clone(),
+            PlaybackOrder::Performed => self.performed(),
+        }
+    }
+
+    fn performed(&self) -> SongData {
+        let order = performance_order(&self.measures);
+        if order.iter().copied().eq(0..self.measures.len()) {
+            return self.clone();
+        }
+
+        let mut slices = Vec::new();
+        let mut tempo_changes: Vec<TempoChange> = Vec::new();
+        let mut time_signatures: Vec<TimeSignature> = Vec::new();
+        let mut measures = Vec::new();
+        let mut end_timestamp = Fraction::from(0);
+        let mut position = Fraction::from(0);
+        for (order_index, &measure_index) in order.iter().enumerate() {
+            let measure = &self.measures[measure_index];
+            let measure_end = measure.timestamp + measure.duration;
+            let to_performed = |timestamp: Fraction| timestamp - measure.timestamp + position;
+            // Notes can only be held across the barline if we're going to/from the measure
+            // they're tied to.
+            let follows_written = match order_index.checked_sub(1) {
+                Some(previous) => order[previous] + 1 == measure_index,
+                None => measure_index == 0,
+            };
+            let leads_into_written = order
+                .get(order_index + 1)
+                .is_none_or(|&next| next == measure_index + 1);
+
+            let measure_slices = self
+                .slices
+                .iter()
+                .filter(|s| s.timestamp >= measure.timestamp && s.timestamp < measure_end)
+                .collect_vec();
+            let first_onset = measure_slices.first().map(|s| s.timestamp);
+            for slice in measure_slices {
+                let notes_by_voice = slice
+                    .notes_by_voice
+                    .iter()
+                    .map(|notes| {
+                        notes
+                            .iter()
+                            .map(|note| {
+                                let onset = match first_onset {
+                                    Some(first_onset)
+                                        if note.onset < measure.timestamp && !follows_written =>
+                                    {
+                                        // Restrike it rather than leaving a hole.
+                                        first_onset
+                                    }
+                                    _ => note.onset,
+                                };
+                                let end = if leads_into_written {
+                                    note.end_timestamp
+                                } else {
+                                    note.end_timestamp.min(measure_end)
+                                };
+                                end_timestamp = end_timestamp.max(to_performed(end));
+                                SliceNote {
+                                    pitch: note.pitch,
+                                    onset: to_performed(onset),
+                                    end_timestamp: to_performed(end),
+                                }
+                            })
+                            .collect_vec()
+                    })
+                    .collect_vec();
+                slices.push(TimeSlice {
+                    timestamp: to_performed(slice.timestamp),
+                    notes_by_voice,
+                    cursor_index: slice.cursor_index,
+                });
+            }
+
+            // Restate the tempo and meter at the start of each measure since we may have jumped
+            // here from somewhere else, then copy over any changes partway through.
+            if let Some(bpm) = self.tempo_at(measure.timestamp) {
+                if tempo_changes.last().map(|tc| tc.bpm) != Some(bpm) {
+                    tempo_changes.push(TempoChange {
+                        timestamp: position,
+                        bpm,
+                    });
+                }
+            }
+            for tempo_change in self
+                .tempo_changes
+                .iter()
+                .filter(|tc| tc.timestamp > measure.timestamp && tc.timestamp < measure_end)
+            {
+                tempo_changes.push(TempoChange {
+                    timestamp: to_performed(tempo_change.timestamp),
+                    bpm: tempo_change.bpm,
+                });
+            }
+            if let Some(time_signature) = self
+                .time_signatures
+                .iter()
+                .take_while(|ts| ts.timestamp <= measure.timestamp)
+                .last()
+            {
+                let signature = (time_signature.numerator, time_signature.denominator);
+                if time_signatures
+                    .last()
+                    .map(|ts| (ts.numerator, ts.denominator))
+                    != Some(signature)
+                {
+                    time_signatures.push(TimeSignature {
+                        timestamp: position,
+                        numerator: signature.0,
+                        denominator: signature.1,
+                    });
+                }
+            }
+
+            measures.push(Measure {
+                timestamp: position,
+                duration: measure.duration,
+                ..Default::default()
+            });
+            position += measure.duration;
+        }
+
+        SongData {
+            voice_index_mapping: self.voice_index_mapping.clone(),
+            voice_names: self.voice_names.clone(),
+            slices,
+            end_timestamp: end_timestamp.max(position),
+            tempo_changes,
+            time_signatures,
+            measures,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_a_simple_repeat_twice() {
+        let measures = [
+            Measure::default(),
+            Measure {
+                repeat_start: true,
+                ..Default::default()
+            },
+            Measure {
+                repeat_end: Some(2),
+                ..Default::default()
+            },
+            Measure::default(),
+        ];
+        assert_eq!(performance_order(&measures), vec![0, 1, 2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn takes_each_ending_on_its_pass() {
+        let measures = [
+            Measure::default(),
+            Measure::default(),
+            Measure {
+                ending_numbers: vec![1],
+                repeat_end: Some(2),
+                ..Default::default()
+            },
+            Measure {
+                ending_numbers: vec![2],
+                ..Default::default()
+            },
+            Measure::default(),
+        ];
+        assert_eq!(performance_order(&measures), vec![0, 1, 2, 0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn follows_dal_segno_al_coda() {
+        let measures = [
+            Measure::default(),
+            Measure {
+                segno: true,
+                ..Default::default()
+            },
+            Measure {
+                to_coda: true,
+                ..Default::default()
+            },
+            Measure {
+                jump: Some(Jump::DalSegno),
+                ..Default::default()
+            },
+            Measure {
+                coda: true,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(performance_order(&measures), vec![0, 1, 2, 3, 1, 2, 4]);
+    }
+
+    #[test]
+    fn follows_da_capo_al_fine_without_repeating_again() {
+        let measures = [
+            Measure {
+                repeat_start: true,
+                ..Default::default()
+            },
+            Measure {
+                repeat_end: Some(2),
+                fine: true,
+                ..Default::default()
+            },
+            Measure {
+                jump: Some(Jump::DaCapo),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(performance_order(&measures), vec![0, 1, 0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn gives_up_on_runaway_repeats() {
+        let measures = [
+            Measure::default(),
+            Measure {
+                repeat_end: Some(u32::MAX),
+                ..Default::default()
+            },
+        ];
+        let order = performance_order(&measures);
+        assert_eq!(order.len(), measures.len() * MAX_PASSES_THROUGH_SCORE + 1);
+        assert!(order.iter().all(|&index| index < measures.len()));
+    }
+}
@@ -0,0 +1,552 @@
+use bit_set::BitSet;
+use fraction::Fraction;
+use log::error;
+
+use crate::instrument::PlaybackGuard;
+use crate::loop_region::LoopRegion;
+use crate::playback_manager::PlaybackManager;
+use crate::song_data::{SongData, TimeSlice, DEFAULT_BPM};
+
+/// How far past the current audio time we schedule notes on each tick. This needs to comfortably
+/// cover the gap between ticks (which are driven by a much less reliable UI timer).
+const LOOKAHEAD_SECONDS: f64 = 0.2;
+/// A small delay before the first note so that it isn't already late by the time it's scheduled.
+const START_DELAY_SECONDS: f64 = 0.05;
+/// How long to hang onto notes after they've been released, so their fade-out isn't cut short.
+const RELEASE_SECONDS: f64 = 1.0;
+
+/// What the transport plays through. This is the `PlaybackManager`, apart from in tests.
+pub trait Player {
+    /// Holds onto a note that's been scheduled. Dropping it stops the note.
+    type Guard;
+
+    fn song_data(&self) -> Option<&SongData>;
+    /// The clock that notes are scheduled against, in seconds.
+    fn current_time(&self) -> f64;
+    fn cent_offsets_for_slice(&self, slice: &TimeSlice) -> Vec<Vec<f64>>;
+    /// Starts a note at `start_time` and releases it at `release_time`.
+    fn play_note(
+        &self,
+        voice: usize,
+        pitch: u32,
+        cents: f64,
+        start_time: f64,
+        release_time: f64,
+    ) -> Self::Guard;
+}
+
+impl Player for PlaybackManager {
+    type Guard = PlaybackGuard;
+
+    fn song_data(&self) -> Option<&SongData> {
+        PlaybackManager::song_data(self)
+    }
+
+    fn current_time(&self) -> f64 {
+        PlaybackManager::current_time(self)
+    }
+
+    fn cent_offsets_for_slice(&self, slice: &TimeSlice) -> Vec<Vec<f64>> {
+        PlaybackManager::cent_offsets_for_slice(self, slice)
+    }
+
+    fn play_note(
+        &self,
+        voice: usize,
+        pitch: u32,
+        cents: f64,
+        start_time: f64,
+        release_time: f64,
+    ) -> PlaybackGuard {
+        let guard = self.start_note(voice, pitch, cents, start_time);
+        if let Err(e) = guard.release_at(release_time) {
+            error!("Failed to schedule note release: {e:?}");
+        }
+        guard
+    }
+}
+
+/// Plays through the song in real time, starting from a given slice. Notes are scheduled a little
+/// ahead of time on the `AudioContext`'s clock, so `tick` only needs to be called often enough to
+/// stay ahead of the lookahead window, not precisely on each note.
+///
+/// Dropping the transport stops anything it had scheduled.
+pub struct Transport<G = PlaybackGuard> {
+    first_song_index: usize,
+    start_time: f64,
+    /// Multiplier on the song's own tempo.
+    tempo_scale: f64,
+    next_song_index: usize,
+    /// Playback stops before this slice, cutting off anything still held.
+    end_song_index: Option<usize>,
+    /// When set, reaching `end_song_index` goes back to the start of the loop instead of stopping,
+    /// until we run out of repeats.
+    loop_region: Option<LoopRegion>,
+    /// How many times we've gone back to the start of the loop, when scheduling.
+    loops_completed: u32,
+    /// How many times we'd gone back to the start of the loop as of the slice that's sounding.
+    last_started_pass: u32,
+    last_started_song_index: Option<usize>,
+    scheduled: Vec<ScheduledSlice<G>>,
+}
+
+struct ScheduledSlice<G> {
+    song_index: usize,
+    /// `loops_completed` when this was scheduled.
+    pass: u32,
+    start_time: f64,
+    /// When the last note struck in this slice is released.
+    end_time: f64,
+    _guards: Vec<G>,
+}
+
+pub struct TransportTick {
+    /// The slice that's sounding right now, if any has started yet.
+    pub current_song_index: Option<usize>,
+    pub cursor_index: Option<usize>,
+    /// Which time through the loop we're on, counting from 1, or `None` if there's no loop.
+    pub pass: Option<u32>,
+    /// Whether we've played through the end of the song.
+    pub finished: bool,
+}
+
+impl<G> Transport<G> {
+    /// Starts playback at `song_index`. If `tempo_bpm` is set it replaces the song's tempo at that
+    /// point, and any later tempo changes are scaled to match.
+    pub fn start(
+        player: &impl Player<Guard = G>,
+        song_index: usize,
+        tempo_bpm: Option<f64>,
+    ) -> Option<Self> {
+        let song_data = player.song_data()?;
+        let slice = song_data.slices.get(song_index)?;
+        let tempo_scale = match tempo_bpm {
+            Some(tempo_bpm) => {
+                tempo_bpm / song_data.tempo_at(slice.timestamp).unwrap_or(DEFAULT_BPM)
+            }
+            None => 1.0,
+        };
+
+        Some(Self {
+            first_song_index: song_index,
+            start_time: player.current_time() + START_DELAY_SECONDS,
+            tempo_scale,
+            next_song_index: song_index,
+            end_song_index: None,
+            loop_region: None,
+            loops_completed: 0,
+            last_started_pass: 0,
+            last_started_song_index: None,
+            scheduled: Vec::new(),
+        })
+    }
+
+    /// Stops playback before `end_song_index` rather than at the end of the song.
+    pub fn with_end(mut self, end_song_index: usize) -> Self {
+        self.end_song_index = Some(end_song_index);
+        self
+    }
+
+    /// Goes around `loop_region` rather than playing on past it. Starting after the loop ignores
+    /// it, since we'd never get there.
+    pub fn with_loop(mut self, loop_region: LoopRegion) -> Self {
+        if self.first_song_index <= loop_region.end {
+            self.end_song_index = Some(loop_region.end + 1);
+            self.loop_region = Some(loop_region);
+        }
+        self
+    }
+
+    /// Schedules any slices coming up soon and reports where playback currently is.
+    pub fn tick(
+        &mut self,
+        player: &impl Player<Guard = G>,
+        active_voices: &BitSet,
+    ) -> TransportTick {
+        let now = player.current_time();
+        self.schedule_until(player, active_voices, now + LOOKAHEAD_SECONDS);
+
+        if let Some(started) = self
+            .scheduled
+            .iter()
+            .rev()
+            .find(|scheduled| scheduled.start_time <= now)
+        {
+            self.last_started_song_index = Some(started.song_index);
+            self.last_started_pass = started.pass;
+        }
+        self.scheduled
+            .retain(|scheduled| scheduled.end_time + RELEASE_SECONDS >= now);
+
+        let end_song_index = self.end_song_index(player);
+        let song_end_time = self.time_of(player, end_song_index);
+        TransportTick {
+            current_song_index: self.last_started_song_index,
+            cursor_index: self.last_started_song_index.and_then(|song_index| {
+                Some(player.song_data()?.slices.get(song_index)?.cursor_index)
+            }),
+            pass: self.loop_region.map(|_| self.last_started_pass + 1),
+            finished: self.next_song_index >= end_song_index
+                && song_end_time.is_none_or(|end_time| end_time <= now),
+        }
+    }
+
+    /// Schedules everything through to the end at once, eg when rendering into an
+    /// `OfflineAudioContext`.
+    pub fn schedule_all(&mut self, player: &impl Player<Guard = G>, active_voices: &BitSet) {
+        self.schedule_until(player, active_voices, f64::INFINITY);
+    }
+
+    /// How long playback lasts from when the transport was started, through to the end of the
+    /// last note's release.
+    pub fn duration(&self, player: &impl Player<Guard = G>) -> f64 {
+        let end_time = self
+            .time_of(player, self.end_song_index(player))
+            .unwrap_or(self.start_time);
+        START_DELAY_SECONDS + end_time - self.start_time + RELEASE_SECONDS
+    }
+
+    /// Schedules every slice that starts before `until` (in `AudioContext` time).
+    fn schedule_until(
+        &mut self,
+        player: &impl Player<Guard = G>,
+        active_voices: &BitSet,
+        until: f64,
+    ) {
+        let end_song_index = self.end_song_index(player);
+        loop {
+            if self.next_song_index >= end_song_index && !self.go_around_loop(player) {
+                break;
+            }
+            let stop_time = self.time_of(player, end_song_index);
+            let Some(start_time) = self.time_of(player, self.next_song_index) else {
+                break;
+            };
+            if start_time > until {
+                break;
+            }
+            let song_data = player
+                .song_data()
+                .expect("We already know there's a slice here");
+            let slice = &song_data.slices[self.next_song_index];
+            // Only strike notes which start here, anything held over is still ringing from when
+            // it was struck. The exception is the very first slice, since nothing's been struck
+            // yet.
+            let is_first = self.next_song_index == self.first_song_index;
+            let cent_offsets = player.cent_offsets_for_slice(slice);
+            let mut guards = Vec::new();
+            let mut end_time = start_time;
+            for (voice, notes) in slice.notes_by_voice.iter().enumerate() {
+                if !active_voices.contains(voice) {
+                    continue;
+                }
+                for (note, cents) in notes.iter().zip(&cent_offsets[voice]) {
+                    if !is_first && !slice.is_newly_struck(note) {
+                        continue;
+                    }
+                    let note_end_time = self
+                        .time_at(player, note.end_timestamp)
+                        .unwrap_or(start_time);
+                    let note_end_time =
+                        stop_time.map_or(note_end_time, |stop| note_end_time.min(stop));
+                    guards.push(player.play_note(
+                        voice,
+                        note.pitch,
+                        *cents,
+                        start_time,
+                        note_end_time,
+                    ));
+                    end_time = end_time.max(note_end_time);
+                }
+            }
+            self.scheduled.push(ScheduledSlice {
+                song_index: self.next_song_index,
+                pass: self.loops_completed,
+                start_time,
+                end_time,
+                _guards: guards,
+            });
+            self.next_song_index += 1;
+        }
+    }
+
+    /// Goes back to the start of the loop if there is one and it has repeats left, picking up
+    /// exactly where this pass ends and speeding up if asked. Returns whether it did.
+    fn go_around_loop(&mut self, player: &impl Player<Guard = G>) -> bool {
+        let Some(loop_region) = self.loop_region else {
+            return false;
+        };
+        if loop_region
+            .repeats
+            .is_some_and(|repeats| self.loops_completed + 1 >= repeats)
+        {
+            return false;
+        }
+        let Some(pass_end_time) = self.time_of(player, loop_region.end + 1) else {
+            return false;
+        };
+        self.start_time = pass_end_time;
+        self.first_song_index = loop_region.start;
+        self.next_song_index = loop_region.start;
+        self.tempo_scale *= 1.0 + loop_region.speed_up_per_pass;
+        self.loops_completed += 1;
+        true
+    }
+
+    /// Where playback stops, which may be the number of slices if it plays through to the end.
+    fn end_song_index(&self, player: &impl Player<Guard = G>) -> usize {
+        let num_slices = player.song_data().map(|sd| sd.slices.len()).unwrap_or(0);
+        self.end_song_index
+            .map_or(num_slices, |end_song_index| end_song_index.min(num_slices))
+    }
+
+    /// When the given slice starts in `AudioContext` time. Passing the number of slices gives the
+    /// time the song ends.
+    fn time_of(&self, player: &impl Player<Guard = G>, song_index: usize) -> Option<f64> {
+        let song_data = player.song_data()?;
+        let timestamp = match song_data.slices.get(song_index) {
+            Some(slice) => slice.timestamp,
+            None if song_index == song_data.slices.len() => song_data.end_timestamp,
+            None => return None,
+        };
+        self.time_at(player, timestamp)
+    }
+
+    /// Converts a timestamp in the song to `AudioContext` time. `None` if the song has changed out
+    /// from under us so that we no longer know where we started, in which case there's nothing
+    /// left to play.
+    fn time_at(&self, player: &impl Player<Guard = G>, timestamp: Fraction) -> Option<f64> {
+        let song_data = player.song_data()?;
+        let from = song_data.slices.get(self.first_song_index)?.timestamp;
+        Some(self.start_time + song_data.seconds_between(from, timestamp) / self.tempo_scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::song_data::{SliceBuilder, TempoChange, VoiceIndexMapping};
+
+    /// A song on its own clock, which remembers what it was asked to play.
+    struct FakePlayer {
+        song_data: SongData,
+        now: Cell<f64>,
+        /// (voice, pitch, start time, release time), with times in milliseconds.
+        played: RefCell<Vec<(usize, u32, i64, i64)>>,
+    }
+
+    impl FakePlayer {
+        fn new(song_data: SongData) -> Self {
+            Self {
+                song_data,
+                now: Cell::new(0.0),
+                played: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn take_played(&self) -> Vec<(usize, u32, i64, i64)> {
+            self.played.take()
+        }
+    }
+
+    impl Player for FakePlayer {
+        type Guard = ();
+
+        fn song_data(&self) -> Option<&SongData> {
+            Some(&self.song_data)
+        }
+
+        fn current_time(&self) -> f64 {
+            self.now.get()
+        }
+
+        fn cent_offsets_for_slice(&self, slice: &TimeSlice) -> Vec<Vec<f64>> {
+            slice
+                .notes_by_voice
+                .iter()
+                .map(|notes| vec![0.0; notes.len()])
+                .collect_vec()
+        }
+
+        fn play_note(&self, voice: usize, pitch: u32, _cents: f64, start: f64, release: f64) {
+            let ms = |seconds: f64| (seconds * 1000.0).round() as i64;
+            self.played
+                .borrow_mut()
+                .push((voice, pitch, ms(start), ms(release)));
+        }
+    }
+
+    /// At 120 bpm, so each quarter note lasts half a second. Voice 0 plays `num_quarters` quarter
+    /// notes, while voice 1 holds half notes underneath.
+    fn song(num_quarters: u32) -> SongData {
+        let mut slice_builder = SliceBuilder::new(2);
+        for i in 0..num_quarters {
+            slice_builder.advance_to(Fraction::new(i, 4u32));
+            slice_builder.add_note(0, 60 + i, Fraction::new(1u32, 4u32));
+            if i % 2 == 0 {
+                slice_builder.add_note(1, 48 + i, Fraction::new(1u32, 2u32));
+            }
+            slice_builder.finish_position(i as usize);
+        }
+        let (slices, end_timestamp) = slice_builder.build();
+        SongData {
+            voice_index_mapping: VoiceIndexMapping::from_iter([(0, 1), (0, 2)]),
+            slices,
+            end_timestamp,
+            tempo_changes: vec![TempoChange {
+                timestamp: Fraction::from(0),
+                bpm: 120.0,
+            }],
+            time_signatures: Vec::new(),
+            voice_names: vec!["Melody".to_string(), "Bass".to_string()],
+            measures: Vec::new(),
+        }
+    }
+
+    fn all_voices() -> BitSet {
+        BitSet::from_iter([0, 1])
+    }
+
+    #[test]
+    fn schedules_slices_as_they_come_into_the_lookahead() {
+        let player = FakePlayer::new(song(4));
+        let mut transport = Transport::start(&player, 0, None).unwrap();
+
+        let tick = transport.tick(&player, &all_voices());
+        assert_eq!(tick.current_song_index, None);
+        assert_eq!(
+            player.take_played(),
+            vec![(0, 60, 50, 550), (1, 48, 50, 1050)]
+        );
+
+        player.now.set(0.4);
+        let tick = transport.tick(&player, &all_voices());
+        assert_eq!(tick.current_song_index, Some(0));
+        assert_eq!(tick.cursor_index, Some(0));
+        // The bass is still holding its note from the first slice.
+        assert_eq!(player.take_played(), vec![(0, 61, 550, 1050)]);
+    }
+
+    #[test]
+    fn strikes_held_notes_when_starting_partway_through_them() {
+        let player = FakePlayer::new(song(4));
+        let mut transport = Transport::start(&player, 1, None).unwrap();
+        transport.tick(&player, &all_voices());
+        assert_eq!(
+            player.take_played(),
+            vec![(0, 61, 50, 550), (1, 48, 50, 550)]
+        );
+    }
+
+    #[test]
+    fn leaves_out_inactive_voices() {
+        let player = FakePlayer::new(song(4));
+        let mut transport = Transport::start(&player, 0, None).unwrap();
+        transport.schedule_all(&player, &BitSet::from_iter([1]));
+        assert_eq!(
+            player.take_played(),
+            vec![(1, 48, 50, 1050), (1, 50, 1050, 2050)]
+        );
+    }
+
+    #[test]
+    fn cuts_off_held_notes_at_the_end() {
+        let player = FakePlayer::new(song(4));
+        let mut transport = Transport::start(&player, 0, None).unwrap().with_end(1);
+        transport.schedule_all(&player, &all_voices());
+        assert_eq!(
+            player.take_played(),
+            vec![(0, 60, 50, 550), (1, 48, 50, 550)]
+        );
+    }
+
+    #[test]
+    fn goes_around_the_loop_until_out_of_repeats() {
+        let player = FakePlayer::new(song(4));
+        let mut loop_region = LoopRegion::new(1, 2);
+        loop_region.repeats = Some(2);
+        let mut transport = Transport::start(&player, 0, None)
+            .unwrap()
+            .with_loop(loop_region);
+        transport.schedule_all(&player, &BitSet::from_iter([0]));
+        assert_eq!(
+            player.take_played(),
+            vec![
+                (0, 60, 50, 550),
+                (0, 61, 550, 1050),
+                (0, 62, 1050, 1550),
+                (0, 61, 1550, 2050),
+                (0, 62, 2050, 2550),
+            ]
+        );
+
+        player.now.set(1.6);
+        let tick = transport.tick(&player, &all_voices());
+        assert_eq!(tick.current_song_index, Some(1));
+        assert_eq!(tick.pass, Some(2));
+        assert!(!tick.finished);
+    }
+
+    #[test]
+    fn speeds_up_on_each_pass() {
+        let player = FakePlayer::new(song(2));
+        let mut loop_region = LoopRegion::new(0, 1);
+        loop_region.repeats = Some(2);
+        loop_region.speed_up_per_pass = 1.0;
+        let mut transport = Transport::start(&player, 0, None)
+            .unwrap()
+            .with_loop(loop_region);
+        transport.schedule_all(&player, &BitSet::from_iter([0]));
+        assert_eq!(
+            player.take_played(),
+            vec![
+                (0, 60, 50, 550),
+                (0, 61, 550, 1050),
+                (0, 60, 1050, 1300),
+                (0, 61, 1300, 1550),
+            ]
+        );
+    }
+
+    #[test]
+    fn finishes_once_the_last_slice_has_ended() {
+        let player = FakePlayer::new(song(4));
+        let mut transport = Transport::start(&player, 0, None).unwrap();
+
+        player.now.set(1.9);
+        assert!(!transport.tick(&player, &all_voices()).finished);
+
+        player.now.set(2.1);
+        let tick = transport.tick(&player, &all_voices());
+        assert!(tick.finished);
+        assert_eq!(tick.current_song_index, Some(3));
+    }
+
+    #[test]
+    fn lasts_until_the_end_plus_the_release() {
+        let player = FakePlayer::new(song(4));
+        let transport = Transport::start(&player, 0, None).unwrap();
+        assert_eq!(transport.duration(&player), 3.05);
+
+        let transport = Transport::start(&player, 1, None).unwrap().with_end(3);
+        assert_eq!(transport.duration(&player), 2.05);
+
+        let transport = Transport::start(&player, 0, Some(240.0)).unwrap();
+        assert_eq!(transport.duration(&player), 2.05);
+    }
+
+    #[test]
+    fn finishes_if_the_song_shrinks_out_from_under_it() {
+        let player = FakePlayer::new(song(4));
+        let mut transport = Transport::start(&player, 3, None).unwrap();
+
+        let player = FakePlayer::new(song(2));
+        let tick = transport.tick(&player, &all_voices());
+        assert!(tick.finished);
+        assert_eq!(player.take_played(), Vec::new());
+    }
+}
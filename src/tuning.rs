@@ -0,0 +1,293 @@
+use itertools::Itertools;
+
+/// Frequency ratios above the reference for each of the 12 pitch classes.
+type Ratios = [f64; 12];
+
+/// Classic 5-limit just intonation, built from pure fifths and thirds.
+const FIVE_LIMIT_RATIOS: Ratios = [
+    1.0,
+    16.0 / 15.0,
+    9.0 / 8.0,
+    6.0 / 5.0,
+    5.0 / 4.0,
+    4.0 / 3.0,
+    45.0 / 32.0,
+    3.0 / 2.0,
+    8.0 / 5.0,
+    5.0 / 3.0,
+    9.0 / 5.0,
+    15.0 / 8.0,
+];
+
+/// 5-limit, except the minor seventh and tritone come from the 7th harmonic. This is what makes a
+/// barbershop seventh chord (4:5:6:7) lock and ring.
+const BARBERSHOP_RATIOS: Ratios = [
+    1.0,
+    16.0 / 15.0,
+    9.0 / 8.0,
+    6.0 / 5.0,
+    5.0 / 4.0,
+    4.0 / 3.0,
+    7.0 / 5.0,
+    3.0 / 2.0,
+    8.0 / 5.0,
+    5.0 / 3.0,
+    7.0 / 4.0,
+    15.0 / 8.0,
+];
+
+/// Which just intervals to tune to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JustIntervals {
+    FiveLimit,
+    Barbershop,
+}
+
+impl JustIntervals {
+    fn ratios(&self) -> &'static Ratios {
+        match self {
+            JustIntervals::FiveLimit => &FIVE_LIMIT_RATIOS,
+            JustIntervals::Barbershop => &BARBERSHOP_RATIOS,
+        }
+    }
+}
+
+/// What the just intervals are measured from. The reference note itself stays at its equal
+/// tempered pitch.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TuningReference {
+    /// The root of whatever chord is sounding, so every chord is pure (but the key can drift).
+    ChordRoot,
+    /// A fixed pitch class (0 = C), usually the key of the song.
+    FixedTonic(u32),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Tuning {
+    /// Standard 12-tone equal temperament, like a piano.
+    #[default]
+    Equal,
+    Just {
+        intervals: JustIntervals,
+        reference: TuningReference,
+    },
+}
+
+/// The tunings offered in the UI, by name. Fixed tonic presets default to C, use `with_tonic` to
+/// move them.
+pub const TUNING_PRESETS: &[(&str, Tuning)] = &[
+    ("Equal temperament", Tuning::Equal),
+    (
+        "Barbershop (just, chord root)",
+        Tuning::Just {
+            intervals: JustIntervals::Barbershop,
+            reference: TuningReference::ChordRoot,
+        },
+    ),
+    (
+        "Just, chord root",
+        Tuning::Just {
+            intervals: JustIntervals::FiveLimit,
+            reference: TuningReference::ChordRoot,
+        },
+    ),
+    (
+        "Just, fixed tonic",
+        Tuning::Just {
+            intervals: JustIntervals::FiveLimit,
+            reference: TuningReference::FixedTonic(0),
+        },
+    ),
+    (
+        "Barbershop, fixed tonic",
+        Tuning::Just {
+            intervals: JustIntervals::Barbershop,
+            reference: TuningReference::FixedTonic(0),
+        },
+    ),
+];
+
+/// Chord shapes we know how to find the root of, as semitones above the root. Earlier entries win
+/// ties, so the more common chords come first.
+const CHORD_TEMPLATES: &[&[u32]] = &[
+    &[0, 4, 7],
+    &[0, 3, 7],
+    &[0, 4, 7, 10],
+    &[0, 3, 7, 10],
+    &[0, 4, 7, 11],
+    &[0, 4, 7, 9],
+    &[0, 3, 6, 10],
+    &[0, 3, 6, 9],
+    &[0, 3, 6],
+    &[0, 4, 8],
+    &[0, 5, 7],
+    &[0, 2, 4, 7, 10],
+];
+
+impl Tuning {
+    /// Same tuning, but measured from the given tonic if it uses a fixed one.
+    pub fn with_tonic(self, tonic: u32) -> Self {
+        match self {
+            Tuning::Just {
+                intervals,
+                reference: TuningReference::FixedTonic(_),
+            } => Tuning::Just {
+                intervals,
+                reference: TuningReference::FixedTonic(tonic % 12),
+            },
+            other => other,
+        }
+    }
+
+    /// How far (in cents) each of the given MIDI pitches should be moved away from equal
+    /// temperament. The pitches should be everything sounding together, since the chord as a
+    /// whole decides how each note is tuned.
+    pub fn cent_offsets(&self, pitches: &[u32]) -> Vec<f64> {
+        let Tuning::Just {
+            intervals,
+            reference,
+        } = self
+        else {
+            return vec![0.0; pitches.len()];
+        };
+        let reference = match reference {
+            TuningReference::ChordRoot => match chord_root(pitches) {
+                Some(root) => root,
+                None => return vec![0.0; pitches.len()],
+            },
+            TuningReference::FixedTonic(tonic) => *tonic,
+        };
+        let ratios = intervals.ratios();
+        pitches
+            .iter()
+            .map(|pitch| {
+                let interval = (pitch + 12 - reference % 12) % 12;
+                let just_cents = 1200.0 * ratios[interval as usize].log2();
+                just_cents - 100.0 * interval as f64
+            })
+            .collect_vec()
+    }
+}
+
+/// Guesses the root pitch class of the chord made by the given pitches, by finding the chord
+/// shape that explains the most of them. Ties go to the bass note being the root, since that's
+/// the usual voicing.
+fn chord_root(pitches: &[u32]) -> Option<u32> {
+    let bass = pitches.iter().min()? % 12;
+    let pitch_classes = pitches.iter().map(|p| p % 12).unique().collect_vec();
+    if pitch_classes.len() < 2 {
+        return Some(bass);
+    }
+
+    let pitch_classes = &pitch_classes;
+    pitch_classes
+        .iter()
+        .flat_map(|&root| {
+            CHORD_TEMPLATES
+                .iter()
+                .enumerate()
+                .map(move |(template_index, template)| {
+                    let matched = pitch_classes
+                        .iter()
+                        .filter(|&&pc| template.contains(&((pc + 12 - root) % 12)))
+                        .count() as i32;
+                    let unmatched = pitch_classes.len() as i32 - matched;
+                    let missing = template.len() as i32 - matched;
+                    let score = (
+                        matched * 4 - unmatched * 6 - missing,
+                        root == bass,
+                        -(template_index as i32),
+                    );
+                    (score, root)
+                })
+        })
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, root)| root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JUST_MAJOR_THIRD: f64 = -13.686;
+    const JUST_FIFTH: f64 = 1.955;
+
+    fn assert_cents(actual: Vec<f64>, expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (actual_cents, expected_cents) in actual.iter().zip(expected) {
+            assert!(
+                (actual_cents - expected_cents).abs() < 0.01,
+                "{actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    fn just(intervals: JustIntervals, reference: TuningReference) -> Tuning {
+        Tuning::Just {
+            intervals,
+            reference,
+        }
+    }
+
+    #[test]
+    fn tunes_a_major_triad_to_its_root() {
+        let tuning = just(JustIntervals::FiveLimit, TuningReference::ChordRoot);
+        // C E G, in both close and open voicings.
+        assert_cents(
+            tuning.cent_offsets(&[60, 64, 67]),
+            &[0.0, JUST_MAJOR_THIRD, JUST_FIFTH],
+        );
+        assert_cents(
+            tuning.cent_offsets(&[48, 67, 76]),
+            &[0.0, JUST_FIFTH, JUST_MAJOR_THIRD],
+        );
+        assert_cents(Tuning::Equal.cent_offsets(&[60, 64, 67]), &[0.0; 3]);
+    }
+
+    #[test]
+    fn tunes_a_barbershop_seventh_to_the_harmonic_series() {
+        // G B D F
+        let pitches = [55, 59, 62, 65];
+        assert_cents(
+            just(JustIntervals::Barbershop, TuningReference::ChordRoot).cent_offsets(&pitches),
+            &[0.0, JUST_MAJOR_THIRD, JUST_FIFTH, -31.174],
+        );
+        // 5-limit's 9/5 seventh is much sharper.
+        assert_cents(
+            just(JustIntervals::FiveLimit, TuningReference::ChordRoot).cent_offsets(&pitches),
+            &[0.0, JUST_MAJOR_THIRD, JUST_FIFTH, 17.596],
+        );
+    }
+
+    #[test]
+    fn fixed_tonic_ignores_the_chord() {
+        // A G major triad, heard in G and then in C.
+        let pitches = [55, 59, 62];
+        assert_cents(
+            just(JustIntervals::FiveLimit, TuningReference::ChordRoot).cent_offsets(&pitches),
+            &[0.0, JUST_MAJOR_THIRD, JUST_FIFTH],
+        );
+        assert_cents(
+            just(JustIntervals::FiveLimit, TuningReference::FixedTonic(0)).cent_offsets(&pitches),
+            &[JUST_FIFTH, -11.731, 3.910],
+        );
+        assert_eq!(
+            just(JustIntervals::FiveLimit, TuningReference::FixedTonic(0)).with_tonic(19),
+            just(JustIntervals::FiveLimit, TuningReference::FixedTonic(7)),
+        );
+    }
+
+    #[test]
+    fn ambiguous_chords_are_rooted_on_the_bass() {
+        // C E G A is both C6 and Am7, so the voicing decides.
+        assert_eq!(chord_root(&[60, 64, 67, 69]), Some(0));
+        assert_eq!(chord_root(&[57, 64, 67, 72]), Some(9));
+        // Not enough to go on.
+        assert_eq!(chord_root(&[62, 74]), Some(2));
+        assert_eq!(chord_root(&[]), None);
+        assert_cents(
+            just(JustIntervals::FiveLimit, TuningReference::ChordRoot).cent_offsets(&[]),
+            &[],
+        );
+    }
+}
@@ -0,0 +1,84 @@
+// Lets the app load without a network connection. Everything we serve ourselves (the app shell,
+// the vendored OSMD bundle and the examples) is kept in a cache. Pages are fetched network-first
+// so new deploys show up straight away, everything else is served from the cache and refreshed in
+// the background. Samples come from another site and are cached by the app itself.
+
+// Must match `SHELL_CACHE` in `src/offline_cache.rs`, which precaches the examples into it.
+const SHELL_CACHE = "magic-piano-shell-v1";
+
+self.addEventListener("install", (event) => {
+    event.waitUntil(
+        (async () => {
+            const cache = await caches.open(SHELL_CACHE);
+            // Trunk gives the build outputs hashed names, so pull them out of the page rather
+            // than listing them here.
+            const page = await fetch("./", { cache: "no-cache" });
+            const html = await page.clone().text();
+            await cache.put("./", page);
+            const assets = [...html.matchAll(/(?:href|src)="([^"]+)"/g)]
+                .map((match) => new URL(match[1], self.registration.scope))
+                .filter((url) => url.origin === self.location.origin);
+            await cache.addAll(assets.map((url) => url.href));
+            await self.skipWaiting();
+        })(),
+    );
+});
+
+self.addEventListener("activate", (event) => {
+    event.waitUntil(
+        (async () => {
+            const names = await caches.keys();
+            await Promise.all(
+                names
+                    .filter((name) => name.startsWith("magic-piano-shell-") && name !== SHELL_CACHE)
+                    .map((name) => caches.delete(name)),
+            );
+            await self.clients.claim();
+        })(),
+    );
+});
+
+self.addEventListener("fetch", (event) => {
+    const request = event.request;
+    if (request.method !== "GET" || new URL(request.url).origin !== self.location.origin) {
+        return;
+    }
+
+    if (request.mode === "navigate") {
+        event.respondWith(
+            (async () => {
+                const cache = await caches.open(SHELL_CACHE);
+                try {
+                    const response = await fetch(request);
+                    if (response.ok) {
+                        await cache.put("./", response.clone());
+                    }
+                    return response;
+                } catch (e) {
+                    return (await cache.match("./")) ?? Response.error();
+                }
+            })(),
+        );
+        return;
+    }
+
+    event.respondWith(
+        (async () => {
+            const cache = await caches.open(SHELL_CACHE);
+            const cached = await cache.match(request);
+            const refreshed = fetch(request)
+                .then(async (response) => {
+                    if (response.ok) {
+                        await cache.put(request, response.clone());
+                    }
+                    return response;
+                })
+                .catch(() => cached ?? Response.error());
+            if (cached) {
+                event.waitUntil(refreshed);
+                return cached;
+            }
+            return refreshed;
+        })(),
+    );
+});